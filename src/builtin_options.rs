@@ -0,0 +1,396 @@
+//! Built-in options newer than the `protobuf` crate.
+//!
+//! The `descriptor.proto` compiled into `protobuf` 3.7 predates options such
+//! as `debug_redact`, `retention` or extension range declarations. Descriptors
+//! produced by `protoc` or read from descriptor sets still carry them, as
+//! unknown fields of the options messages, so they are decoded here from
+//! their `descriptor.proto` definitions.
+
+use crate::custom_options::{group_by_number, FIRST_EXTENSION_NUMBER};
+use crate::editions;
+use crate::text_gen::TextGenerator;
+use anyhow::{bail, Context, Result};
+use protobuf::well_known_types::empty::Empty;
+use protobuf::{Message, MessageDyn, UnknownValueRef};
+
+/// `uninterpreted_option` field of every `*Options` message
+const UNINTERPRETED_OPTION_FIELD: u32 = 999;
+
+/// Names and numbers of the values of an enum.
+type EnumValues = &'static [(&'static str, u64)];
+
+/// Type of an option value.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Kind {
+    Bool,
+    Int32,
+    String,
+    Enum(EnumValues),
+    Message(&'static [Field]),
+}
+
+/// A field of an options message, or of a message used as an option value.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Field {
+    pub(crate) name: &'static str,
+    pub(crate) number: u32,
+    pub(crate) kind: Kind,
+    pub(crate) repeated: bool,
+}
+
+const fn singular(name: &'static str, number: u32, kind: Kind) -> Field {
+    Field {
+        name,
+        number,
+        kind,
+        repeated: false,
+    }
+}
+
+const fn repeated(name: &'static str, number: u32, kind: Kind) -> Field {
+    Field {
+        name,
+        number,
+        kind,
+        repeated: true,
+    }
+}
+
+const EDITION: EnumValues = &[
+    ("EDITION_UNKNOWN", 0),
+    ("EDITION_1_TEST_ONLY", 1),
+    ("EDITION_2_TEST_ONLY", 2),
+    ("EDITION_LEGACY", 900),
+    ("EDITION_PROTO2", 998),
+    ("EDITION_PROTO3", 999),
+    ("EDITION_2023", 1000),
+    ("EDITION_2024", 1001),
+    ("EDITION_99997_TEST_ONLY", 99997),
+    ("EDITION_99998_TEST_ONLY", 99998),
+    ("EDITION_99999_TEST_ONLY", 99999),
+    ("EDITION_MAX", 0x7FFF_FFFF),
+];
+
+const OPTION_RETENTION: EnumValues = &[
+    ("RETENTION_UNKNOWN", 0),
+    ("RETENTION_RUNTIME", 1),
+    ("RETENTION_SOURCE", 2),
+];
+
+const OPTION_TARGET_TYPE: EnumValues = &[
+    ("TARGET_TYPE_UNKNOWN", 0),
+    ("TARGET_TYPE_FILE", 1),
+    ("TARGET_TYPE_EXTENSION_RANGE", 2),
+    ("TARGET_TYPE_MESSAGE", 3),
+    ("TARGET_TYPE_FIELD", 4),
+    ("TARGET_TYPE_ONEOF", 5),
+    ("TARGET_TYPE_ENUM", 6),
+    ("TARGET_TYPE_ENUM_ENTRY", 7),
+    ("TARGET_TYPE_SERVICE", 8),
+    ("TARGET_TYPE_METHOD", 9),
+];
+
+const VERIFICATION_STATE: EnumValues = &[("DECLARATION", 0), ("UNVERIFIED", 1)];
+
+/// `FieldOptions.EditionDefault`
+const EDITION_DEFAULT: &[Field] = &[
+    singular("value", 2, Kind::String),
+    singular("edition", 3, Kind::Enum(EDITION)),
+];
+
+/// `FieldOptions.FeatureSupport`
+const FEATURE_SUPPORT: &[Field] = &[
+    singular("edition_introduced", 1, Kind::Enum(EDITION)),
+    singular("edition_deprecated", 2, Kind::Enum(EDITION)),
+    singular("deprecation_warning", 3, Kind::String),
+    singular("edition_removed", 4, Kind::Enum(EDITION)),
+    singular("removal_error", 5, Kind::String),
+];
+
+/// `ExtensionRangeOptions.Declaration`
+const DECLARATION: &[Field] = &[
+    singular("number", 1, Kind::Int32),
+    singular("full_name", 2, Kind::String),
+    singular("type", 3, Kind::String),
+    singular("reserved", 5, Kind::Bool),
+    singular("repeated", 6, Kind::Bool),
+];
+
+/// Options missing from the `protobuf` crate, by options message.
+pub(crate) const NEWER_OPTIONS: &[(&str, &[Field])] = &[
+    (
+        "google.protobuf.MessageOptions",
+        &[singular(
+            "deprecated_legacy_json_field_conflicts",
+            11,
+            Kind::Bool,
+        )],
+    ),
+    (
+        "google.protobuf.FieldOptions",
+        &[
+            singular("unverified_lazy", 15, Kind::Bool),
+            singular("debug_redact", 16, Kind::Bool),
+            singular("retention", 17, Kind::Enum(OPTION_RETENTION)),
+            repeated("targets", 19, Kind::Enum(OPTION_TARGET_TYPE)),
+            repeated("edition_defaults", 20, Kind::Message(EDITION_DEFAULT)),
+            singular("feature_support", 22, Kind::Message(FEATURE_SUPPORT)),
+        ],
+    ),
+    (
+        "google.protobuf.EnumOptions",
+        &[singular(
+            "deprecated_legacy_json_field_conflicts",
+            6,
+            Kind::Bool,
+        )],
+    ),
+    (
+        "google.protobuf.EnumValueOptions",
+        &[
+            singular("debug_redact", 3, Kind::Bool),
+            singular("feature_support", 4, Kind::Message(FEATURE_SUPPORT)),
+        ],
+    ),
+    (
+        "google.protobuf.ExtensionRangeOptions",
+        &[
+            repeated("declaration", 2, Kind::Message(DECLARATION)),
+            singular("verification", 3, Kind::Enum(VERIFICATION_STATE)),
+        ],
+    ),
+];
+
+/// Built-in options of `options` unknown to the `protobuf` crate, rendered
+/// as `<name> = <value>` in field number order, one entry per value of
+/// repeated options.
+///
+/// Fails on unknown fields below the extension range that are neither one
+/// of these options nor `features`.
+pub(crate) fn render(options: &dyn MessageDyn) -> Result<Vec<String>> {
+    let descriptor = options.descriptor_dyn();
+    let message = descriptor.full_name();
+    let fields = NEWER_OPTIONS
+        .iter()
+        .find(|(name, _)| *name == message)
+        .map_or(&[][..], |(_, fields)| fields);
+
+    let mut rendered = Vec::new();
+    for (number, values) in group_by_number(options.unknown_fields_dyn()) {
+        if number >= FIRST_EXTENSION_NUMBER
+            || number == UNINTERPRETED_OPTION_FIELD
+            || Some(number) == editions::features_field(message)
+        {
+            continue;
+        }
+        let Some(field) = fields.iter().find(|f| f.number == number) else {
+            bail!("Unsupported built-in option {number} of {message}");
+        };
+        let values = render_values(field, &values)
+            .with_context(|| format!("Malformed value of option {}", field.name))?;
+        rendered.extend(values.into_iter().map(|v| format!("{} = {v}", field.name)));
+    }
+    Ok(rendered)
+}
+
+/// Values of `field`: every element if repeated, otherwise the last one.
+fn render_values(field: &Field, values: &[UnknownValueRef]) -> Result<Vec<String>> {
+    let mut rendered = Vec::new();
+    for value in values {
+        match (field.kind, value) {
+            // Packed repeated enums
+            (Kind::Enum(names), UnknownValueRef::LengthDelimited(bytes)) => {
+                let mut stream = protobuf::CodedInputStream::from_bytes(bytes);
+                while !stream.eof()? {
+                    rendered.push(enum_name(names, stream.read_raw_varint64()?));
+                }
+            }
+            _ => rendered.push(render_value(field.kind, value)?),
+        }
+    }
+    if !field.repeated {
+        rendered = rendered.pop().into_iter().collect();
+    }
+    Ok(rendered)
+}
+
+fn render_value(kind: Kind, value: &UnknownValueRef) -> Result<String> {
+    Ok(match (kind, value) {
+        (Kind::Bool, UnknownValueRef::Varint(v)) => (*v != 0).to_string(),
+        (Kind::Int32, UnknownValueRef::Varint(v)) => (*v as i32).to_string(),
+        (Kind::Enum(names), UnknownValueRef::Varint(v)) => enum_name(names, *v),
+        (Kind::String, UnknownValueRef::LengthDelimited(bytes)) => {
+            let text = std::str::from_utf8(bytes).context("Invalid UTF-8")?;
+            format!("\"{}\"", TextGenerator::escape_string(text))
+        }
+        (Kind::Message(fields), UnknownValueRef::LengthDelimited(bytes)) => {
+            render_message(fields, bytes)?
+        }
+        _ => bail!("Unexpected wire type"),
+    })
+}
+
+/// A message value as a single-line aggregate: `{ a: 1 b: "x" }`.
+fn render_message(fields: &[Field], bytes: &[u8]) -> Result<String> {
+    let message = Empty::parse_from_bytes(bytes)?;
+    let mut entries = Vec::new();
+    for (number, values) in group_by_number(message.special_fields.unknown_fields()) {
+        let Some(field) = fields.iter().find(|f| f.number == number) else {
+            bail!("Unknown field {number}");
+        };
+        for value in render_values(field, &values)? {
+            entries.push(format!("{}: {value}", field.name));
+        }
+    }
+    if entries.is_empty() {
+        return Ok("{}".to_string());
+    }
+    Ok(format!("{{ {} }}", entries.join(" ")))
+}
+
+fn enum_name(names: EnumValues, value: u64) -> String {
+    names
+        .iter()
+        .find(|(_, v)| *v == value)
+        .map(|(name, _)| name.to_string())
+        .unwrap_or_else(|| (value as i32).to_string())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use protobuf::descriptor::{ExtensionRangeOptions, FieldOptions, FileDescriptorProto};
+    use protobuf::reflect::FileDescriptor;
+    use protobuf::UnknownFields;
+
+    /// The options messages of the protobuf project's `descriptor.proto`
+    /// (see `third_party/protobuf`), newer than the copy compiled into the
+    /// `protobuf` crate.
+    pub(crate) fn upstream_descriptor() -> FileDescriptorProto {
+        // The pure parser only knows the options of the protobuf crate, so
+        // the one newer option these messages use is dropped. Any other makes
+        // the parse fail instead of changing what is checked.
+        let source = include_str!("../third_party/protobuf/google/protobuf/descriptor.proto")
+            .replace(", retention = RETENTION_SOURCE", "")
+            .replace(" [retention = RETENTION_SOURCE]", "");
+        // Not vendored; only referenced by the `features` fields
+        let source = format!("{source}\nmessage FeatureSet {{ extensions 1000 to max; }}\n");
+        crate::ParseSession::new()
+            .with_file_name("upstream/descriptor.proto")
+            .parse(&source)
+            .unwrap()
+    }
+
+    /// Add a value of `field` that differs from its default.
+    fn add_sample(fields: &mut UnknownFields, field: &Field) {
+        match field.kind {
+            Kind::Bool => fields.add_varint(field.number, 1),
+            Kind::Int32 => fields.add_varint(field.number, 7),
+            Kind::String => fields.add_length_delimited(field.number, b"x\"y".to_vec()),
+            Kind::Enum(values) => fields.add_varint(field.number, values.last().unwrap().1),
+            Kind::Message(nested) => {
+                let mut message = Empty::new();
+                for nested_field in nested {
+                    add_sample(message.special_fields.mut_unknown_fields(), nested_field);
+                }
+                fields.add_length_delimited(field.number, message.write_to_bytes().unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn test_newer_options_round_trip() {
+        let upstream = FileDescriptor::new_dynamic(upstream_descriptor(), &[]).unwrap();
+        for (options_name, fields) in NEWER_OPTIONS {
+            let name = options_name.trim_start_matches("google.protobuf.");
+            let known = protobuf::descriptor::file_descriptor()
+                .message_by_package_relative_name(name)
+                .unwrap();
+            for field in *fields {
+                let mut options = known.new_instance();
+                add_sample(options.mut_unknown_fields_dyn(), field);
+                let rendered = render(&*options).unwrap();
+
+                // Read the text back with the upstream definition of the
+                // options message, as text format
+                let text: Vec<String> = rendered
+                    .iter()
+                    .map(|entry| match entry.split_once(" = {") {
+                        Some((name, message)) => format!("{name} {{{message}"),
+                        None => entry.replacen(" = ", ": ", 1),
+                    })
+                    .collect();
+                let mut parsed = upstream
+                    .message_by_package_relative_name(name)
+                    .unwrap()
+                    .new_instance();
+                protobuf::text_format::merge_from_str(&mut *parsed, &text.join(" "))
+                    .unwrap_or_else(|e| panic!("{name}.{}: {e}", field.name));
+
+                let mut reparsed = known.new_instance();
+                reparsed
+                    .merge_from_bytes_dyn(&parsed.write_to_bytes_dyn().unwrap())
+                    .unwrap();
+                let numbers = |m: &dyn MessageDyn| -> Vec<u32> {
+                    m.unknown_fields_dyn().iter().map(|(n, _)| n).collect()
+                };
+                assert_eq!(
+                    numbers(&*reparsed),
+                    vec![field.number],
+                    "{name}.{}",
+                    field.name
+                );
+                assert_eq!(
+                    render(&*reparsed).unwrap(),
+                    rendered,
+                    "{name}.{}",
+                    field.name
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_render_newer_options() {
+        let mut options = FieldOptions::new();
+        let unknown = options.mut_unknown_fields();
+        unknown.add_varint(19, 4);
+        unknown.add_length_delimited(19, vec![1, 3]);
+        unknown.add_varint(16, 1);
+        unknown.add_varint(17, 1);
+        unknown.add_varint(17, 2);
+        assert_eq!(
+            render(&options).unwrap(),
+            vec![
+                "debug_redact = true",
+                "retention = RETENTION_SOURCE",
+                "targets = TARGET_TYPE_FIELD",
+                "targets = TARGET_TYPE_FILE",
+                "targets = TARGET_TYPE_MESSAGE",
+            ]
+        );
+
+        let mut declaration = Empty::new();
+        let fields = declaration.special_fields.mut_unknown_fields();
+        fields.add_varint(1, 100);
+        fields.add_length_delimited(2, b".pkg.ext".to_vec());
+        fields.add_varint(6, 1);
+        let mut options = ExtensionRangeOptions::new();
+        options
+            .mut_unknown_fields()
+            .add_length_delimited(2, declaration.write_to_bytes().unwrap());
+        options.mut_unknown_fields().add_varint(3, 1);
+        assert_eq!(
+            render(&options).unwrap(),
+            vec![
+                "declaration = { number: 100 full_name: \".pkg.ext\" repeated: true }",
+                "verification = UNVERIFIED",
+            ]
+        );
+
+        let mut options = FieldOptions::new();
+        options.mut_unknown_fields().add_varint(14, 1);
+        let err = render(&options).unwrap_err();
+        assert!(err.to_string().contains("Unsupported built-in option 14"));
+    }
+}
@@ -0,0 +1,333 @@
+//! Semantic canonicalization of descriptors.
+//!
+//! Different spellings of the same schema parse to different descriptors:
+//! `reserved 1, 2, 3;` and `reserved 1 to 3;` produce three ranges and one,
+//! `default = 0x10` and `default = 16` keep their literal, and options set to
+//! their default value are recorded as set. [`canonicalize`] rewrites those
+//! to a single form so that equivalent files render to the same text and
+//! fingerprint.
+
+use protobuf::descriptor::descriptor_proto::{ExtensionRange, ReservedRange};
+use protobuf::descriptor::enum_descriptor_proto::EnumReservedRange;
+use protobuf::descriptor::field_descriptor_proto::{Label, Type};
+use protobuf::descriptor::{
+    DescriptorProto, EnumDescriptorProto, FieldDescriptorProto, FileDescriptorProto,
+};
+use protobuf::reflect::{ReflectValueRef, RuntimeFieldType};
+use protobuf::{MessageField, MessageFull};
+
+/// Return the canonical form of `file`.
+///
+/// - Reserved ranges are sorted and overlapping or adjacent ones merged;
+///   reserved names are sorted and deduplicated. Extension ranges are sorted
+///   and adjacent ones merged when neither has options.
+/// - Numeric `default` values are written in decimal (`0x10` becomes `16`,
+///   `1.50` becomes `1.5`, `-Infinity` becomes `-inf`).
+/// - Built-in options set to their default value are removed, as is
+///   `packed` when it matches the packing the syntax implies. Options
+///   messages left empty are removed.
+///
+/// Merging and fingerprinting canonicalize their input; rendering a
+/// descriptor with [`TextGenerator`](crate::TextGenerator) does not.
+pub fn canonicalize(file: &FileDescriptorProto) -> FileDescriptorProto {
+    let mut file = file.clone();
+    let packed_by_default = match file.syntax() {
+        "proto3" => Some(true),
+        "" | "proto2" => Some(false),
+        // Editions decide packing through features
+        _ => None,
+    };
+
+    clear_default_options(&mut file.options);
+    for message in &mut file.message_type {
+        canonicalize_message(message, packed_by_default);
+    }
+    for enum_type in &mut file.enum_type {
+        canonicalize_enum(enum_type);
+    }
+    for service in &mut file.service {
+        clear_default_options(&mut service.options);
+        for method in &mut service.method {
+            clear_default_options(&mut method.options);
+        }
+    }
+    for extension in &mut file.extension {
+        canonicalize_field(extension, packed_by_default);
+    }
+    file
+}
+
+fn canonicalize_message(message: &mut DescriptorProto, packed_by_default: Option<bool>) {
+    clear_default_options(&mut message.options);
+    for field in message.field.iter_mut().chain(&mut message.extension) {
+        canonicalize_field(field, packed_by_default);
+    }
+    for oneof in &mut message.oneof_decl {
+        clear_default_options(&mut oneof.options);
+    }
+    for nested in &mut message.nested_type {
+        canonicalize_message(nested, packed_by_default);
+    }
+    for enum_type in &mut message.enum_type {
+        canonicalize_enum(enum_type);
+    }
+
+    // Message ranges have an exclusive end
+    message
+        .reserved_range
+        .sort_by_key(|range| (range.start(), range.end()));
+    let mut reserved: Vec<ReservedRange> = Vec::with_capacity(message.reserved_range.len());
+    for range in message.reserved_range.drain(..) {
+        match reserved.last_mut() {
+            Some(last) if range.start() <= last.end() => {
+                last.set_end(last.end().max(range.end()));
+            }
+            _ => reserved.push(range),
+        }
+    }
+    message.reserved_range = reserved;
+    message.reserved_name.sort();
+    message.reserved_name.dedup();
+
+    for range in &mut message.extension_range {
+        clear_default_options(&mut range.options);
+    }
+    message
+        .extension_range
+        .sort_by_key(|range| (range.start(), range.end()));
+    let mut extension_ranges: Vec<ExtensionRange> =
+        Vec::with_capacity(message.extension_range.len());
+    for range in message.extension_range.drain(..) {
+        match extension_ranges.last_mut() {
+            Some(last)
+                if range.start() <= last.end()
+                    && last.options.is_none()
+                    && range.options.is_none() =>
+            {
+                last.set_end(last.end().max(range.end()));
+            }
+            _ => extension_ranges.push(range),
+        }
+    }
+    message.extension_range = extension_ranges;
+}
+
+fn canonicalize_enum(enum_type: &mut EnumDescriptorProto) {
+    clear_default_options(&mut enum_type.options);
+    for value in &mut enum_type.value {
+        clear_default_options(&mut value.options);
+    }
+
+    // Enum ranges have an inclusive end
+    enum_type
+        .reserved_range
+        .sort_by_key(|range| (range.start(), range.end()));
+    let mut reserved: Vec<EnumReservedRange> = Vec::with_capacity(enum_type.reserved_range.len());
+    for range in enum_type.reserved_range.drain(..) {
+        match reserved.last_mut() {
+            Some(last) if i64::from(range.start()) <= i64::from(last.end()) + 1 => {
+                last.set_end(last.end().max(range.end()));
+            }
+            _ => reserved.push(range),
+        }
+    }
+    enum_type.reserved_range = reserved;
+    enum_type.reserved_name.sort();
+    enum_type.reserved_name.dedup();
+}
+
+fn canonicalize_field(field: &mut FieldDescriptorProto, packed_by_default: Option<bool>) {
+    if let Some(value) = field
+        .default_value
+        .as_deref()
+        .and_then(|value| canonical_default(field.type_(), value))
+    {
+        field.set_default_value(value);
+    }
+
+    let packable = field.label() == Label::LABEL_REPEATED
+        && !matches!(
+            field.type_(),
+            Type::TYPE_STRING | Type::TYPE_BYTES | Type::TYPE_MESSAGE | Type::TYPE_GROUP
+        );
+    if let (Some(options), Some(default)) = (field.options.as_mut(), packed_by_default) {
+        if packable && options.packed == Some(default) {
+            options.packed = None;
+        }
+    }
+    clear_default_options(&mut field.options);
+}
+
+/// Canonical spelling of a numeric default, or `None` to keep `value`.
+fn canonical_default(field_type: Type, value: &str) -> Option<String> {
+    match field_type {
+        Type::TYPE_INT32
+        | Type::TYPE_INT64
+        | Type::TYPE_UINT32
+        | Type::TYPE_UINT64
+        | Type::TYPE_SINT32
+        | Type::TYPE_SINT64
+        | Type::TYPE_FIXED32
+        | Type::TYPE_FIXED64
+        | Type::TYPE_SFIXED32
+        | Type::TYPE_SFIXED64 => parse_integer(value).map(|value| value.to_string()),
+        Type::TYPE_FLOAT | Type::TYPE_DOUBLE => canonical_float(value),
+        _ => None,
+    }
+}
+
+/// Parse a decimal, hexadecimal (`0x`) or octal (leading `0`) integer.
+fn parse_integer(value: &str) -> Option<i128> {
+    let (negative, digits) = match value.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, value),
+    };
+    let magnitude = if let Some(hex) = digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        u64::from_str_radix(hex, 16).ok()?
+    } else if digits.len() > 1 && digits.starts_with('0') {
+        u64::from_str_radix(&digits[1..], 8).ok()?
+    } else {
+        digits.parse().ok()?
+    };
+    let magnitude = i128::from(magnitude);
+    Some(if negative { -magnitude } else { magnitude })
+}
+
+fn canonical_float(value: &str) -> Option<String> {
+    let lower = value.to_ascii_lowercase();
+    let (negative, magnitude) = match lower.strip_prefix('-') {
+        Some(magnitude) => (true, magnitude),
+        None => (false, lower.as_str()),
+    };
+    match magnitude {
+        "inf" | "infinity" => return Some(if negative { "-inf" } else { "inf" }.to_string()),
+        "nan" => return Some("nan".to_string()),
+        _ => {}
+    }
+    let number = match parse_integer(value) {
+        Some(integer) => integer as f64,
+        None => value.parse::<f64>().ok()?,
+    };
+    // `Display` never uses an exponent, which is unwieldy at the extremes
+    let magnitude = number.abs();
+    Some(if magnitude != 0.0 && !(1e-5..1e16).contains(&magnitude) {
+        format!("{number:e}")
+    } else {
+        format!("{number}")
+    })
+}
+
+/// Clear every singular scalar or enum option of `options` that is set to
+/// its default value, then drop `options` if nothing is left.
+///
+/// `packed` is handled by the caller since its default depends on the
+/// syntax. Unknown fields (options newer than the `protobuf` crate and
+/// custom options) are kept.
+fn clear_default_options<M: MessageFull>(options: &mut MessageField<M>) {
+    let Some(message) = options.as_mut() else {
+        return;
+    };
+    let descriptor = M::descriptor();
+    let defaults = M::new();
+    for field in descriptor.fields() {
+        if field.name() == "packed"
+            || !matches!(field.runtime_field_type(), RuntimeFieldType::Singular(_))
+        {
+            continue;
+        }
+        let is_default = match field.get_singular(message) {
+            Some(ReflectValueRef::Message(_)) | None => false,
+            Some(value) => value == field.get_singular_field_or_default(&defaults),
+        };
+        if is_default {
+            field.clear_field(message);
+        }
+    }
+    if message.compute_size() == 0 {
+        *options = MessageField::none();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{descriptor_to_proto, parse_proto_to_file_descriptor};
+
+    fn canonical_text(content: &str) -> String {
+        let descriptor = parse_proto_to_file_descriptor(content).unwrap();
+        descriptor_to_proto(&canonicalize(&descriptor)).unwrap()
+    }
+
+    #[test]
+    fn test_equivalent_spellings_canonicalize_identically() {
+        let pairs = [
+            (
+                "syntax = \"proto3\"; message M { reserved 1, 2, 3, 5 to 7, 4; reserved \"b\", \"a\"; }",
+                "syntax = \"proto3\"; message M { reserved 1 to 7; reserved \"a\", \"b\", \"a\"; }",
+            ),
+            (
+                "syntax = \"proto3\"; enum E { ZERO = 0; reserved 1, 2, 3 to 4; }",
+                "syntax = \"proto3\"; enum E { ZERO = 0; reserved 1 to 4; }",
+            ),
+            (
+                "syntax = \"proto2\"; message M { optional int32 a = 1 [default = 0x10]; optional double b = 2 [default = 1.50]; }",
+                "syntax = \"proto2\"; message M { optional int32 a = 1 [default = 16]; optional double b = 2 [default = 1.5]; }",
+            ),
+            (
+                "syntax = \"proto3\"; option optimize_for = SPEED; option java_multiple_files = false; message M { repeated int32 a = 1 [packed = true, deprecated = false]; }",
+                "syntax = \"proto3\"; message M { repeated int32 a = 1; }",
+            ),
+            (
+                "syntax = \"proto2\"; message M { repeated int32 a = 1 [packed = false]; extensions 100 to 199; extensions 200 to 299; }",
+                "syntax = \"proto2\"; message M { repeated int32 a = 1; extensions 100 to 299; }",
+            ),
+        ];
+        for (left, right) in pairs {
+            assert_eq!(canonical_text(left), canonical_text(right), "{left}");
+        }
+
+        // Non-default values are kept
+        let text = canonical_text(
+            "syntax = \"proto3\"; option optimize_for = CODE_SIZE; option cc_enable_arenas = false; message M { repeated int32 a = 1 [packed = false]; }",
+        );
+        assert!(text.contains("option optimize_for = CODE_SIZE;"), "{text}");
+        assert!(text.contains("option cc_enable_arenas = false;"), "{text}");
+        assert!(text.contains("[packed = false]"), "{text}");
+    }
+
+    #[test]
+    fn test_canonical_default() {
+        assert_eq!(
+            canonical_default(Type::TYPE_INT32, "0x10").as_deref(),
+            Some("16")
+        );
+        assert_eq!(
+            canonical_default(Type::TYPE_INT64, "-0X1f").as_deref(),
+            Some("-31")
+        );
+        assert_eq!(
+            canonical_default(Type::TYPE_UINT32, "010").as_deref(),
+            Some("8")
+        );
+        assert_eq!(
+            canonical_default(Type::TYPE_DOUBLE, "1.50").as_deref(),
+            Some("1.5")
+        );
+        assert_eq!(
+            canonical_default(Type::TYPE_DOUBLE, "1e2").as_deref(),
+            Some("100")
+        );
+        assert_eq!(
+            canonical_default(Type::TYPE_FLOAT, "1e30").as_deref(),
+            Some("1e30")
+        );
+        assert_eq!(
+            canonical_default(Type::TYPE_FLOAT, "-Infinity").as_deref(),
+            Some("-inf")
+        );
+        assert_eq!(canonical_default(Type::TYPE_STRING, "0x10"), None);
+    }
+}
@@ -0,0 +1,645 @@
+//! Comments of `.proto` sources.
+//!
+//! Neither parser backend fills `source_code_info`, so it is rebuilt from the
+//! source text: declarations are located with the lexer, matched to their
+//! descriptor elements by name, and given the leading, trailing and detached
+//! comments `protoc` would attach to them. Only elements that can carry
+//! comments in the generated text get a location.
+
+use crate::lexer::{tokenize, Token, TokenKind};
+use anyhow::Result;
+use protobuf::descriptor::{
+    source_code_info::Location, DescriptorProto, EnumDescriptorProto, FieldDescriptorProto,
+    FileDescriptorProto, ServiceDescriptorProto, SourceCodeInfo,
+};
+
+// Field numbers used in location paths (see `descriptor.proto`)
+pub(crate) const FILE_PACKAGE: i32 = 2;
+pub(crate) const FILE_DEPENDENCY: i32 = 3;
+pub(crate) const FILE_MESSAGE_TYPE: i32 = 4;
+pub(crate) const FILE_ENUM_TYPE: i32 = 5;
+pub(crate) const FILE_SERVICE: i32 = 6;
+pub(crate) const FILE_EXTENSION: i32 = 7;
+pub(crate) const FILE_SYNTAX: i32 = 12;
+pub(crate) const MESSAGE_FIELD: i32 = 2;
+pub(crate) const MESSAGE_NESTED_TYPE: i32 = 3;
+pub(crate) const MESSAGE_ENUM_TYPE: i32 = 4;
+pub(crate) const MESSAGE_EXTENSION: i32 = 6;
+pub(crate) const MESSAGE_ONEOF_DECL: i32 = 8;
+pub(crate) const ENUM_VALUE: i32 = 2;
+pub(crate) const SERVICE_METHOD: i32 = 2;
+
+/// Build the `source_code_info` of `file` from its `source`.
+pub(crate) fn source_code_info(source: &str, file: &FileDescriptorProto) -> Result<SourceCodeInfo> {
+    let tokens = tokenize(source)?;
+    let mut walker = Walker {
+        significant: tokens
+            .iter()
+            .filter(|t| t.kind != TokenKind::Comment)
+            .cloned()
+            .collect(),
+        pos: 0,
+        elements: Vec::new(),
+    };
+    walker.walk_file(file);
+
+    let mut info = SourceCodeInfo::new();
+    info.location = attach_comments(&tokens, &walker.significant, walker.elements);
+    Ok(info)
+}
+
+/// A declaration found in the source.
+struct Element {
+    path: Vec<i32>,
+    /// First and last significant token
+    start: usize,
+    end: usize,
+    /// Token after which a comment on the same line is a trailing comment:
+    /// the `;` of simple declarations, the `{` of blocks
+    anchor: usize,
+}
+
+/// The messages that may hold the type of a group declared in a scope: the
+/// nested types of the enclosing message, or the top-level messages for
+/// extensions declared at file scope.
+#[derive(Clone, Copy)]
+struct Groups<'a> {
+    messages: &'a [DescriptorProto],
+    /// Path of the owner of `messages` and the field number holding them
+    path: &'a [i32],
+    kind: i32,
+}
+
+impl<'a> Groups<'a> {
+    fn nested(message: &'a DescriptorProto, path: &'a [i32]) -> Self {
+        Self {
+            messages: &message.nested_type,
+            path,
+            kind: MESSAGE_NESTED_TYPE,
+        }
+    }
+}
+
+struct Walker<'a> {
+    significant: Vec<Token<'a>>,
+    pos: usize,
+    elements: Vec<Element>,
+}
+
+impl Walker<'_> {
+    fn peek(&self) -> Option<&Token<'_>> {
+        self.significant.get(self.pos)
+    }
+
+    fn at_symbol(&self, symbol: char) -> bool {
+        self.peek().is_some_and(|t| t.is_symbol(symbol))
+    }
+
+    fn next_text(&mut self) -> String {
+        let text = self.peek().map(|t| t.text.to_string()).unwrap_or_default();
+        self.pos += 1;
+        text
+    }
+
+    /// Skip to the end of the current statement, past its `;` or past the
+    /// block it opens. Stops before an unbalanced `}`.
+    fn skip_statement(&mut self) -> usize {
+        let mut depth = 0usize;
+        while let Some(token) = self.peek() {
+            if token.is_symbol('{') || token.is_symbol('[') || token.is_symbol('(') {
+                depth += 1;
+            } else if token.is_symbol('}') || token.is_symbol(']') || token.is_symbol(')') {
+                if depth == 0 {
+                    break;
+                }
+                depth -= 1;
+                if depth == 0 && token.is_symbol('}') {
+                    self.pos += 1;
+                    return self.pos - 1;
+                }
+            } else if token.is_symbol(';') && depth == 0 {
+                self.pos += 1;
+                return self.pos - 1;
+            }
+            self.pos += 1;
+        }
+        self.pos.saturating_sub(1)
+    }
+
+    /// Skip tokens up to the next `{` or `;` outside brackets and parentheses.
+    fn skip_header(&mut self) {
+        let mut depth = 0usize;
+        while let Some(token) = self.peek() {
+            if token.is_symbol('[') || token.is_symbol('(') {
+                depth += 1;
+            } else if token.is_symbol(']') || token.is_symbol(')') {
+                depth = depth.saturating_sub(1);
+            } else if depth == 0 && (token.is_symbol('{') || token.is_symbol(';')) {
+                return;
+            }
+            self.pos += 1;
+        }
+    }
+
+    fn push(&mut self, path: Vec<i32>, start: usize, anchor: usize, end: usize) {
+        // Unterminated declarations only occur in sources the parser rejects
+        if end >= self.significant.len() {
+            return;
+        }
+        self.elements.push(Element {
+            path,
+            start,
+            end,
+            anchor,
+        });
+    }
+
+    /// Record a `;`-terminated declaration starting at `start`.
+    fn simple(&mut self, path: Option<Vec<i32>>, start: usize) {
+        let end = self.skip_statement();
+        if let Some(path) = path {
+            self.push(path, start, end, end);
+        }
+    }
+
+    fn walk_file(&mut self, file: &FileDescriptorProto) {
+        let mut imports = 0;
+        while let Some(token) = self.peek() {
+            let start = self.pos;
+            let keyword = token.text;
+            match keyword {
+                "syntax" | "edition" => self.simple(Some(vec![FILE_SYNTAX]), start),
+                "package" => self.simple(Some(vec![FILE_PACKAGE]), start),
+                "import" => {
+                    self.simple(Some(vec![FILE_DEPENDENCY, imports]), start);
+                    imports += 1;
+                }
+                "message" => {
+                    let name = self.name_after_keyword();
+                    let index = position(&file.message_type, |m| m.name() == name);
+                    self.walk_message(
+                        index.map(|i| (&file.message_type[i], vec![FILE_MESSAGE_TYPE, i as i32])),
+                        start,
+                    );
+                }
+                "enum" => {
+                    let name = self.name_after_keyword();
+                    let index = position(&file.enum_type, |e| e.name() == name);
+                    self.walk_enum(
+                        index.map(|i| (&file.enum_type[i], vec![FILE_ENUM_TYPE, i as i32])),
+                        start,
+                    );
+                }
+                "service" => {
+                    let name = self.name_after_keyword();
+                    let index = position(&file.service, |s| s.name() == name);
+                    self.walk_service(
+                        index.map(|i| (&file.service[i], vec![FILE_SERVICE, i as i32])),
+                        start,
+                    );
+                }
+                "extend" => {
+                    let groups = Groups {
+                        messages: &file.message_type,
+                        path: &[],
+                        kind: FILE_MESSAGE_TYPE,
+                    };
+                    self.walk_extend(&file.extension, &[FILE_EXTENSION], groups)
+                }
+                _ => self.simple(None, start),
+            }
+        }
+    }
+
+    /// Consume `keyword NAME` and return the name.
+    fn name_after_keyword(&mut self) -> String {
+        self.pos += 1;
+        self.next_text()
+    }
+
+    /// Walk a `message` (or group) body. The header up to `{` has been
+    /// partly consumed; `start` is the first token of the declaration.
+    fn walk_message(&mut self, message: Option<(&DescriptorProto, Vec<i32>)>, start: usize) {
+        self.skip_header();
+        let Some((message, path)) = message else {
+            self.skip_statement();
+            return;
+        };
+        if !self.at_symbol('{') {
+            self.skip_statement();
+            return;
+        }
+        let anchor = self.pos;
+        self.pos += 1;
+        self.walk_message_body(message, &path);
+        let end = self.pos;
+        self.pos += 1;
+        self.push(path, start, anchor, end);
+    }
+
+    /// Walk declarations up to the closing `}` of a message, leaving it
+    /// unconsumed.
+    fn walk_message_body(&mut self, message: &DescriptorProto, path: &[i32]) {
+        let child = |kind: i32, index: usize| {
+            let mut child = path.to_vec();
+            child.extend([kind, index as i32]);
+            child
+        };
+        while let Some(token) = self.peek() {
+            let start = self.pos;
+            match token.text {
+                "}" if token.kind == TokenKind::Symbol => return,
+                "message" => {
+                    let name = self.name_after_keyword();
+                    let index = position(&message.nested_type, |m| m.name() == name);
+                    self.walk_message(
+                        index.map(|i| (&message.nested_type[i], child(MESSAGE_NESTED_TYPE, i))),
+                        start,
+                    );
+                }
+                "enum" => {
+                    let name = self.name_after_keyword();
+                    let index = position(&message.enum_type, |e| e.name() == name);
+                    self.walk_enum(
+                        index.map(|i| (&message.enum_type[i], child(MESSAGE_ENUM_TYPE, i))),
+                        start,
+                    );
+                }
+                "extend" => {
+                    let mut prefix = path.to_vec();
+                    prefix.push(MESSAGE_EXTENSION);
+                    self.walk_extend(&message.extension, &prefix, Groups::nested(message, path));
+                }
+                "oneof" => {
+                    let name = self.name_after_keyword();
+                    let index = position(&message.oneof_decl, |o| o.name() == name);
+                    self.skip_header();
+                    match index {
+                        Some(i) if self.at_symbol('{') => {
+                            let anchor = self.pos;
+                            self.pos += 1;
+                            self.walk_fields(&message.field, path, message);
+                            let end = self.pos;
+                            self.pos += 1;
+                            self.push(child(MESSAGE_ONEOF_DECL, i), start, anchor, end);
+                        }
+                        _ => {
+                            self.skip_statement();
+                        }
+                    }
+                }
+                "option" | "reserved" | "extensions" | ";" => self.simple(None, start),
+                _ => self.walk_field(&message.field, path, true, Groups::nested(message, path)),
+            }
+        }
+    }
+
+    /// Walk field declarations up to a closing `}`, leaving it unconsumed.
+    fn walk_fields(
+        &mut self,
+        fields: &[FieldDescriptorProto],
+        prefix: &[i32],
+        scope: &DescriptorProto,
+    ) {
+        while let Some(token) = self.peek() {
+            let start = self.pos;
+            match token.text {
+                "}" if token.kind == TokenKind::Symbol => return,
+                "option" | ";" => self.simple(None, start),
+                _ => self.walk_field(fields, prefix, true, Groups::nested(scope, prefix)),
+            }
+        }
+    }
+
+    /// Walk one field (or group) declaration. `prefix` is the path of the
+    /// element owning `fields`; field paths are `prefix + [2, index]` for
+    /// message fields, `prefix + [index]` for extensions (where `is_field`
+    /// is false). The body of a group is looked up in `groups`.
+    fn walk_field(
+        &mut self,
+        fields: &[FieldDescriptorProto],
+        prefix: &[i32],
+        is_field: bool,
+        groups: Groups,
+    ) {
+        let start = self.pos;
+        let mut name = None;
+        let mut group = None;
+        let mut depth = 0usize;
+        while let Some(token) = self.peek() {
+            if token.is_symbol('[') || token.is_symbol('<') {
+                depth += 1;
+            } else if token.is_symbol(']') || token.is_symbol('>') {
+                depth = depth.saturating_sub(1);
+            } else if depth == 0 {
+                if token.is_symbol('=') && name.is_none() {
+                    name = self
+                        .significant
+                        .get(self.pos.wrapping_sub(1))
+                        .map(|t| t.text.to_string());
+                } else if token.is_ident("group") && name.is_none() {
+                    group = self
+                        .significant
+                        .get(self.pos + 1)
+                        .map(|t| t.text.to_string());
+                } else if token.is_symbol(';') || token.is_symbol('{') || token.is_symbol('}') {
+                    break;
+                }
+            }
+            self.pos += 1;
+        }
+        // Groups are declared by their type name; the field is lowercase
+        let field_name = match &group {
+            Some(type_name) => Some(type_name.to_lowercase()),
+            None => name,
+        };
+        let path = field_name
+            .and_then(|name| position(fields, |f| f.name() == name))
+            .map(|index| {
+                let mut path = prefix.to_vec();
+                if is_field {
+                    path.push(MESSAGE_FIELD);
+                }
+                path.push(index as i32);
+                path
+            });
+
+        if self.at_symbol('}') {
+            return;
+        }
+        let anchor = self.pos;
+        if self.at_symbol(';') {
+            self.pos += 1;
+            if let Some(path) = path {
+                self.push(path, start, anchor, anchor);
+            }
+            return;
+        }
+
+        // Group body: the message of the group type
+        let nested = group.and_then(|type_name| {
+            let index = position(groups.messages, |m| m.name() == type_name)?;
+            let mut nested_path = groups.path.to_vec();
+            nested_path.extend([groups.kind, index as i32]);
+            Some((&groups.messages[index], nested_path))
+        });
+        self.pos += 1;
+        match nested {
+            Some((message, nested_path)) => self.walk_message_body(message, &nested_path),
+            None => {
+                self.pos -= 1;
+                self.skip_statement();
+                return;
+            }
+        }
+        let end = self.pos;
+        self.pos += 1;
+        if let Some(path) = path {
+            self.push(path, start, anchor, end);
+        }
+    }
+
+    fn walk_extend(&mut self, extensions: &[FieldDescriptorProto], prefix: &[i32], groups: Groups) {
+        self.skip_header();
+        if !self.at_symbol('{') {
+            self.skip_statement();
+            return;
+        }
+        self.pos += 1;
+        while let Some(token) = self.peek() {
+            let start = self.pos;
+            match token.text {
+                "}" if token.kind == TokenKind::Symbol => break,
+                ";" => self.simple(None, start),
+                _ => self.walk_field(extensions, prefix, false, groups),
+            }
+        }
+        self.pos += 1;
+    }
+
+    fn walk_enum(&mut self, enum_type: Option<(&EnumDescriptorProto, Vec<i32>)>, start: usize) {
+        self.skip_header();
+        let Some((enum_type, path)) = enum_type.filter(|_| self.at_symbol('{')) else {
+            self.skip_statement();
+            return;
+        };
+        let anchor = self.pos;
+        self.pos += 1;
+        while let Some(token) = self.peek() {
+            let value_start = self.pos;
+            match token.text {
+                "}" if token.kind == TokenKind::Symbol => break,
+                "option" | "reserved" | ";" => self.simple(None, value_start),
+                name => {
+                    let value_path = position(&enum_type.value, |v| v.name() == name).map(|i| {
+                        let mut value_path = path.clone();
+                        value_path.extend([ENUM_VALUE, i as i32]);
+                        value_path
+                    });
+                    self.simple(value_path, value_start);
+                }
+            }
+        }
+        let end = self.pos;
+        self.pos += 1;
+        self.push(path, start, anchor, end);
+    }
+
+    fn walk_service(&mut self, service: Option<(&ServiceDescriptorProto, Vec<i32>)>, start: usize) {
+        self.skip_header();
+        let Some((service, path)) = service.filter(|_| self.at_symbol('{')) else {
+            self.skip_statement();
+            return;
+        };
+        let anchor = self.pos;
+        self.pos += 1;
+        while let Some(token) = self.peek() {
+            let method_start = self.pos;
+            match token.text {
+                "}" if token.kind == TokenKind::Symbol => break,
+                "rpc" => {
+                    let name = self.name_after_keyword();
+                    let method_path = position(&service.method, |m| m.name() == name).map(|i| {
+                        let mut method_path = path.clone();
+                        method_path.extend([SERVICE_METHOD, i as i32]);
+                        method_path
+                    });
+                    self.skip_header();
+                    let method_anchor = self.pos;
+                    let end = self.skip_statement();
+                    // `rpc M(A) returns (B) {}` may be followed by a stray `;`
+                    if self.significant[end].is_symbol('}') && self.at_symbol(';') {
+                        self.pos += 1;
+                    }
+                    if let Some(method_path) = method_path {
+                        self.push(method_path, method_start, method_anchor, end);
+                    }
+                }
+                _ => self.simple(None, method_start),
+            }
+        }
+        let end = self.pos;
+        self.pos += 1;
+        self.push(path, start, anchor, end);
+    }
+}
+
+fn position<T>(items: &[T], pred: impl Fn(&T) -> bool) -> Option<usize> {
+    items.iter().position(pred)
+}
+
+/// Turn found elements into locations, assigning every comment to the
+/// element it documents.
+fn attach_comments(
+    tokens: &[Token<'_>],
+    significant: &[Token<'_>],
+    elements: Vec<Element>,
+) -> Vec<Location> {
+    // Comments between consecutive significant tokens: gaps[i] holds the
+    // comments before significant token i (gaps[len] those at the end)
+    let mut gaps: Vec<Vec<&Token>> = vec![Vec::new(); significant.len() + 1];
+    let mut index = 0;
+    for token in tokens {
+        if token.kind == TokenKind::Comment {
+            gaps[index].push(token);
+        } else {
+            index += 1;
+        }
+    }
+
+    let mut locations = Vec::new();
+    for element in &elements {
+        let mut location = Location::new();
+        location.path = element.path.clone();
+        location.span = span(&significant[element.start], &significant[element.end]);
+
+        // Trailing: the first comment on the anchor's line
+        if let Some(comment) = gaps[element.anchor + 1].first() {
+            if comment.position.line == significant[element.anchor].position.line {
+                location.trailing_comments = Some(comment_text(comment));
+            }
+        }
+
+        // Leading and detached: blocks between the previous token and the start
+        let before = &gaps[element.start];
+        let previous_line = element
+            .start
+            .checked_sub(1)
+            .map(|i| significant[i].position.line);
+        let mut blocks: Vec<Vec<&Token>> = Vec::new();
+        for comment in before {
+            // Skip the trailing comment of the previous token
+            if Some(comment.position.line) == previous_line {
+                continue;
+            }
+            let joins = blocks.last().and_then(|b| b.last()).is_some_and(|last| {
+                last.text.starts_with("//")
+                    && comment.text.starts_with("//")
+                    && end_line(last) + 1 == comment.position.line
+            });
+            match blocks.last_mut() {
+                Some(block) if joins => block.push(comment),
+                _ => blocks.push(vec![comment]),
+            }
+        }
+        let start_line = significant[element.start].position.line;
+        if blocks
+            .last()
+            .and_then(|b| b.last())
+            .is_some_and(|last| end_line(last) + 1 >= start_line)
+        {
+            let block = blocks.pop().unwrap_or_default();
+            location.leading_comments = Some(block.iter().map(|c| comment_text(c)).collect());
+        }
+        location.leading_detached_comments = blocks
+            .iter()
+            .map(|block| block.iter().map(|c| comment_text(c)).collect())
+            .collect();
+
+        locations.push(location);
+    }
+    locations
+}
+
+fn end_line(token: &Token<'_>) -> usize {
+    token.position.line + token.text.matches('\n').count()
+}
+
+/// `[start line, start column, (end line,) end column]`, zero-based.
+fn span(start: &Token<'_>, end: &Token<'_>) -> Vec<i32> {
+    let start_line = start.position.line as i32 - 1;
+    let end_line = end.position.line as i32 - 1;
+    let end_column = (end.position.column + end.text.chars().count()) as i32 - 1;
+    let mut span = vec![start_line, start.position.column as i32 - 1];
+    if end_line != start_line {
+        span.push(end_line);
+    }
+    span.push(end_column);
+    span
+}
+
+/// Comment text as stored in `source_code_info`: the delimiters removed,
+/// one `\n`-terminated line per source line. Block comments spanning several
+/// lines also lose the `*` starting their lines and their empty first and
+/// last lines (as in `/**\n * Doc\n */`).
+fn comment_text(token: &Token<'_>) -> String {
+    if let Some(line) = token.text.strip_prefix("//") {
+        return format!("{}\n", line.trim_end_matches('\r'));
+    }
+    let inner = token
+        .text
+        .strip_prefix("/*")
+        .and_then(|t| t.strip_suffix("*/"))
+        .unwrap_or(token.text);
+    if !inner.contains('\n') {
+        return inner.to_string();
+    }
+    let mut lines: Vec<&str> = inner
+        .lines()
+        .enumerate()
+        .map(|(i, line)| match i {
+            0 => line.trim_start_matches('*'),
+            _ => {
+                let line = line.trim_start();
+                line.strip_prefix('*').unwrap_or(line)
+            }
+        })
+        .collect();
+    if lines.first().is_some_and(|line| line.trim().is_empty()) {
+        lines.remove(0);
+    }
+    if lines.last().is_some_and(|line| line.trim().is_empty()) {
+        lines.pop();
+    }
+    lines.iter().map(|line| format!("{line}\n")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ParseSession;
+
+    #[test]
+    fn test_source_code_info_paths_and_comments() {
+        let source = "syntax = \"proto3\";\n\n// Detached\n\n// Leading\nmessage M { // Trailing\n  \
+                      reserved 5;\n  /* Field */\n  string a = 1;\n  oneof o {\n    int32 b = 2;\n  }\n}\n";
+        let file = ParseSession::new().parse(source).unwrap();
+        let info = source_code_info(source, &file).unwrap();
+        let find = |path: &[i32]| info.location.iter().find(|l| l.path == path).unwrap();
+
+        let message = find(&[FILE_MESSAGE_TYPE, 0]);
+        assert_eq!(message.span, vec![5, 0, 12, 1]);
+        assert_eq!(message.leading_detached_comments, vec![" Detached\n"]);
+        assert_eq!(message.leading_comments(), " Leading\n");
+        assert_eq!(message.trailing_comments(), " Trailing\n");
+
+        let field = find(&[FILE_MESSAGE_TYPE, 0, MESSAGE_FIELD, 0]);
+        assert_eq!(field.span, vec![8, 2, 15]);
+        assert_eq!(field.leading_comments(), " Field ");
+        assert!(find(&[FILE_MESSAGE_TYPE, 0, MESSAGE_ONEOF_DECL, 0])
+            .trailing_comments
+            .is_none());
+        find(&[FILE_MESSAGE_TYPE, 0, MESSAGE_FIELD, 1]);
+        find(&[FILE_SYNTAX]);
+    }
+}
@@ -0,0 +1,387 @@
+//! Custom (extension) options.
+//!
+//! The `*Options` messages of the `protobuf` crate only know the fields of
+//! `descriptor.proto`, so custom options such as `(google.api.http)` are kept
+//! as unknown fields, whichever backend parsed the file. [`ExtensionIndex`]
+//! collects the extensions, messages and enums declared by a file and its
+//! imports so that those unknown fields can be decoded and rendered back as
+//! option text.
+
+use crate::text_gen::TextGenerator;
+use anyhow::{bail, Context, Result};
+use protobuf::descriptor::{
+    field_descriptor_proto::{Label, Type},
+    DescriptorProto, EnumDescriptorProto, FieldDescriptorProto, FileDescriptorProto,
+};
+use protobuf::well_known_types::empty::Empty;
+use protobuf::{Message, MessageDyn, UnknownFields, UnknownValueRef};
+use std::collections::BTreeMap;
+
+/// Start of the extension range of every `*Options` message. Unknown fields
+/// below it are `descriptor.proto` fields the `protobuf` crate predates
+/// (such as `features`), not custom options.
+pub(crate) const FIRST_EXTENSION_NUMBER: u32 = 1000;
+
+/// What [`ExtensionIndex::render`] does with a custom option whose extension
+/// is not in the index, e.g. one declared in an import that was not provided.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Unresolved {
+    /// Fail the rendering
+    Fail,
+    /// Leave the option out, with a warning
+    Skip,
+    /// Render its raw values by field number, as `(50000) = "bytes"`. The
+    /// text does not parse; it only keeps the option in fingerprints.
+    Raw,
+}
+
+/// Extensions, messages and enums of a set of files, by full name.
+///
+/// Full names carry a leading dot, like the `type_name` and `extendee` of
+/// parsed descriptors.
+#[derive(Debug, Default)]
+pub(crate) struct ExtensionIndex {
+    /// Extension fields by (extendee, number), with the extension full name
+    extensions: BTreeMap<(String, i32), (String, FieldDescriptorProto)>,
+    messages: BTreeMap<String, DescriptorProto>,
+    enums: BTreeMap<String, EnumDescriptorProto>,
+}
+
+impl ExtensionIndex {
+    pub(crate) fn new<'a>(files: impl IntoIterator<Item = &'a FileDescriptorProto>) -> Self {
+        let mut index = Self::default();
+        for file in files {
+            let scope = match file.package() {
+                "" => String::new(),
+                package => format!(".{package}"),
+            };
+            index.add_extensions(&scope, &file.extension);
+            index.add_enums(&scope, &file.enum_type);
+            index.add_messages(&scope, &file.message_type);
+        }
+        index
+    }
+
+    fn add_messages(&mut self, scope: &str, messages: &[DescriptorProto]) {
+        for message in messages {
+            let name = format!("{scope}.{}", message.name());
+            self.add_extensions(&name, &message.extension);
+            self.add_enums(&name, &message.enum_type);
+            self.add_messages(&name, &message.nested_type);
+            self.messages.insert(name, message.clone());
+        }
+    }
+
+    fn add_enums(&mut self, scope: &str, enums: &[EnumDescriptorProto]) {
+        for enum_type in enums {
+            self.enums
+                .insert(format!("{scope}.{}", enum_type.name()), enum_type.clone());
+        }
+    }
+
+    fn add_extensions(&mut self, scope: &str, extensions: &[FieldDescriptorProto]) {
+        for extension in extensions {
+            self.extensions.insert(
+                (extension.extendee().to_string(), extension.number()),
+                (format!("{scope}.{}", extension.name()), extension.clone()),
+            );
+        }
+    }
+
+    /// Render the custom options set on `options` as `(name) = value`
+    /// entries, sorted by extension name.
+    ///
+    /// Repeated options yield one entry per value, in their original order.
+    /// Message values are rendered as single-line aggregates
+    /// (`(name) = { field: value }`).
+    ///
+    /// Options whose extension is not in the index are handled according to
+    /// `unresolved`.
+    pub(crate) fn render(
+        &self,
+        options: &dyn MessageDyn,
+        unresolved: Unresolved,
+    ) -> Result<Vec<String>> {
+        let extendee = format!(".{}", options.descriptor_dyn().full_name());
+        let mut rendered = Vec::new();
+        for (number, values) in group_by_number(options.unknown_fields_dyn()) {
+            if number < FIRST_EXTENSION_NUMBER {
+                continue;
+            }
+            let Some((name, extension)) = self.extensions.get(&(extendee.clone(), number as i32))
+            else {
+                let extendee = extendee.trim_start_matches('.');
+                match unresolved {
+                    Unresolved::Fail => bail!(
+                        "Cannot render custom option {number} of {extendee}: its extension is not \
+                         defined in the file or its imports"
+                    ),
+                    Unresolved::Skip => log::warn!(
+                        "Skipping custom option {number} of {extendee}: its extension is not \
+                         defined in the file or its imports"
+                    ),
+                    Unresolved::Raw => {
+                        rendered.extend(values.iter().map(|v| (number.to_string(), render_raw(v))))
+                    }
+                }
+                continue;
+            };
+            let name = name.trim_start_matches('.');
+            for value in self
+                .render_values(extension, &values)
+                .with_context(|| format!("Malformed custom option ({name})"))?
+            {
+                rendered.push((name.to_string(), value));
+            }
+        }
+        rendered.sort_by(|a, b| a.0.cmp(&b.0));
+
+        Ok(rendered
+            .into_iter()
+            .map(|(name, value)| format!("({name}) = {value}"))
+            .collect())
+    }
+
+    /// Render the values of one field, one string per element.
+    fn render_values(
+        &self,
+        field: &FieldDescriptorProto,
+        values: &[UnknownValueRef],
+    ) -> Result<Vec<String>> {
+        let repeated = field.label() == Label::LABEL_REPEATED;
+        let mut rendered = Vec::new();
+
+        if field.type_() == Type::TYPE_MESSAGE {
+            let mut chunks = Vec::new();
+            for value in values {
+                let UnknownValueRef::LengthDelimited(bytes) = value else {
+                    bail!("expected a length-delimited value for {}", field.name());
+                };
+                chunks.push(bytes.to_vec());
+            }
+            // A singular message set several times is the merge of all of them
+            if !repeated && chunks.len() > 1 {
+                chunks = vec![chunks.concat()];
+            }
+            for bytes in chunks {
+                rendered.push(self.render_message(field.type_name(), &bytes)?);
+            }
+        } else {
+            for value in values {
+                match value {
+                    UnknownValueRef::LengthDelimited(bytes)
+                        if !matches!(field.type_(), Type::TYPE_STRING | Type::TYPE_BYTES) =>
+                    {
+                        for element in unpack(field.type_(), bytes)? {
+                            rendered.push(self.render_scalar(field, &element)?);
+                        }
+                    }
+                    value => rendered.push(self.render_scalar(field, value)?),
+                }
+            }
+            // A singular scalar set several times keeps the last value
+            if !repeated && rendered.len() > 1 {
+                rendered.drain(..rendered.len() - 1);
+            }
+        }
+
+        Ok(rendered)
+    }
+
+    /// Render a serialized message of type `type_name` as a text-format
+    /// aggregate: `{ a: 1 b { c: "x" } [pkg.ext]: 2 }`.
+    fn render_message(&self, type_name: &str, bytes: &[u8]) -> Result<String> {
+        let message = self.messages.get(type_name).with_context(|| {
+            format!(
+                "message type {} is not defined",
+                type_name.trim_start_matches('.')
+            )
+        })?;
+        let fields = Empty::parse_from_bytes(bytes)
+            .with_context(|| format!("Malformed {} value", type_name.trim_start_matches('.')))?;
+
+        let mut parts = Vec::new();
+        for (number, values) in group_by_number(fields.special_fields.unknown_fields()) {
+            let (name, field) = match message.field.iter().find(|f| f.number() == number as i32) {
+                Some(field) => (field.name().to_string(), field),
+                None => match self.extensions.get(&(type_name.to_string(), number as i32)) {
+                    Some((name, extension)) => {
+                        (format!("[{}]", name.trim_start_matches('.')), extension)
+                    }
+                    None => bail!(
+                        "unknown field {number} in {} value",
+                        type_name.trim_start_matches('.')
+                    ),
+                },
+            };
+            let separator = if field.type_() == Type::TYPE_MESSAGE {
+                " "
+            } else {
+                ": "
+            };
+            for value in self.render_values(field, &values)? {
+                parts.push(format!("{name}{separator}{value}"));
+            }
+        }
+
+        Ok(match parts.is_empty() {
+            true => "{}".to_string(),
+            false => format!("{{ {} }}", parts.join(" ")),
+        })
+    }
+
+    fn render_scalar(
+        &self,
+        field: &FieldDescriptorProto,
+        value: &UnknownValueRef,
+    ) -> Result<String> {
+        Ok(match (field.type_(), value) {
+            (Type::TYPE_INT32, UnknownValueRef::Varint(v)) => (*v as i32).to_string(),
+            (Type::TYPE_INT64, UnknownValueRef::Varint(v)) => (*v as i64).to_string(),
+            (Type::TYPE_UINT32, UnknownValueRef::Varint(v)) => (*v as u32).to_string(),
+            (Type::TYPE_UINT64, UnknownValueRef::Varint(v)) => v.to_string(),
+            (Type::TYPE_SINT32, UnknownValueRef::Varint(v)) => {
+                (((*v >> 1) as i32) ^ -((*v & 1) as i32)).to_string()
+            }
+            (Type::TYPE_SINT64, UnknownValueRef::Varint(v)) => {
+                (((*v >> 1) as i64) ^ -((*v & 1) as i64)).to_string()
+            }
+            (Type::TYPE_BOOL, UnknownValueRef::Varint(v)) => (*v != 0).to_string(),
+            (Type::TYPE_ENUM, UnknownValueRef::Varint(v)) => {
+                let number = *v as i32;
+                self.enums
+                    .get(field.type_name())
+                    .and_then(|e| e.value.iter().find(|value| value.number() == number))
+                    .map_or_else(|| number.to_string(), |value| value.name().to_string())
+            }
+            (Type::TYPE_FIXED32, UnknownValueRef::Fixed32(v)) => v.to_string(),
+            (Type::TYPE_SFIXED32, UnknownValueRef::Fixed32(v)) => (*v as i32).to_string(),
+            (Type::TYPE_FLOAT, UnknownValueRef::Fixed32(v)) => format_float(f32::from_bits(*v)),
+            (Type::TYPE_FIXED64, UnknownValueRef::Fixed64(v)) => v.to_string(),
+            (Type::TYPE_SFIXED64, UnknownValueRef::Fixed64(v)) => (*v as i64).to_string(),
+            (Type::TYPE_DOUBLE, UnknownValueRef::Fixed64(v)) => format_float(f64::from_bits(*v)),
+            (Type::TYPE_STRING, UnknownValueRef::LengthDelimited(bytes)) => {
+                match std::str::from_utf8(bytes) {
+                    Ok(s) => format!("\"{}\"", TextGenerator::escape_string(s)),
+                    Err(_) => format!("\"{}\"", TextGenerator::escape_bytes(bytes)),
+                }
+            }
+            (Type::TYPE_BYTES, UnknownValueRef::LengthDelimited(bytes)) => {
+                format!("\"{}\"", TextGenerator::escape_bytes(bytes))
+            }
+            (type_, value) => bail!(
+                "unexpected {:?} value for {type_:?} field {}",
+                value.wire_type(),
+                field.name()
+            ),
+        })
+    }
+}
+
+/// The wire value of an option whose type is unknown.
+fn render_raw(value: &UnknownValueRef) -> String {
+    match value {
+        UnknownValueRef::Varint(v) => v.to_string(),
+        UnknownValueRef::Fixed32(v) => format!("0x{v:08x}"),
+        UnknownValueRef::Fixed64(v) => format!("0x{v:016x}"),
+        UnknownValueRef::LengthDelimited(bytes) => {
+            format!("\"{}\"", TextGenerator::escape_bytes(bytes))
+        }
+    }
+}
+
+/// Group unknown fields by number, in ascending order.
+pub(crate) fn group_by_number(fields: &UnknownFields) -> BTreeMap<u32, Vec<UnknownValueRef<'_>>> {
+    let mut grouped: BTreeMap<u32, Vec<UnknownValueRef>> = BTreeMap::new();
+    for (number, value) in fields.iter() {
+        grouped.entry(number).or_default().push(value);
+    }
+    grouped
+}
+
+/// Split a packed repeated scalar field into its elements.
+fn unpack(type_: Type, bytes: &[u8]) -> Result<Vec<UnknownValueRef<'static>>> {
+    let mut stream = protobuf::CodedInputStream::from_bytes(bytes);
+    let mut elements = Vec::new();
+    while !stream.eof()? {
+        elements.push(match type_ {
+            Type::TYPE_FIXED32 | Type::TYPE_SFIXED32 | Type::TYPE_FLOAT => {
+                UnknownValueRef::Fixed32(stream.read_fixed32()?)
+            }
+            Type::TYPE_FIXED64 | Type::TYPE_SFIXED64 | Type::TYPE_DOUBLE => {
+                UnknownValueRef::Fixed64(stream.read_fixed64()?)
+            }
+            _ => UnknownValueRef::Varint(stream.read_raw_varint64()?),
+        });
+    }
+    Ok(elements)
+}
+
+fn format_float<T: Copy + Into<f64> + std::fmt::Debug>(value: T) -> String {
+    if value.into().is_nan() {
+        "nan".to_string()
+    } else {
+        format!("{value:?}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protobuf::descriptor::FieldOptions;
+
+    #[test]
+    fn test_render_packed_and_merged_values() {
+        let options = r#"
+syntax = "proto2";
+package opt;
+import "google/protobuf/descriptor.proto";
+message Range { optional int32 min = 1; optional int32 max = 2; }
+extend google.protobuf.FieldOptions {
+  repeated sint32 offsets = 60000;
+  optional Range range = 60001;
+  optional float scale = 60002;
+}
+"#;
+        let file = crate::ParseSession::new()
+            .with_file_name("opt.proto")
+            .parse(options)
+            .unwrap();
+        let index = ExtensionIndex::new([&file]);
+
+        let mut field_options = FieldOptions::new();
+        let unknown = field_options.mut_unknown_fields();
+        // Packed [-1, 2]
+        unknown.add_length_delimited(60000, vec![1, 4]);
+        // Two partial messages merge into `{ min: 1 max: 2 }`
+        unknown.add_length_delimited(60001, vec![8, 1]);
+        unknown.add_length_delimited(60001, vec![16, 2]);
+        unknown.add_fixed32(60002, 0.25f32.to_bits());
+
+        assert_eq!(
+            index.render(&field_options, Unresolved::Fail).unwrap(),
+            vec![
+                "(opt.offsets) = -1",
+                "(opt.offsets) = 2",
+                "(opt.range) = { min: 1 max: 2 }",
+                "(opt.scale) = 0.25",
+            ]
+        );
+
+        field_options.mut_unknown_fields().add_varint(60003, 1);
+        let err = index.render(&field_options, Unresolved::Fail).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Cannot render custom option 60003"));
+        assert_eq!(
+            index
+                .render(&field_options, Unresolved::Skip)
+                .unwrap()
+                .len(),
+            4
+        );
+        let raw = index.render(&field_options, Unresolved::Raw).unwrap();
+        assert_eq!(raw[0], "(60003) = 1");
+        assert_eq!(raw.len(), 5);
+    }
+}
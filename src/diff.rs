@@ -0,0 +1,612 @@
+//! Field-by-field comparison of file descriptors.
+//!
+//! Walks two descriptors with protobuf reflection and reports every element
+//! that is present on only one side or has a different value. Repeated
+//! elements that carry a `name` are matched by name, so reordering
+//! declarations is not reported as a difference; reserved and extension
+//! ranges are compared in sorted order. Indices into other lists
+//! (public and weak dependencies, `oneof_index`) are compared by what they
+//! point to. `source_code_info` is always ignored.
+
+use protobuf::descriptor::{DescriptorProto, FieldDescriptorProto, FileDescriptorProto};
+use protobuf::reflect::{
+    FieldDescriptor, MessageDescriptor, ReflectValueRef, RuntimeFieldType, RuntimeType,
+};
+use protobuf::{MessageDyn, UnknownValueRef};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// How an element differs between the two compared descriptors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DifferenceKind {
+    /// Present only in the left descriptor
+    Missing,
+    /// Present only in the right descriptor
+    Added,
+    /// Present in both with different values
+    Changed,
+}
+
+/// A single difference between two descriptors.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DescriptorDifference {
+    /// Path to the element, e.g. `message_type[User].field[name].json_name`
+    pub path: String,
+    pub kind: DifferenceKind,
+    /// Value in the left descriptor, if present
+    pub left: Option<String>,
+    /// Value in the right descriptor, if present
+    pub right: Option<String>,
+}
+
+impl fmt::Display for DescriptorDifference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let left = self.left.as_deref().unwrap_or("<unset>");
+        let right = self.right.as_deref().unwrap_or("<unset>");
+        match self.kind {
+            DifferenceKind::Missing => write!(f, "{}: missing (was {left})", self.path),
+            DifferenceKind::Added => write!(f, "{}: added ({right})", self.path),
+            DifferenceKind::Changed => write!(f, "{}: {left} -> {right}", self.path),
+        }
+    }
+}
+
+/// Compare two file descriptors, ignoring `source_code_info`.
+pub fn diff_file_descriptors(
+    left: &FileDescriptorProto,
+    right: &FileDescriptorProto,
+) -> Vec<DescriptorDifference> {
+    let mut left = left.clone();
+    let mut right = right.clone();
+    left.source_code_info.clear();
+    right.source_code_info.clear();
+    resolve_dependency_indices(&mut left);
+    resolve_dependency_indices(&mut right);
+
+    let mut out = Vec::new();
+    diff_messages("", &left, &right, Oneofs::default(), &mut out);
+    out
+}
+
+/// Oneof names of the messages enclosing the compared elements, on the left
+/// and on the right, to compare `oneof_index` by name: oneofs are matched by
+/// name, so the same oneof may have a different index on each side.
+#[derive(Clone, Copy, Default)]
+struct Oneofs<'a> {
+    left: &'a [String],
+    right: &'a [String],
+}
+
+fn oneof_names(message: &DescriptorProto) -> Vec<String> {
+    message
+        .oneof_decl
+        .iter()
+        .map(|oneof| oneof.name().to_string())
+        .collect()
+}
+
+/// Name of the oneof at `index` in `names`, or the index itself if there is
+/// no such oneof.
+fn oneof_name(value: ReflectValueRef, names: &[String]) -> String {
+    match value {
+        ReflectValueRef::I32(index) => usize::try_from(index)
+            .ok()
+            .and_then(|index| names.get(index))
+            .cloned()
+            .unwrap_or_else(|| index.to_string()),
+        value => format_value(value),
+    }
+}
+
+/// Public and weak dependencies are stored as indices into `dependency`,
+/// which change whenever imports are reordered. Reorder `dependency` so that
+/// indices are stable for the same set of imports.
+fn resolve_dependency_indices(file: &mut FileDescriptorProto) {
+    let public: Vec<String> = file
+        .public_dependency
+        .iter()
+        .filter_map(|&i| file.dependency.get(i as usize).cloned())
+        .collect();
+    let weak: Vec<String> = file
+        .weak_dependency
+        .iter()
+        .filter_map(|&i| file.dependency.get(i as usize).cloned())
+        .collect();
+
+    file.dependency.sort();
+    let index_of = |name: &String, deps: &[String]| {
+        deps.iter().position(|d| d == name).unwrap_or_default() as i32
+    };
+    let mut public: Vec<i32> = public
+        .iter()
+        .map(|d| index_of(d, &file.dependency))
+        .collect();
+    let mut weak: Vec<i32> = weak.iter().map(|d| index_of(d, &file.dependency)).collect();
+    public.sort();
+    weak.sort();
+    file.public_dependency = public;
+    file.weak_dependency = weak;
+}
+
+fn diff_messages(
+    path: &str,
+    left: &dyn MessageDyn,
+    right: &dyn MessageDyn,
+    oneofs: Oneofs,
+    out: &mut Vec<DescriptorDifference>,
+) {
+    let names;
+    let oneofs = match (
+        left.downcast_ref::<DescriptorProto>(),
+        right.downcast_ref::<DescriptorProto>(),
+    ) {
+        (Some(l), Some(r)) => {
+            names = (oneof_names(l), oneof_names(r));
+            Oneofs {
+                left: &names.0,
+                right: &names.1,
+            }
+        }
+        _ => oneofs,
+    };
+    let is_field = left.downcast_ref::<FieldDescriptorProto>().is_some();
+
+    let descriptor = left.descriptor_dyn();
+    for field in descriptor.fields() {
+        let field_path = join_path(path, field.name());
+        match field.runtime_field_type() {
+            RuntimeFieldType::Singular(_) if is_field && field.name() == "oneof_index" => {
+                let l = field.get_singular(left).map(|v| oneof_name(v, oneofs.left));
+                let r = field
+                    .get_singular(right)
+                    .map(|v| oneof_name(v, oneofs.right));
+                if l != r {
+                    push(out, field_path, l, r);
+                }
+            }
+            RuntimeFieldType::Singular(_) => {
+                match (field.get_singular(left), field.get_singular(right)) {
+                    (None, None) => {}
+                    (Some(l), Some(r)) => diff_values(&field_path, l, r, oneofs, out),
+                    (l, r) => push(out, field_path, l.map(format_value), r.map(format_value)),
+                }
+            }
+            RuntimeFieldType::Repeated(elem) => {
+                let l = field.get_repeated(left);
+                let r = field.get_repeated(right);
+                let l: Vec<_> = (0..l.len()).map(|i| l.get(i)).collect();
+                let r: Vec<_> = (0..r.len()).map(|i| r.get(i)).collect();
+                diff_repeated(&field_path, &elem, l, r, oneofs, out);
+            }
+            // Descriptor messages have no map fields
+            RuntimeFieldType::Map(..) => {}
+        }
+    }
+
+    diff_unknown_fields(path, left, right, out);
+}
+
+fn diff_values(
+    path: &str,
+    left: ReflectValueRef,
+    right: ReflectValueRef,
+    oneofs: Oneofs,
+    out: &mut Vec<DescriptorDifference>,
+) {
+    match (&left, &right) {
+        (ReflectValueRef::Message(l), ReflectValueRef::Message(r)) => {
+            diff_messages(path, &**l, &**r, oneofs, out)
+        }
+        _ => {
+            let (l, r) = (format_value(left), format_value(right));
+            if l != r {
+                push(out, path.to_string(), Some(l), Some(r));
+            }
+        }
+    }
+}
+
+fn diff_repeated(
+    path: &str,
+    elem: &RuntimeType,
+    mut left: Vec<ReflectValueRef>,
+    mut right: Vec<ReflectValueRef>,
+    oneofs: Oneofs,
+    out: &mut Vec<DescriptorDifference>,
+) {
+    let named = matches!(elem, RuntimeType::Message(m) if name_field(m).is_some());
+    if named {
+        let key = |v: &ReflectValueRef| match v {
+            ReflectValueRef::Message(m) => element_name(&**m),
+            _ => String::new(),
+        };
+        // Each element on the right matches at most one on the left, so a
+        // name declared twice on one side is reported
+        let right_keys: Vec<String> = right.iter().map(key).collect();
+        let mut matched = vec![false; right.len()];
+        for l in &left {
+            let name = key(l);
+            let item_path = format!("{path}[{name}]");
+            match (0..right.len()).find(|&idx| !matched[idx] && right_keys[idx] == name) {
+                Some(idx) => {
+                    matched[idx] = true;
+                    diff_values(&item_path, l.clone(), right[idx].clone(), oneofs, out)
+                }
+                None => push(out, item_path, Some(format_value(l.clone())), None),
+            }
+        }
+        for (idx, r) in right.iter().enumerate() {
+            if !matched[idx] {
+                let item_path = format!("{path}[{}]", right_keys[idx]);
+                push(out, item_path, None, Some(format_value(r.clone())));
+            }
+        }
+        return;
+    }
+
+    if let RuntimeType::Message(m) = elem {
+        // Reserved and extension ranges are sets, compared in sorted order
+        if let Some((start, end)) = range_fields(m) {
+            let key = |v: &ReflectValueRef| match v {
+                ReflectValueRef::Message(m) => (
+                    start.get_singular_field_or_default(&**m).to_i32(),
+                    end.get_singular_field_or_default(&**m).to_i32(),
+                ),
+                _ => (None, None),
+            };
+            left.sort_by_key(key);
+            right.sort_by_key(key);
+        }
+        let len = left.len().max(right.len());
+        for idx in 0..len {
+            let item_path = format!("{path}[{idx}]");
+            match (left.get(idx), right.get(idx)) {
+                (Some(l), Some(r)) => diff_values(&item_path, l.clone(), r.clone(), oneofs, out),
+                (l, r) => push(
+                    out,
+                    item_path,
+                    l.cloned().map(format_value),
+                    r.cloned().map(format_value),
+                ),
+            }
+        }
+        return;
+    }
+
+    // Scalar lists (dependencies, reserved names) compare as sorted sets
+    let mut l: Vec<String> = left.into_iter().map(format_value).collect();
+    let mut r: Vec<String> = right.into_iter().map(format_value).collect();
+    l.sort();
+    r.sort();
+    if l != r {
+        push(
+            out,
+            path.to_string(),
+            Some(format!("[{}]", l.join(", "))),
+            Some(format!("[{}]", r.join(", "))),
+        );
+    }
+}
+
+fn diff_unknown_fields(
+    path: &str,
+    left: &dyn MessageDyn,
+    right: &dyn MessageDyn,
+    out: &mut Vec<DescriptorDifference>,
+) {
+    // Values of each field number, compared as sorted multisets
+    let mut values: BTreeMap<u32, (Vec<String>, Vec<String>)> = BTreeMap::new();
+    for (number, value) in left.unknown_fields_dyn().iter() {
+        values
+            .entry(number)
+            .or_default()
+            .0
+            .push(format_unknown(value));
+    }
+    for (number, value) in right.unknown_fields_dyn().iter() {
+        values
+            .entry(number)
+            .or_default()
+            .1
+            .push(format_unknown(value));
+    }
+
+    for (number, (mut left, mut added)) in values {
+        left.sort();
+        added.sort();
+        let mut missing = Vec::new();
+        for value in left {
+            match added.iter().position(|v| *v == value) {
+                Some(index) => {
+                    added.remove(index);
+                }
+                None => missing.push(value),
+            }
+        }
+
+        // Unmatched values on both sides pair up as changes
+        let item_path = join_path(path, &format!("({number})"));
+        let changed = missing.len().max(added.len());
+        let mut missing = missing.into_iter();
+        let mut added = added.into_iter();
+        for _ in 0..changed {
+            push(out, item_path.clone(), missing.next(), added.next());
+        }
+    }
+}
+
+/// The `name` field of `descriptor`, if it is a singular string. Elements
+/// with such a field are matched by name; others, such as
+/// `UninterpretedOption` whose `name` is a repeated `NamePart`, by position.
+fn name_field(descriptor: &MessageDescriptor) -> Option<FieldDescriptor> {
+    descriptor.field_by_name("name").filter(|field| {
+        matches!(
+            field.runtime_field_type(),
+            RuntimeFieldType::Singular(RuntimeType::String)
+        )
+    })
+}
+
+/// The `start` and `end` fields of a range message such as `ReservedRange`
+/// or `ExtensionRange`.
+fn range_fields(descriptor: &MessageDescriptor) -> Option<(FieldDescriptor, FieldDescriptor)> {
+    Some((
+        descriptor.field_by_name("start")?,
+        descriptor.field_by_name("end")?,
+    ))
+}
+
+fn element_name(message: &dyn MessageDyn) -> String {
+    name_field(&message.descriptor_dyn())
+        .and_then(|f| f.get_singular(message))
+        .map(format_value)
+        .unwrap_or_default()
+}
+
+fn format_value(value: ReflectValueRef) -> String {
+    match value {
+        ReflectValueRef::U32(v) => v.to_string(),
+        ReflectValueRef::U64(v) => v.to_string(),
+        ReflectValueRef::I32(v) => v.to_string(),
+        ReflectValueRef::I64(v) => v.to_string(),
+        ReflectValueRef::F32(v) => v.to_string(),
+        ReflectValueRef::F64(v) => v.to_string(),
+        ReflectValueRef::Bool(v) => v.to_string(),
+        ReflectValueRef::String(v) => v.to_string(),
+        ReflectValueRef::Bytes(v) => format!("{v:?}"),
+        ReflectValueRef::Enum(descriptor, number) => descriptor
+            .value_by_number(number)
+            .map(|v| v.name().to_string())
+            .unwrap_or_else(|| number.to_string()),
+        ReflectValueRef::Message(m) => {
+            let name = element_name(&*m);
+            if name.is_empty() {
+                protobuf::text_format::print_to_string(&*m)
+            } else {
+                name
+            }
+        }
+    }
+}
+
+fn format_unknown(value: UnknownValueRef) -> String {
+    match value {
+        UnknownValueRef::Fixed32(v) => format!("fixed32 {v}"),
+        UnknownValueRef::Fixed64(v) => format!("fixed64 {v}"),
+        UnknownValueRef::Varint(v) => format!("varint {v}"),
+        UnknownValueRef::LengthDelimited(v) => format!("bytes {v:?}"),
+    }
+}
+
+fn join_path(path: &str, segment: &str) -> String {
+    if path.is_empty() {
+        segment.to_string()
+    } else {
+        format!("{path}.{segment}")
+    }
+}
+
+fn push(
+    out: &mut Vec<DescriptorDifference>,
+    path: String,
+    left: Option<String>,
+    right: Option<String>,
+) {
+    let kind = match (&left, &right) {
+        (Some(_), None) => DifferenceKind::Missing,
+        (None, Some(_)) => DifferenceKind::Added,
+        _ => DifferenceKind::Changed,
+    };
+    out.push(DescriptorDifference {
+        path,
+        kind,
+        left,
+        right,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_proto_to_file_descriptor;
+
+    #[test]
+    fn test_identical_descriptors() {
+        let proto = "syntax = \"proto3\"; message A { string x = 1; }";
+        let a = parse_proto_to_file_descriptor(proto).unwrap();
+        let b = parse_proto_to_file_descriptor(proto).unwrap();
+        assert!(diff_file_descriptors(&a, &b).is_empty());
+    }
+
+    #[test]
+    fn test_reordering_is_not_a_difference() {
+        let a = parse_proto_to_file_descriptor(
+            "syntax = \"proto3\"; message A { string x = 1; int32 y = 2; } message B {}",
+        )
+        .unwrap();
+        let b = parse_proto_to_file_descriptor(
+            "syntax = \"proto3\"; message B {} message A { int32 y = 2; string x = 1; }",
+        )
+        .unwrap();
+        assert!(diff_file_descriptors(&a, &b).is_empty());
+
+        let a = parse_proto_to_file_descriptor(
+            "syntax = \"proto2\"; message A { reserved 5, 1 to 3; reserved \"b\", \"a\"; extensions 100, 10 to 20; }",
+        )
+        .unwrap();
+        let b = parse_proto_to_file_descriptor(
+            "syntax = \"proto2\"; message A { reserved 1 to 3, 5; reserved \"a\", \"b\"; extensions 10 to 20, 100; }",
+        )
+        .unwrap();
+        assert!(diff_file_descriptors(&a, &b).is_empty());
+    }
+
+    #[test]
+    fn test_reports_changed_missing_and_added() {
+        let a = parse_proto_to_file_descriptor(
+            "syntax = \"proto2\"; message A { optional int32 x = 1 [default = 5]; optional int32 gone = 2; }",
+        )
+        .unwrap();
+        let b = parse_proto_to_file_descriptor(
+            "syntax = \"proto2\"; message A { optional int32 x = 1 [default = 6]; optional int32 new = 3; }",
+        )
+        .unwrap();
+
+        let diffs = diff_file_descriptors(&a, &b);
+        let rendered: Vec<String> = diffs.iter().map(ToString::to_string).collect();
+        assert!(
+            rendered.contains(&"message_type[A].field[x].default_value: 5 -> 6".to_string()),
+            "{rendered:?}"
+        );
+        assert!(diffs
+            .iter()
+            .any(|d| d.path == "message_type[A].field[gone]" && d.kind == DifferenceKind::Missing));
+        assert!(diffs
+            .iter()
+            .any(|d| d.path == "message_type[A].field[new]" && d.kind == DifferenceKind::Added));
+    }
+
+    #[test]
+    fn test_duplicate_names_are_reported() {
+        let a = parse_proto_to_file_descriptor("syntax = \"proto3\"; message A {}").unwrap();
+        let mut b = a.clone();
+        b.message_type.push(b.message_type[0].clone());
+
+        let diffs = diff_file_descriptors(&a, &b);
+        assert_eq!(diffs.len(), 1, "{diffs:?}");
+        assert_eq!(diffs[0].path, "message_type[A]");
+        assert_eq!(diffs[0].kind, DifferenceKind::Added);
+        let diffs = diff_file_descriptors(&b, &a);
+        assert_eq!(diffs.len(), 1, "{diffs:?}");
+        assert_eq!(diffs[0].kind, DifferenceKind::Missing);
+    }
+
+    #[test]
+    fn test_oneof_index_compares_by_name() {
+        let a = parse_proto_to_file_descriptor(
+            "syntax = \"proto3\"; message A { oneof x { int32 a = 1; } oneof y { int32 b = 2; } }",
+        )
+        .unwrap();
+        let b = parse_proto_to_file_descriptor(
+            "syntax = \"proto3\"; message A { oneof y { int32 b = 2; } oneof x { int32 a = 1; } }",
+        )
+        .unwrap();
+        assert!(diff_file_descriptors(&a, &b).is_empty());
+
+        // Moving a field to another oneof is still reported
+        let c = parse_proto_to_file_descriptor(
+            "syntax = \"proto3\"; message A { oneof x { int32 a = 1; int32 b = 2; } oneof y { int32 c = 3; } }",
+        )
+        .unwrap();
+        let rendered: Vec<String> = diff_file_descriptors(&a, &c)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert!(
+            rendered.contains(&"message_type[A].field[b].oneof_index: y -> x".to_string()),
+            "{rendered:?}"
+        );
+    }
+
+    #[test]
+    fn test_uninterpreted_options_match_by_position() {
+        use protobuf::descriptor::uninterpreted_option::NamePart;
+        use protobuf::descriptor::UninterpretedOption;
+
+        let option = |name: &str, value: &str| {
+            let mut part = NamePart::new();
+            part.set_name_part(name.to_string());
+            part.set_is_extension(true);
+            let mut option = UninterpretedOption::new();
+            option.name.push(part);
+            option.set_identifier_value(value.to_string());
+            option
+        };
+        let mut a = parse_proto_to_file_descriptor("syntax = \"proto3\"; message A {}").unwrap();
+        let mut b = a.clone();
+        a.message_type[0]
+            .options
+            .mut_or_insert_default()
+            .uninterpreted_option
+            .push(option("my.opt", "X"));
+        b.message_type[0]
+            .options
+            .mut_or_insert_default()
+            .uninterpreted_option
+            .push(option("my.opt", "Y"));
+
+        assert!(diff_file_descriptors(&a, &a.clone()).is_empty());
+        let differences = diff_file_descriptors(&a, &b);
+        assert_eq!(differences.len(), 1, "{differences:?}");
+        assert_eq!(
+            differences[0].path,
+            "message_type[A].options.uninterpreted_option[0].identifier_value"
+        );
+    }
+
+    #[test]
+    fn test_repeated_custom_options_compare_as_multisets() {
+        let proto = "syntax = \"proto3\"; message A {}";
+        let with_tags = |tags: &[&str]| {
+            let mut file = parse_proto_to_file_descriptor(proto).unwrap();
+            let unknown = file.message_type[0]
+                .options
+                .mut_or_insert_default()
+                .special_fields
+                .mut_unknown_fields();
+            for tag in tags {
+                unknown.add_length_delimited(50000, tag.as_bytes().to_vec());
+            }
+            file
+        };
+        let path = "message_type[A].options.(50000)";
+
+        // An extra value is reported even though the number exists on both sides
+        let differences = diff_file_descriptors(&with_tags(&["a"]), &with_tags(&["a", "b"]));
+        assert_eq!(differences.len(), 1, "{differences:?}");
+        assert_eq!(differences[0].path, path);
+        assert_eq!(differences[0].kind, DifferenceKind::Added);
+        assert_eq!(differences[0].right.as_deref(), Some("bytes [98]"));
+
+        // Only the unmatched values are paired
+        let differences = diff_file_descriptors(&with_tags(&["a", "b"]), &with_tags(&["c", "a"]));
+        assert_eq!(differences.len(), 1, "{differences:?}");
+        assert_eq!(differences[0].kind, DifferenceKind::Changed);
+        assert_eq!(differences[0].left.as_deref(), Some("bytes [98]"));
+        assert_eq!(differences[0].right.as_deref(), Some("bytes [99]"));
+
+        // Order does not matter
+        assert!(diff_file_descriptors(&with_tags(&["a", "b"]), &with_tags(&["b", "a"])).is_empty());
+    }
+
+    #[test]
+    fn test_ignores_source_code_info() {
+        let a = parse_proto_to_file_descriptor("syntax = \"proto3\"; message A {}").unwrap();
+        let mut b = a.clone();
+        b.source_code_info
+            .mut_or_insert_default()
+            .location
+            .push(Default::default());
+        assert!(diff_file_descriptors(&a, &b).is_empty());
+    }
+}
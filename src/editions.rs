@@ -0,0 +1,819 @@
+//! Protobuf Editions support.
+//!
+//! Neither the pure parser of `protobuf-parse` nor the descriptor types of
+//! the `protobuf` crate know about editions: the parser only accepts
+//! `syntax = "proto2"` or `"proto3"` and has no `features` options, and
+//! `FileDescriptorProto` has no `edition` field. Editions files are therefore
+//! lowered to proto2 before parsing (header replaced, `optional` added to
+//! unlabeled fields, `features` options removed), and the edition and the
+//! features are attached to the parsed descriptor afterwards as unknown
+//! fields, using their `descriptor.proto` field numbers.
+
+use crate::lexer::{decode_str_lit, tokenize, Token, TokenKind};
+use anyhow::{bail, Context, Result};
+use protobuf::descriptor::{DescriptorProto, FieldDescriptorProto, FileDescriptorProto};
+use protobuf::well_known_types::empty::Empty;
+use protobuf::{Message, MessageDyn, UnknownValueRef};
+use std::collections::BTreeMap;
+
+/// `FileDescriptorProto.edition`
+const EDITION_FIELD: u32 = 14;
+/// Number of the `features` field of each `*Options` message.
+const FEATURES_FIELDS: &[(&str, u32)] = &[
+    ("google.protobuf.FileOptions", 50),
+    ("google.protobuf.MessageOptions", 12),
+    ("google.protobuf.FieldOptions", 21),
+    ("google.protobuf.OneofOptions", 1),
+    ("google.protobuf.EnumOptions", 7),
+    ("google.protobuf.EnumValueOptions", 2),
+    ("google.protobuf.ServiceOptions", 34),
+    ("google.protobuf.MethodOptions", 35),
+    ("google.protobuf.ExtensionRangeOptions", 50),
+];
+
+/// Value of `FileDescriptorProto.syntax` for editions files.
+pub(crate) const EDITIONS_SYNTAX: &str = "editions";
+
+/// Supported editions and their `Edition` enum values.
+const EDITIONS: &[(&str, u64)] = &[("2023", 1000)];
+
+/// Names and numbers of the values of an enum.
+type EnumValues = &'static [(&'static str, u64)];
+
+/// `FeatureSet` fields and the names of their enum values, by number.
+const FEATURES: &[(&str, u32, EnumValues)] = &[
+    (
+        "field_presence",
+        1,
+        &[("EXPLICIT", 1), ("IMPLICIT", 2), ("LEGACY_REQUIRED", 3)],
+    ),
+    ("enum_type", 2, &[("OPEN", 1), ("CLOSED", 2)]),
+    (
+        "repeated_field_encoding",
+        3,
+        &[("PACKED", 1), ("EXPANDED", 2)],
+    ),
+    ("utf8_validation", 4, &[("VERIFY", 2), ("NONE", 3)]),
+    (
+        "message_encoding",
+        5,
+        &[("LENGTH_PREFIXED", 1), ("DELIMITED", 2)],
+    ),
+    ("json_format", 6, &[("ALLOW", 1), ("LEGACY_BEST_EFFORT", 2)]),
+];
+
+/// Edition of `file` (e.g. `"2023"`), if it is an editions file.
+pub(crate) fn edition(file: &FileDescriptorProto) -> Option<String> {
+    if file.syntax() != EDITIONS_SYNTAX {
+        return None;
+    }
+    let number = match file.special_fields.unknown_fields().get(EDITION_FIELD) {
+        Some(UnknownValueRef::Varint(number)) => number,
+        _ => return Some(EDITIONS[0].0.to_string()),
+    };
+    Some(
+        EDITIONS
+            .iter()
+            .find(|(_, n)| *n == number)
+            .map(|(name, _)| name.to_string())
+            .unwrap_or_else(|| number.to_string()),
+    )
+}
+
+/// Mark `file` as an editions file of the given edition.
+pub(crate) fn set_edition(file: &mut FileDescriptorProto, edition: &str) -> Result<()> {
+    let number = edition_number(edition)?;
+    file.set_syntax(EDITIONS_SYNTAX.to_string());
+    let unknown = file.special_fields.mut_unknown_fields();
+    unknown.remove(EDITION_FIELD);
+    unknown.add_varint(EDITION_FIELD, number);
+    Ok(())
+}
+
+fn edition_number(edition: &str) -> Result<u64> {
+    match EDITIONS.iter().find(|(name, _)| *name == edition) {
+        Some((_, number)) => Ok(*number),
+        None => bail!("Unsupported edition \"{edition}\""),
+    }
+}
+
+/// Number of the `features` field of the options message `message`
+/// (a full name such as `google.protobuf.FieldOptions`).
+pub(crate) fn features_field(message: &str) -> Option<u32> {
+    FEATURES_FIELDS
+        .iter()
+        .find(|(name, _)| *name == message)
+        .map(|(_, number)| *number)
+}
+
+/// Features set directly on an options message, rendered as
+/// `features.<name> = <VALUE>` in field number order.
+pub(crate) fn features(options: &dyn MessageDyn) -> Result<Vec<String>> {
+    let features_field = features_field(options.descriptor_dyn().full_name());
+    let mut rendered = Vec::new();
+    for (number, value) in options.unknown_fields_dyn().iter() {
+        if Some(number) != features_field {
+            continue;
+        }
+        let UnknownValueRef::LengthDelimited(bytes) = value else {
+            bail!("Malformed features option");
+        };
+        let set = Empty::parse_from_bytes(bytes).context("Malformed features option")?;
+        let mut entries: Vec<(u32, String)> = Vec::new();
+        for (number, value) in set.special_fields.unknown_fields().iter() {
+            let (name, values) = match FEATURES.iter().find(|(_, n, _)| *n == number) {
+                Some((name, _, values)) => (name, values),
+                None => bail!("Unsupported feature field {number}"),
+            };
+            let UnknownValueRef::Varint(value) = value else {
+                bail!("Malformed value of feature {name}");
+            };
+            let value = values
+                .iter()
+                .find(|(_, v)| *v == value)
+                .map(|(value_name, _)| value_name.to_string())
+                .unwrap_or_else(|| value.to_string());
+            entries.push((number, format!("features.{name} = {value}")));
+        }
+        entries.sort();
+        rendered.extend(entries.into_iter().map(|(_, entry)| entry));
+    }
+    Ok(rendered)
+}
+
+/// Encode `features` (name, value) pairs as a `FeatureSet` and attach them
+/// to an options message.
+fn set_features(options: &mut dyn MessageDyn, features: &[(String, String)]) -> Result<()> {
+    let mut set = Empty::new();
+    for (name, value) in features {
+        let (number, values) = match FEATURES.iter().find(|(n, _, _)| n == name) {
+            Some((_, number, values)) => (*number, values),
+            None => bail!("Unknown feature \"{name}\""),
+        };
+        let value = match values.iter().find(|(v, _)| v == value) {
+            Some((_, value)) => *value,
+            None => bail!("Invalid value {value} for feature {name}"),
+        };
+        set.special_fields
+            .mut_unknown_fields()
+            .add_varint(number, value);
+    }
+    let descriptor = options.descriptor_dyn();
+    let number = features_field(descriptor.full_name())
+        .with_context(|| format!("{} has no features", descriptor.full_name()))?;
+    options
+        .mut_unknown_fields_dyn()
+        .add_length_delimited(number, set.write_to_bytes()?);
+    Ok(())
+}
+
+/// Element of a file that can carry `features` options.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Segment {
+    Message(String),
+    Enum(String),
+    EnumValue(String),
+    Field(String),
+    Extension(String),
+    Oneof(String),
+    Service(String),
+    Method(String),
+}
+
+/// A `features` option removed from the source.
+#[derive(Debug, Clone)]
+struct Feature {
+    path: Vec<Segment>,
+    name: String,
+    value: String,
+}
+
+/// An editions file rewritten to proto2 for the pure parser.
+#[derive(Debug, Clone)]
+pub(crate) struct LoweredSource {
+    /// Rewritten source text
+    pub source: String,
+    edition: String,
+    features: Vec<Feature>,
+}
+
+impl LoweredSource {
+    /// Restore the edition and the removed features on the descriptor
+    /// parsed from [`Self::source`].
+    pub fn apply(&self, file: &mut FileDescriptorProto) -> Result<()> {
+        set_edition(file, &self.edition)?;
+
+        // One FeatureSet per element
+        let mut grouped: BTreeMap<&[Segment], Vec<(String, String)>> = BTreeMap::new();
+        for feature in &self.features {
+            grouped
+                .entry(&feature.path)
+                .or_default()
+                .push((feature.name.clone(), feature.value.clone()));
+        }
+
+        for (path, features) in grouped {
+            let options = options_at(file, path)
+                .with_context(|| format!("Could not find the element of features {path:?}"))?;
+            set_features(options, &features)?;
+        }
+        Ok(())
+    }
+}
+
+/// Options message of the element at `path`, created if missing.
+fn options_at<'a>(
+    file: &'a mut FileDescriptorProto,
+    path: &[Segment],
+) -> Option<&'a mut dyn MessageDyn> {
+    let Some((first, rest)) = path.split_first() else {
+        return Some(file.options.mut_or_insert_default());
+    };
+    match first {
+        Segment::Message(name) => {
+            let message = file.message_type.iter_mut().find(|m| m.name() == name)?;
+            message_options_at(message, rest)
+        }
+        Segment::Enum(name) => {
+            let enum_type = file.enum_type.iter_mut().find(|e| e.name() == name)?;
+            match rest {
+                [] => Some(enum_type.options.mut_or_insert_default()),
+                [Segment::EnumValue(value)] => {
+                    let value = enum_type.value.iter_mut().find(|v| v.name() == value)?;
+                    Some(value.options.mut_or_insert_default())
+                }
+                _ => None,
+            }
+        }
+        Segment::Extension(name) => field_options(&mut file.extension, name, rest),
+        Segment::Service(name) => {
+            let service = file.service.iter_mut().find(|s| s.name() == name)?;
+            match rest {
+                [] => Some(service.options.mut_or_insert_default()),
+                [Segment::Method(method)] => {
+                    let method = service.method.iter_mut().find(|m| m.name() == method)?;
+                    Some(method.options.mut_or_insert_default())
+                }
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+fn message_options_at<'a>(
+    message: &'a mut DescriptorProto,
+    path: &[Segment],
+) -> Option<&'a mut dyn MessageDyn> {
+    let Some((first, rest)) = path.split_first() else {
+        return Some(message.options.mut_or_insert_default());
+    };
+    match first {
+        Segment::Message(name) => {
+            let nested = message.nested_type.iter_mut().find(|m| m.name() == name)?;
+            message_options_at(nested, rest)
+        }
+        Segment::Enum(name) => {
+            let enum_type = message.enum_type.iter_mut().find(|e| e.name() == name)?;
+            match rest {
+                [] => Some(enum_type.options.mut_or_insert_default()),
+                [Segment::EnumValue(value)] => {
+                    let value = enum_type.value.iter_mut().find(|v| v.name() == value)?;
+                    Some(value.options.mut_or_insert_default())
+                }
+                _ => None,
+            }
+        }
+        Segment::Field(name) => field_options(&mut message.field, name, rest),
+        Segment::Extension(name) => field_options(&mut message.extension, name, rest),
+        Segment::Oneof(name) if rest.is_empty() => {
+            let oneof = message.oneof_decl.iter_mut().find(|o| o.name() == name)?;
+            Some(oneof.options.mut_or_insert_default())
+        }
+        _ => None,
+    }
+}
+
+fn field_options<'a>(
+    fields: &'a mut [FieldDescriptorProto],
+    name: &str,
+    rest: &[Segment],
+) -> Option<&'a mut dyn MessageDyn> {
+    if !rest.is_empty() {
+        return None;
+    }
+    let field = fields.iter_mut().find(|f| f.name() == name)?;
+    Some(field.options.mut_or_insert_default())
+}
+
+/// Lower an editions file to proto2 for the pure parser.
+///
+/// Returns `None` for files without an `edition` declaration.
+pub(crate) fn lower(source: &str) -> Result<Option<LoweredSource>> {
+    let tokens: Vec<Token> = tokenize(source)?
+        .into_iter()
+        .filter(|t| t.kind != TokenKind::Comment)
+        .collect();
+
+    let header = match tokens.as_slice() {
+        [kw, eq, value, semi, ..]
+            if kw.is_ident("edition")
+                && eq.is_symbol('=')
+                && value.kind == TokenKind::StrLit
+                && semi.is_symbol(';') =>
+        {
+            (kw, value, semi)
+        }
+        [kw, ..] if kw.is_ident("edition") => bail!(
+            "Malformed edition declaration at {}:{}",
+            kw.position.line,
+            kw.position.column
+        ),
+        _ => return Ok(None),
+    };
+
+    let edition = decode_str_lit(header.1.text)?;
+    edition_number(&edition).with_context(|| {
+        format!(
+            "Unsupported edition at {}:{}",
+            header.1.position.line, header.1.position.column
+        )
+    })?;
+
+    let mut lowering = Lowering {
+        tokens: &tokens,
+        idx: 4,
+        edits: vec![Edit {
+            start: header.0.position.offset,
+            end: end_offset(header.2),
+            replacement: "syntax = \"proto2\";".to_string(),
+        }],
+        features: Vec::new(),
+    };
+    lowering.body(&[], Scope::File)?;
+    if lowering.idx < tokens.len() {
+        let token = &tokens[lowering.idx];
+        bail!(
+            "Unexpected '{}' at {}:{}",
+            token.text,
+            token.position.line,
+            token.position.column
+        );
+    }
+
+    let Lowering {
+        mut edits,
+        features,
+        ..
+    } = lowering;
+    edits.sort_by_key(|e| e.start);
+    let mut lowered = String::with_capacity(source.len());
+    let mut cursor = 0;
+    for edit in edits {
+        lowered.push_str(&source[cursor..edit.start]);
+        lowered.push_str(&edit.replacement);
+        // Keep line breaks so parser errors point at the original lines
+        lowered.extend(source[edit.start..edit.end].chars().filter(|c| *c == '\n'));
+        cursor = edit.end;
+    }
+    lowered.push_str(&source[cursor..]);
+
+    Ok(Some(LoweredSource {
+        source: lowered,
+        edition,
+        features,
+    }))
+}
+
+/// Replacement of `source[start..end]`.
+#[derive(Debug)]
+struct Edit {
+    start: usize,
+    end: usize,
+    replacement: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Scope {
+    File,
+    Message,
+    Enum,
+    Service,
+    Method,
+    Oneof,
+    Extend,
+}
+
+struct Lowering<'t, 'a> {
+    tokens: &'t [Token<'a>],
+    idx: usize,
+    edits: Vec<Edit>,
+    features: Vec<Feature>,
+}
+
+impl<'t, 'a> Lowering<'t, 'a> {
+    fn peek(&self) -> Option<&'t Token<'a>> {
+        self.tokens.get(self.idx)
+    }
+
+    fn next(&mut self) -> Result<&'t Token<'a>> {
+        let token = self
+            .tokens
+            .get(self.idx)
+            .context("Unexpected end of file")?;
+        self.idx += 1;
+        Ok(token)
+    }
+
+    fn expect_symbol(&mut self, symbol: char) -> Result<&'t Token<'a>> {
+        let token = self.next()?;
+        if !token.is_symbol(symbol) {
+            bail!(
+                "Expected '{symbol}' at {}:{}, found '{}'",
+                token.position.line,
+                token.position.column,
+                token.text
+            );
+        }
+        Ok(token)
+    }
+
+    /// Statements up to the closing `}` of the current scope (not consumed).
+    fn body(&mut self, path: &[Segment], scope: Scope) -> Result<()> {
+        while let Some(token) = self.peek() {
+            if token.is_symbol('}') {
+                return Ok(());
+            }
+            if token.is_symbol(';') {
+                self.idx += 1;
+                continue;
+            }
+
+            let nested = |segment: Segment| {
+                let mut path = path.to_vec();
+                path.push(segment);
+                path
+            };
+            let keyword = if token.kind == TokenKind::Ident {
+                token.text
+            } else {
+                ""
+            };
+            match (scope, keyword) {
+                (Scope::File | Scope::Message, "message") => {
+                    self.idx += 1;
+                    let name = self.next()?.text.to_string();
+                    self.block(&nested(Segment::Message(name)), Scope::Message)?;
+                }
+                (Scope::File | Scope::Message, "enum") => {
+                    self.idx += 1;
+                    let name = self.next()?.text.to_string();
+                    self.block(&nested(Segment::Enum(name)), Scope::Enum)?;
+                }
+                (Scope::File, "service") => {
+                    self.idx += 1;
+                    let name = self.next()?.text.to_string();
+                    self.block(&nested(Segment::Service(name)), Scope::Service)?;
+                }
+                (Scope::Message, "oneof") => {
+                    self.idx += 1;
+                    let name = self.next()?.text.to_string();
+                    self.block(&nested(Segment::Oneof(name)), Scope::Oneof)?;
+                }
+                (Scope::File | Scope::Message, "extend") => {
+                    self.idx += 1;
+                    while self.peek().is_some_and(|t| !t.is_symbol('{')) {
+                        self.idx += 1;
+                    }
+                    self.block(path, Scope::Extend)?;
+                }
+                (Scope::Service, "rpc") => {
+                    self.idx += 1;
+                    let name = self.next()?.text.to_string();
+                    while self
+                        .peek()
+                        .is_some_and(|t| !t.is_symbol('{') && !t.is_symbol(';'))
+                    {
+                        self.idx += 1;
+                    }
+                    if self.peek().is_some_and(|t| t.is_symbol('{')) {
+                        self.block(&nested(Segment::Method(name)), Scope::Method)?;
+                    }
+                }
+                (_, "option") => self.option_statement(path)?,
+                (_, "syntax" | "edition" | "package" | "import" | "reserved" | "extensions") => {
+                    self.skip_statement()?;
+                }
+                (Scope::Message | Scope::Oneof | Scope::Extend, _) => {
+                    self.field(path, scope)?;
+                }
+                (Scope::Enum, _) => self.field(path, scope)?,
+                _ => bail!(
+                    "Unexpected '{}' at {}:{}",
+                    token.text,
+                    token.position.line,
+                    token.position.column
+                ),
+            }
+        }
+        if scope == Scope::File {
+            Ok(())
+        } else {
+            bail!("Unexpected end of file, expected '}}'")
+        }
+    }
+
+    fn block(&mut self, path: &[Segment], scope: Scope) -> Result<()> {
+        self.expect_symbol('{')?;
+        self.body(path, scope)?;
+        self.expect_symbol('}')?;
+        Ok(())
+    }
+
+    /// Skip to the `;` ending the current statement, stepping over any
+    /// bracketed or braced sections.
+    fn skip_statement(&mut self) -> Result<usize> {
+        let mut depth = 0usize;
+        loop {
+            let token = self.next()?;
+            if token.is_symbol('{') || token.is_symbol('[') || token.is_symbol('(') {
+                depth += 1;
+            } else if token.is_symbol('}') || token.is_symbol(']') || token.is_symbol(')') {
+                depth = depth.saturating_sub(1);
+            } else if depth == 0 && token.is_symbol(';') {
+                return Ok(self.idx - 1);
+            }
+        }
+    }
+
+    /// `option features.<name> = <VALUE>;` is recorded and removed; any
+    /// other option is left untouched.
+    fn option_statement(&mut self, path: &[Segment]) -> Result<()> {
+        let start = self.idx;
+        let end = self.skip_statement()?;
+        let statement = &self.tokens[start + 1..end];
+        if let Some((name, value)) = feature_assignment(statement)? {
+            self.features.push(Feature {
+                path: path.to_vec(),
+                name,
+                value,
+            });
+            self.edits.push(Edit {
+                start: self.tokens[start].position.offset,
+                end: end_offset(&self.tokens[end]),
+                replacement: String::new(),
+            });
+        }
+        Ok(())
+    }
+
+    /// A field, map field, extension or enum value statement.
+    fn field(&mut self, path: &[Segment], scope: Scope) -> Result<()> {
+        let start = self.idx;
+        let end = self.skip_statement()?;
+        let statement = &self.tokens[start..end];
+
+        let first = &statement[0];
+        let labeled = ["optional", "required", "repeated", "map"]
+            .iter()
+            .any(|label| first.is_ident(label));
+        if matches!(scope, Scope::Message | Scope::Extend) && !labeled {
+            if first.is_ident("group") {
+                bail!(
+                    "Groups are not allowed in editions at {}:{}",
+                    first.position.line,
+                    first.position.column
+                );
+            }
+            self.edits.push(Edit {
+                start: first.position.offset,
+                end: first.position.offset,
+                replacement: "optional ".to_string(),
+            });
+        }
+
+        let Some(eq) = statement.iter().position(|t| t.is_symbol('=')) else {
+            bail!(
+                "Expected '=' in statement at {}:{}",
+                first.position.line,
+                first.position.column
+            );
+        };
+        let name = statement[eq.saturating_sub(1)].text.to_string();
+        let segment = match scope {
+            Scope::Enum => Segment::EnumValue(name),
+            Scope::Extend => Segment::Extension(name),
+            _ => Segment::Field(name),
+        };
+        let mut element = path.to_vec();
+        if scope == Scope::Oneof {
+            // Oneof members are fields of the enclosing message
+            element.pop();
+        }
+        element.push(segment);
+
+        if let Some(open) = statement.iter().position(|t| t.is_symbol('[')) {
+            self.bracket_options(&element, &statement[open..])?;
+        }
+        Ok(())
+    }
+
+    /// Record and remove `features.*` entries of a `[...]` option list.
+    fn bracket_options(&mut self, element: &[Segment], tokens: &[Token]) -> Result<()> {
+        let Some(close) = tokens.iter().rposition(|t| t.is_symbol(']')) else {
+            bail!(
+                "Unterminated option list at {}:{}",
+                tokens[0].position.line,
+                tokens[0].position.column
+            );
+        };
+
+        // Split the entries on top-level commas
+        let mut entries: Vec<(usize, usize)> = Vec::new();
+        let mut depth = 0usize;
+        let mut entry_start = 1;
+        for (idx, token) in tokens.iter().enumerate().take(close).skip(1) {
+            if token.is_symbol('{') || token.is_symbol('[') || token.is_symbol('(') {
+                depth += 1;
+            } else if token.is_symbol('}') || token.is_symbol(']') || token.is_symbol(')') {
+                depth = depth.saturating_sub(1);
+            } else if depth == 0 && token.is_symbol(',') {
+                entries.push((entry_start, idx));
+                entry_start = idx + 1;
+            }
+        }
+        entries.push((entry_start, close));
+
+        let mut removed = Vec::new();
+        for (entry_idx, &(start, end)) in entries.iter().enumerate() {
+            if let Some((name, value)) = feature_assignment(&tokens[start..end])? {
+                self.features.push(Feature {
+                    path: element.to_vec(),
+                    name,
+                    value,
+                });
+                removed.push(entry_idx);
+            }
+        }
+        if removed.is_empty() {
+            return Ok(());
+        }
+
+        if removed.len() == entries.len() {
+            self.edits.push(Edit {
+                start: tokens[0].position.offset,
+                end: end_offset(&tokens[close]),
+                replacement: String::new(),
+            });
+            return Ok(());
+        }
+        for entry_idx in removed {
+            let (start, end) = entries[entry_idx];
+            // Remove the entry with its trailing comma, or the preceding
+            // comma for the last entry
+            let (from, to) = if end < close {
+                (&tokens[start], &tokens[end])
+            } else {
+                (&tokens[start - 1], &tokens[end - 1])
+            };
+            self.edits.push(Edit {
+                start: from.position.offset,
+                end: end_offset(to),
+                replacement: String::new(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Parse `features.<name> = <VALUE>` (without the `option` keyword).
+fn feature_assignment(tokens: &[Token]) -> Result<Option<(String, String)>> {
+    match tokens {
+        [features, ..] if !features.is_ident("features") => Ok(None),
+        [_, dot, name, eq, value]
+            if dot.is_symbol('.')
+                && name.kind == TokenKind::Ident
+                && eq.is_symbol('=')
+                && value.kind == TokenKind::Ident =>
+        {
+            if !FEATURES.iter().any(|(n, _, _)| *n == name.text) {
+                bail!(
+                    "Unsupported feature \"{}\" at {}:{}",
+                    name.text,
+                    name.position.line,
+                    name.position.column
+                );
+            }
+            Ok(Some((name.text.to_string(), value.text.to_string())))
+        }
+        [features, ..] => bail!(
+            "Unsupported features option at {}:{}",
+            features.position.line,
+            features.position.column
+        ),
+        [] => Ok(None),
+    }
+}
+
+fn end_offset(token: &Token) -> usize {
+    token.position.offset + token.text.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EDITION_2023: &str = r#"
+edition = "2023";
+package demo;
+
+option features.field_presence = IMPLICIT;
+
+message User {
+  option features.message_encoding = DELIMITED;
+  string name = 1;
+  int32 id = 2 [features.field_presence = EXPLICIT, deprecated = true];
+  repeated int32 tags = 3 [features.repeated_field_encoding = EXPANDED];
+  map<string, string> labels = 4;
+  oneof contact {
+    string email = 5 [features.utf8_validation = NONE];
+  }
+}
+
+enum Status {
+  option features.enum_type = CLOSED;
+  STATUS_UNKNOWN = 0;
+  STATUS_ACTIVE = 1 [features.enum_type = OPEN];
+}
+"#;
+
+    #[test]
+    fn test_non_editions_files_are_untouched() {
+        assert!(lower("syntax = \"proto3\"; message A {}")
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_lower_rewrites_header_labels_and_features() {
+        let lowered = lower(EDITION_2023).unwrap().unwrap();
+        let compact = lowered
+            .source
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ");
+        assert!(compact.starts_with("syntax = \"proto2\"; package demo;"));
+        assert!(compact.contains("{ optional string name = 1;"));
+        assert!(compact.contains("optional int32 id = 2 [ deprecated = true];"));
+        assert!(compact.contains("repeated int32 tags = 3 ;"));
+        assert!(compact.contains("; map<string, string> labels = 4;"));
+        assert!(compact.contains("oneof contact { string email = 5 ; }"));
+        assert!(!compact.contains("features"));
+        assert_eq!(lowered.source.lines().count(), EDITION_2023.lines().count());
+        assert_eq!(lowered.features.len(), 7);
+    }
+
+    #[test]
+    fn test_apply_restores_edition_and_features() {
+        let file = crate::parse_proto_to_file_descriptor(EDITION_2023).unwrap();
+        assert_eq!(file.syntax(), EDITIONS_SYNTAX);
+        assert_eq!(edition(&file).as_deref(), Some("2023"));
+        assert_eq!(
+            features(&*file.options).unwrap(),
+            vec!["features.field_presence = IMPLICIT"]
+        );
+
+        let user = &file.message_type[0];
+        let id = user.field.iter().find(|f| f.name() == "id").unwrap();
+        assert_eq!(id.options.deprecated, Some(true));
+        assert_eq!(
+            features(&*id.options).unwrap(),
+            vec!["features.field_presence = EXPLICIT"]
+        );
+        let email = user.field.iter().find(|f| f.name() == "email").unwrap();
+        assert_eq!(
+            features(&*email.options).unwrap(),
+            vec!["features.utf8_validation = NONE"]
+        );
+
+        let value = &file.enum_type[0].value[1];
+        assert_eq!(
+            features(&*value.options).unwrap(),
+            vec!["features.enum_type = OPEN"]
+        );
+    }
+
+    #[test]
+    fn test_unsupported_editions_and_features() {
+        let err = lower("edition = \"2099\"; message A {}").unwrap_err();
+        assert!(format!("{err:#}").contains("Unsupported edition \"2099\""));
+
+        let err =
+            lower("edition = \"2023\"; option features.(pb.cpp).string_type = VIEW;").unwrap_err();
+        assert!(err.to_string().contains("Unsupported features option"));
+
+        let err = lower("edition = \"2023\"; message A { int32 x = 1 [features.nope = X]; }")
+            .unwrap_err();
+        assert!(err.to_string().contains("Unsupported feature \"nope\""));
+    }
+}
@@ -0,0 +1,96 @@
+//! Common googleapis protos bundled into the binary.
+//!
+//! With the `googleapis` cargo feature, imports of `google/api/*`,
+//! `google/rpc/*` and `google/type/*` files that are not found in the session
+//! or its include roots are served from copies embedded at build time
+//! (see `third_party/googleapis`). Without the feature nothing is bundled and
+//! such imports are stubbed or rejected like any other missing import.
+
+#[cfg(feature = "googleapis")]
+macro_rules! bundled {
+    ($($path:literal),* $(,)?) => {
+        &[$((
+            $path,
+            include_str!(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/third_party/googleapis/",
+                $path
+            )),
+        )),*]
+    };
+}
+
+/// Bundled files as (import path, content), sorted by path.
+#[cfg(feature = "googleapis")]
+const BUNDLED_FILES: &[(&str, &str)] = bundled![
+    "google/api/annotations.proto",
+    "google/api/client.proto",
+    "google/api/field_behavior.proto",
+    "google/api/http.proto",
+    "google/api/httpbody.proto",
+    "google/api/launch_stage.proto",
+    "google/api/resource.proto",
+    "google/rpc/code.proto",
+    "google/rpc/error_details.proto",
+    "google/rpc/status.proto",
+    "google/type/calendar_period.proto",
+    "google/type/color.proto",
+    "google/type/date.proto",
+    "google/type/datetime.proto",
+    "google/type/dayofweek.proto",
+    "google/type/decimal.proto",
+    "google/type/expr.proto",
+    "google/type/fraction.proto",
+    "google/type/interval.proto",
+    "google/type/latlng.proto",
+    "google/type/localized_text.proto",
+    "google/type/money.proto",
+    "google/type/month.proto",
+    "google/type/phone_number.proto",
+    "google/type/postal_address.proto",
+    "google/type/quaternion.proto",
+    "google/type/timeofday.proto",
+];
+
+#[cfg(not(feature = "googleapis"))]
+const BUNDLED_FILES: &[(&str, &str)] = &[];
+
+/// Content of the bundled file at `import_path`, if any.
+pub(crate) fn lookup(import_path: &str) -> Option<&'static str> {
+    BUNDLED_FILES
+        .binary_search_by_key(&import_path, |(path, _)| path)
+        .ok()
+        .map(|index| BUNDLED_FILES[index].1)
+}
+
+#[cfg(all(test, feature = "googleapis"))]
+mod tests {
+    use super::*;
+    use crate::lexer::extract_imports;
+    use crate::ParseSession;
+
+    #[test]
+    fn test_bundled_files_are_sorted_and_self_contained() {
+        assert!(BUNDLED_FILES.windows(2).all(|w| w[0].0 < w[1].0));
+        for (path, content) in BUNDLED_FILES {
+            for import in extract_imports(content).unwrap() {
+                assert!(
+                    import.path.starts_with("google/protobuf/") || lookup(&import.path).is_some(),
+                    "{path} imports {} which is not bundled",
+                    import.path
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_bundled_files_parse() {
+        for (path, content) in BUNDLED_FILES {
+            let descriptor = ParseSession::new()
+                .with_file_name(*path)
+                .parse(content)
+                .unwrap_or_else(|err| panic!("{path}: {err:#}"));
+            assert_eq!(descriptor.name(), *path);
+        }
+    }
+}
@@ -0,0 +1,487 @@
+//! Minimal protobuf source lexer.
+//!
+//! Splits `.proto` text into tokens with source positions, skipping nothing:
+//! comments are returned as tokens so callers can decide what to do with them.
+//! Used for import discovery, where line matching is not reliable (imports
+//! sharing a line, spanning lines, or appearing inside comments and strings).
+
+use anyhow::{bail, Result};
+
+/// Position of a token in the source text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Position {
+    /// Byte offset from the start of the input
+    pub offset: usize,
+    /// 1-based line number
+    pub line: usize,
+    /// 1-based column number, counted in characters
+    pub column: usize,
+}
+
+/// Kind of a lexed token.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    /// Identifier or keyword (`message`, `foo_bar`, `inf`)
+    Ident,
+    /// Integer literal (decimal, hex or octal)
+    IntLit,
+    /// Floating point literal
+    FloatLit,
+    /// Quoted string literal, quotes included in the token text
+    StrLit,
+    /// Single punctuation character (`{`, `;`, `=`, `.`, ...)
+    Symbol,
+    /// `// line` or `/* block */` comment, delimiters included
+    Comment,
+}
+
+/// A token together with its raw source text and position.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token<'a> {
+    pub kind: TokenKind,
+    /// Raw source text of the token
+    pub text: &'a str,
+    /// Position of the first character of the token
+    pub position: Position,
+}
+
+impl Token<'_> {
+    /// Whether this token is the given punctuation character.
+    pub fn is_symbol(&self, symbol: char) -> bool {
+        self.kind == TokenKind::Symbol && self.text.starts_with(symbol)
+    }
+
+    /// Whether this token is the given identifier or keyword.
+    pub fn is_ident(&self, ident: &str) -> bool {
+        self.kind == TokenKind::Ident && self.text == ident
+    }
+}
+
+/// Split `source` into tokens, comments included.
+pub fn tokenize(source: &str) -> Result<Vec<Token<'_>>> {
+    Lexer::new(source).collect()
+}
+
+/// Streaming tokenizer over `.proto` source text.
+pub struct Lexer<'a> {
+    source: &'a str,
+    position: Position,
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(source: &'a str) -> Self {
+        Self {
+            source,
+            position: Position {
+                offset: 0,
+                line: 1,
+                column: 1,
+            },
+        }
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.source[self.position.offset..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn peek_second(&self) -> Option<char> {
+        self.rest().chars().nth(1)
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let ch = self.peek()?;
+        self.position.offset += ch.len_utf8();
+        if ch == '\n' {
+            self.position.line += 1;
+            self.position.column = 1;
+        } else {
+            self.position.column += 1;
+        }
+        Some(ch)
+    }
+
+    fn bump_while(&mut self, pred: impl Fn(char) -> bool) {
+        while self.peek().is_some_and(&pred) {
+            self.bump();
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        self.bump_while(char::is_whitespace);
+    }
+
+    fn lex_comment(&mut self, start: Position) -> Result<TokenKind> {
+        self.bump();
+        if self.bump() == Some('/') {
+            self.bump_while(|c| c != '\n');
+            return Ok(TokenKind::Comment);
+        }
+        loop {
+            match self.bump() {
+                Some('*') if self.peek() == Some('/') => {
+                    self.bump();
+                    return Ok(TokenKind::Comment);
+                }
+                Some(_) => {}
+                None => bail!(
+                    "Unterminated block comment starting at {}:{}",
+                    start.line,
+                    start.column
+                ),
+            }
+        }
+    }
+
+    fn lex_string(&mut self, start: Position) -> Result<TokenKind> {
+        let quote = self.bump();
+        loop {
+            match self.bump() {
+                Some('\\') => {
+                    self.bump();
+                }
+                Some('\n') | None => bail!(
+                    "Unterminated string literal starting at {}:{}",
+                    start.line,
+                    start.column
+                ),
+                ch if ch == quote => return Ok(TokenKind::StrLit),
+                Some(_) => {}
+            }
+        }
+    }
+
+    fn lex_number(&mut self) -> TokenKind {
+        if self.peek() == Some('0') && matches!(self.peek_second(), Some('x' | 'X')) {
+            self.bump();
+            self.bump();
+            self.bump_while(|c| c.is_ascii_hexdigit());
+            return TokenKind::IntLit;
+        }
+
+        let mut kind = TokenKind::IntLit;
+        self.bump_while(|c| c.is_ascii_digit());
+        if self.peek() == Some('.') {
+            kind = TokenKind::FloatLit;
+            self.bump();
+            self.bump_while(|c| c.is_ascii_digit());
+        }
+        if matches!(self.peek(), Some('e' | 'E')) {
+            kind = TokenKind::FloatLit;
+            self.bump();
+            if matches!(self.peek(), Some('+' | '-')) {
+                self.bump();
+            }
+            self.bump_while(|c| c.is_ascii_digit());
+        }
+        kind
+    }
+}
+
+impl<'a> Iterator for Lexer<'a> {
+    type Item = Result<Token<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.skip_whitespace();
+        let start = self.position;
+        let ch = self.peek()?;
+
+        let kind = match ch {
+            '/' if matches!(self.peek_second(), Some('/' | '*')) => {
+                match self.lex_comment(start) {
+                    Ok(kind) => kind,
+                    Err(err) => {
+                        // Stop iteration after reporting the error
+                        self.position.offset = self.source.len();
+                        return Some(Err(err));
+                    }
+                }
+            }
+            '"' | '\'' => match self.lex_string(start) {
+                Ok(kind) => kind,
+                Err(err) => {
+                    self.position.offset = self.source.len();
+                    return Some(Err(err));
+                }
+            },
+            c if c.is_ascii_digit() => self.lex_number(),
+            '.' if self.peek_second().is_some_and(|c| c.is_ascii_digit()) => self.lex_number(),
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                self.bump_while(|c| c.is_ascii_alphanumeric() || c == '_');
+                TokenKind::Ident
+            }
+            _ => {
+                self.bump();
+                TokenKind::Symbol
+            }
+        };
+
+        Some(Ok(Token {
+            kind,
+            text: &self.source[start.offset..self.position.offset],
+            position: start,
+        }))
+    }
+}
+
+/// Decode the value of a string literal token (quotes included).
+pub fn decode_str_lit(raw: &str) -> Result<String> {
+    let inner = raw
+        .get(1..raw.len().saturating_sub(1))
+        .filter(|_| raw.len() >= 2)
+        .ok_or_else(|| anyhow::anyhow!("Invalid string literal: {raw}"))?;
+
+    let mut bytes = Vec::with_capacity(inner.len());
+    let mut chars = inner.chars().peekable();
+    while let Some(ch) = chars.next() {
+        if ch != '\\' {
+            let mut buf = [0u8; 4];
+            bytes.extend_from_slice(ch.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some('n') => bytes.push(b'\n'),
+            Some('r') => bytes.push(b'\r'),
+            Some('t') => bytes.push(b'\t'),
+            Some('a') => bytes.push(0x07),
+            Some('b') => bytes.push(0x08),
+            Some('f') => bytes.push(0x0c),
+            Some('v') => bytes.push(0x0b),
+            Some(c @ ('\\' | '\'' | '"' | '?')) => bytes.push(c as u8),
+            Some('x' | 'X') => {
+                let mut value = 0u32;
+                for _ in 0..2 {
+                    match chars.peek().and_then(|c| c.to_digit(16)) {
+                        Some(d) => {
+                            value = value * 16 + d;
+                            chars.next();
+                        }
+                        None => break,
+                    }
+                }
+                bytes.push(value as u8);
+            }
+            Some(c @ '0'..='7') => {
+                let mut value = c.to_digit(8).unwrap_or(0);
+                for _ in 0..2 {
+                    match chars.peek().and_then(|c| c.to_digit(8)) {
+                        Some(d) => {
+                            value = value * 8 + d;
+                            chars.next();
+                        }
+                        None => break,
+                    }
+                }
+                bytes.push(value as u8);
+            }
+            Some(c) => bail!("Invalid escape sequence '\\{c}' in string literal {raw}"),
+            None => bail!("Trailing backslash in string literal {raw}"),
+        }
+    }
+
+    String::from_utf8(bytes).map_err(|_| anyhow::anyhow!("String literal is not UTF-8: {raw}"))
+}
+
+/// Kind of an `import` statement.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ImportKind {
+    /// `import "foo.proto";`
+    Default,
+    /// `import public "foo.proto";`
+    Public,
+    /// `import weak "foo.proto";`
+    Weak,
+}
+
+/// An `import` statement found in the source text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportStatement {
+    /// Decoded import path
+    pub path: String,
+    pub kind: ImportKind,
+    /// Position of the `import` keyword
+    pub position: Position,
+}
+
+/// Extract every top-level `import` statement from `source`.
+///
+/// Imports inside comments or string literals are ignored, and statements
+/// may share a line with other statements or span several lines.
+pub fn extract_imports(source: &str) -> Result<Vec<ImportStatement>> {
+    let tokens: Vec<Token> = tokenize(source)?
+        .into_iter()
+        .filter(|t| t.kind != TokenKind::Comment)
+        .collect();
+
+    let mut imports = Vec::new();
+    let mut depth = 0usize;
+    let mut statement_start = true;
+    let mut idx = 0;
+
+    while idx < tokens.len() {
+        let token = &tokens[idx];
+        if depth == 0 && statement_start && token.is_ident("import") {
+            let mut cursor = idx + 1;
+            let kind = match tokens.get(cursor) {
+                Some(t) if t.is_ident("public") => ImportKind::Public,
+                Some(t) if t.is_ident("weak") => ImportKind::Weak,
+                _ => ImportKind::Default,
+            };
+            if kind != ImportKind::Default {
+                cursor += 1;
+            }
+
+            let (path_token, end) = match (tokens.get(cursor), tokens.get(cursor + 1)) {
+                (Some(p), Some(end)) if p.kind == TokenKind::StrLit => (p, end),
+                _ => bail!(
+                    "Malformed import statement at {}:{}",
+                    token.position.line,
+                    token.position.column
+                ),
+            };
+            if !end.is_symbol(';') {
+                bail!(
+                    "Expected ';' after import at {}:{}",
+                    end.position.line,
+                    end.position.column
+                );
+            }
+
+            imports.push(ImportStatement {
+                path: decode_str_lit(path_token.text)?,
+                kind,
+                position: token.position,
+            });
+            idx = cursor + 2;
+            statement_start = true;
+            continue;
+        }
+
+        if token.is_symbol('{') {
+            depth += 1;
+        } else if token.is_symbol('}') {
+            depth = depth.saturating_sub(1);
+        }
+        statement_start = token.is_symbol(';') || token.is_symbol('{') || token.is_symbol('}');
+        idx += 1;
+    }
+
+    Ok(imports)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths(source: &str) -> Vec<(String, ImportKind)> {
+        extract_imports(source)
+            .unwrap()
+            .into_iter()
+            .map(|i| (i.path, i.kind))
+            .collect()
+    }
+
+    #[test]
+    fn test_tokenize_basic() {
+        let tokens = tokenize("message Foo { int32 x = 0x1F; } // done").unwrap();
+        let kinds: Vec<_> = tokens.iter().map(|t| t.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                TokenKind::Ident,
+                TokenKind::Ident,
+                TokenKind::Symbol,
+                TokenKind::Ident,
+                TokenKind::Ident,
+                TokenKind::Symbol,
+                TokenKind::IntLit,
+                TokenKind::Symbol,
+                TokenKind::Symbol,
+                TokenKind::Comment,
+            ]
+        );
+        assert_eq!(tokens[6].text, "0x1F");
+        assert_eq!(tokens[9].position.column, 33);
+    }
+
+    #[test]
+    fn test_tokenize_numbers() {
+        let tokens = tokenize("1 1.5 .5 1e10 2E-3 017").unwrap();
+        let kinds: Vec<_> = tokens.iter().map(|t| t.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                TokenKind::IntLit,
+                TokenKind::FloatLit,
+                TokenKind::FloatLit,
+                TokenKind::FloatLit,
+                TokenKind::FloatLit,
+                TokenKind::IntLit,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_decode_str_lit() {
+        assert_eq!(decode_str_lit(r#""a\"b""#).unwrap(), "a\"b");
+        assert_eq!(decode_str_lit(r"'\x41\101\n'").unwrap(), "AA\n");
+        assert!(decode_str_lit(r#""\q""#).is_err());
+    }
+
+    #[test]
+    fn test_extract_import_kinds_and_positions() {
+        let source = "syntax = \"proto3\";\nimport \"a.proto\";\n  import public \"b.proto\";\nimport weak 'c.proto';\n";
+        let imports = extract_imports(source).unwrap();
+        assert_eq!(imports.len(), 3);
+        assert_eq!(imports[0].kind, ImportKind::Default);
+        assert_eq!(imports[1].kind, ImportKind::Public);
+        assert_eq!(imports[2].kind, ImportKind::Weak);
+        assert_eq!(imports[2].path, "c.proto");
+        assert_eq!(
+            (imports[1].position.line, imports[1].position.column),
+            (3, 3)
+        );
+    }
+
+    #[test]
+    fn test_extract_imports_sharing_lines_and_spanning_lines() {
+        let source = "syntax = \"proto3\"; import \"a.proto\"; import\n  public\n  \"b.proto\"\n;";
+        assert_eq!(
+            paths(source),
+            vec![
+                ("a.proto".to_string(), ImportKind::Default),
+                ("b.proto".to_string(), ImportKind::Public),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_extract_imports_ignores_comments_and_strings() {
+        let source = r#"
+syntax = "proto3";
+/*
+import "commented.proto";
+*/
+// import "line_comment.proto";
+option java_package = "import \"fake.proto\";";
+import "real.proto";
+message Foo {
+  string import = 1;
+}
+"#;
+        assert_eq!(
+            paths(source),
+            vec![("real.proto".to_string(), ImportKind::Default)]
+        );
+    }
+
+    #[test]
+    fn test_lexer_errors() {
+        assert!(tokenize("/* never closed").is_err());
+        assert!(tokenize("\"never closed").is_err());
+        assert!(extract_imports("import foo;").is_err());
+    }
+}
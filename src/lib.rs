@@ -4,73 +4,101 @@
 //! - Merging multiple proto files by package
 //! - Normalizing proto file formatting
 //! - Generating semantic fingerprints
+//! - Canonicalizing equivalent descriptors
 //! - Converting descriptors to proto text
 
+mod builtin_options;
+pub mod canonical;
+mod comments;
+mod custom_options;
+pub mod diff;
+mod editions;
+mod googleapis;
+pub mod lexer;
 pub mod merge;
+mod names;
+pub mod session;
 pub mod text_gen;
+mod vfs;
 
 // Re-export main types
-pub use merge::{merge_by_package, MergeResult};
-pub use text_gen::{descriptor_to_proto, TextGenerator, TextGeneratorOptions};
+pub use canonical::canonicalize;
+pub use diff::{diff_file_descriptors, DescriptorDifference, DifferenceKind};
+pub use merge::{
+    merge_by_package, merge_by_package_with_session, merge_descriptor_set, MergeResult,
+};
+pub use session::{Backend, ParseSession, SourceFile, StubPolicy};
+pub use text_gen::{
+    descriptor_set_to_proto, descriptor_to_proto, descriptor_to_proto_with_imports,
+    verify_roundtrip, BlankLines, DefinitionKind, OptionPlacement, Style, TextGenerator,
+    TextGeneratorOptions,
+};
 
 use anyhow::{Context, Result};
-use protobuf::descriptor::FileDescriptorProto;
-use protobuf_parse::Parser;
+use protobuf::descriptor::{FileDescriptorProto, FileDescriptorSet};
+use protobuf::Message;
 use sha2::{Digest, Sha256};
 
-/// Parse proto content string into FileDescriptorProto.
-pub fn parse_proto_to_file_descriptor(proto_content: &str) -> Result<FileDescriptorProto> {
-    let temp_dir = tempfile::tempdir().context("Failed to create temp directory")?;
-    let file_name = "input.proto";
-    let temp_path = temp_dir.path().join(file_name);
-    std::fs::write(&temp_path, proto_content).context("Failed to write temp file")?;
-
-    // Create dummy imports
-    for line in proto_content.lines() {
-        if line.trim().starts_with("import ") {
-            let path_str = line
-                .trim()
-                .trim_start_matches("import ")
-                .trim_start_matches("public ")
-                .trim_start_matches("weak ")
-                .trim_matches(|c| c == '"' || c == ';')
-                .trim();
-
-            if !path_str.starts_with("google/protobuf/") {
-                let import_path = temp_dir.path().join(path_str);
-                if let Some(parent) = import_path.parent() {
-                    std::fs::create_dir_all(parent).context("Failed to create import dirs")?;
-                }
-                std::fs::write(&import_path, "syntax = \"proto3\";")
-                    .context("Failed to create dummy import")?;
-            }
-        }
+/// Parse proto content into FileDescriptorProto.
+///
+/// Accepts either the content alone or a `(path, content)` pair; the path
+/// becomes the descriptor `name` (default `input.proto`).
+///
+/// Imports are resolved leniently: anything outside `google/protobuf/` (and
+/// the bundled googleapis protos, with the `googleapis` feature) is replaced
+/// with an empty stub. Use a [`ParseSession`] to resolve imports against real
+/// files.
+pub fn parse_proto_to_file_descriptor(
+    source: impl Into<SourceFile>,
+) -> Result<FileDescriptorProto> {
+    let source = source.into();
+    let mut session = ParseSession::lenient();
+    if let Some(path) = source.path {
+        session = session.with_file_name(path);
     }
+    session.parse(&source.content)
+}
 
-    // Parse
-    let parsed = Parser::new()
-        .pure()
-        .include(temp_dir.path())
-        .input(&temp_path)
-        .file_descriptor_set()
-        .context("Protobuf parsing failed")?;
-
-    let file_descriptor = parsed
-        .file
-        .into_iter()
-        .find(|d| d.name() == file_name)
-        .context("Could not find the parsed file descriptor")?;
-
-    Ok(file_descriptor)
+/// Decode a serialized `FileDescriptorSet`, as written by
+/// `protoc --descriptor_set_out` or `buf build`.
+pub fn decode_descriptor_set(bytes: &[u8]) -> Result<FileDescriptorSet> {
+    FileDescriptorSet::parse_from_bytes(bytes).context("Failed to decode FileDescriptorSet")
 }
 
 /// Generate semantic fingerprint for proto content.
+///
+/// The fingerprint is the SHA-256 of the text of the [`canonicalize`]d
+/// descriptor, so equivalent spellings of a file share a fingerprint.
 pub fn generate_fingerprint(proto_content: &str) -> Result<String> {
-    let descriptor = parse_proto_to_file_descriptor(proto_content)?;
-    let normalized = text_gen::descriptor_to_proto(&descriptor)?;
+    generate_fingerprint_with_session(proto_content, &ParseSession::lenient())
+}
+
+/// Generate semantic fingerprint for proto content parsed by `session`.
+pub fn generate_fingerprint_with_session(
+    proto_content: &str,
+    session: &ParseSession,
+) -> Result<String> {
+    let (descriptor, imports) = session.parse_with_imports(proto_content)?;
+    fingerprint_descriptor(&descriptor, imports)
+}
+
+/// SHA-256 of the canonical text of `descriptor`, whose custom options are
+/// declared in `imports`.
+///
+/// Custom options whose extension is not in `imports` are hashed by field
+/// number and raw value rather than left out.
+pub(crate) fn fingerprint_descriptor(
+    descriptor: &FileDescriptorProto,
+    imports: impl IntoIterator<Item = FileDescriptorProto>,
+) -> Result<String> {
+    let descriptor = canonicalize(descriptor);
 
+    // Hash the normalized text as it is generated
     let mut hasher = Sha256::new();
-    hasher.update(normalized.as_bytes());
+    TextGenerator::with_default()
+        .with_imports(imports)
+        .with_raw_unresolved_options()
+        .format_file_to_io(&descriptor, &mut hasher)?;
     let hash_result = hasher.finalize();
 
     Ok(format!("{hash_result:x}"))
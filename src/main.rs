@@ -1,9 +1,13 @@
 //! Proto-regulate CLI tool for debugging and testing
 
 use anyhow::{bail, Context, Result};
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 use log::{debug, error, info, warn};
-use proto_regulate::{descriptor_to_proto, merge_by_package, parse_proto_to_file_descriptor};
+use proto_regulate::{
+    decode_descriptor_set, merge_by_package, merge_descriptor_set, parse_proto_to_file_descriptor,
+    MergeResult, ParseSession, SourceFile, Style, TextGenerator, TextGeneratorOptions,
+};
+use protobuf::descriptor::FileDescriptorProto;
 use std::fs;
 use std::path::{Path, PathBuf};
 
@@ -23,6 +27,8 @@ struct Cli {
 enum Commands {
     /// Normalize proto file(s)
     /// - File mode: normalize a single proto file
+    /// - Descriptor set mode (.pb/.binpb): normalize a single-file set, or
+    ///   merge a multi-file set by package into the output directory
     /// - Directory mode: merge all proto files by package and split output
     Normalize {
         /// Input path (file or directory)
@@ -32,16 +38,52 @@ enum Commands {
         /// Output directory (required for directory mode)
         #[arg(short, long, value_name = "DIR")]
         output: Option<PathBuf>,
+
+        /// Keep comments (file and single-file descriptor set modes)
+        #[arg(long)]
+        keep_comments: bool,
+
+        /// Keep the declaration order instead of sorting (file and
+        /// single-file descriptor set modes)
+        #[arg(long)]
+        preserve_order: bool,
+
+        /// Check that the output parses back to the same descriptor (file
+        /// and single-file descriptor set modes)
+        #[arg(long)]
+        verify_roundtrip: bool,
+
+        /// Layout preset (file and single-file descriptor set modes)
+        #[arg(long, value_enum, default_value_t = StyleName::Canonical)]
+        style: StyleName,
     },
 
     /// Inspect proto file descriptor (output JSON format)
     Inspect {
-        /// Proto file path
+        /// Proto file or descriptor set (.pb/.binpb) path
         #[arg(value_name = "FILE")]
         file: PathBuf,
     },
 }
 
+/// Layout presets selectable with `--style`
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum StyleName {
+    Canonical,
+    Google,
+    Buf,
+}
+
+impl From<StyleName> for Style {
+    fn from(name: StyleName) -> Self {
+        match name {
+            StyleName::Canonical => Style::canonical(),
+            StyleName::Google => Style::google(),
+            StyleName::Buf => Style::buf(),
+        }
+    }
+}
+
 fn main() {
     let cli = Cli::parse();
 
@@ -63,12 +105,37 @@ fn main() {
 
 fn run(cli: Cli) -> Result<()> {
     match cli.command {
-        Commands::Normalize { input, output } => {
-            if input.is_file() {
+        Commands::Normalize {
+            input,
+            output,
+            keep_comments,
+            preserve_order,
+            verify_roundtrip,
+            style,
+        } => {
+            let options = TextGeneratorOptions {
+                include_comments: keep_comments,
+                preserve_order,
+                style: style.into(),
+                ..Default::default()
+            };
+            if input.is_file() && is_descriptor_set(&input) {
+                debug!("描述符集模式: 规范化 FileDescriptorSet");
+                normalize_descriptor_set(&input, output.as_deref(), options, verify_roundtrip)
+            } else if input.is_file() {
                 debug!("文件模式: 规范化单个文件");
-                normalize_file(&input, output.as_deref())
+                normalize_file(&input, output.as_deref(), options, verify_roundtrip)
             } else if input.is_dir() {
                 debug!("目录模式: 合并并分拆 proto 文件");
+                if keep_comments || preserve_order {
+                    warn!("目录模式合并后的文件不保留注释和声明顺序");
+                }
+                if verify_roundtrip {
+                    warn!("目录模式不支持往返校验");
+                }
+                if style != StyleName::Canonical {
+                    warn!("目录模式合并后的文件始终使用规范样式");
+                }
                 normalize_directory(&input, output.as_deref())
             } else {
                 bail!("输入路径不存在或无效: {}", input.display());
@@ -79,16 +146,51 @@ fn run(cli: Cli) -> Result<()> {
 }
 
 /// 规范化单个文件
-fn normalize_file(input: &Path, output: Option<&Path>) -> Result<()> {
+fn normalize_file(
+    input: &Path,
+    output: Option<&Path>,
+    options: TextGeneratorOptions,
+    verify: bool,
+) -> Result<()> {
     info!("读取文件: {}", input.display());
     let content = fs::read_to_string(input).context("读取输入文件失败")?;
 
     debug!("解析 proto 文件");
-    let descriptor = parse_proto_to_file_descriptor(&content).context("解析 proto 文件失败")?;
+    let (descriptor, imports) = ParseSession::lenient()
+        .with_file_name(file_name(input)?)
+        .parse_with_imports(&content)
+        .context("解析 proto 文件失败")?;
 
     debug!("生成规范化内容");
-    let normalized = descriptor_to_proto(&descriptor).context("生成规范化内容失败")?;
+    let normalized =
+        generate_normalized(&descriptor, imports, options, verify).context("生成规范化内容失败")?;
 
+    write_normalized(&normalized, output)
+}
+
+/// 按 `options` 生成单个文件的规范化内容，`verify` 时先做往返校验
+fn generate_normalized(
+    descriptor: &FileDescriptorProto,
+    imports: Vec<FileDescriptorProto>,
+    options: TextGeneratorOptions,
+    verify: bool,
+) -> Result<String> {
+    let mut generator = TextGenerator::new(options).with_imports(imports);
+    if verify {
+        debug!("往返校验");
+        let differences = generator.verify_roundtrip(descriptor)?;
+        for difference in &differences {
+            warn!("往返差异: {difference}");
+        }
+        if !differences.is_empty() {
+            bail!("往返校验失败: {} 处差异", differences.len());
+        }
+    }
+    generator.format_file(descriptor)
+}
+
+/// 写入规范化内容到输出文件，未指定输出时打印到 stdout
+fn write_normalized(normalized: &str, output: Option<&Path>) -> Result<()> {
     if let Some(output_path) = output {
         info!("写入输出文件: {}", output_path.display());
         fs::write(output_path, normalized).context("写入输出文件失败")?;
@@ -114,20 +216,71 @@ fn normalize_directory(input: &Path, output: Option<&Path>) -> Result<()> {
 
     info!("找到 {} 个 proto 文件", proto_files.len());
 
-    // 读取所有文件内容
-    let mut contents = Vec::new();
+    // 读取所有文件内容，以相对于输入目录的路径命名
+    let mut sources = Vec::new();
     for file in &proto_files {
         debug!("读取文件: {}", file.display());
         let content = fs::read_to_string(file)
             .with_context(|| format!("读取文件失败: {}", file.display()))?;
-        contents.push(content);
+        sources.push((file_name(file)?, content));
     }
 
     // 按 package 合并
     info!("按 package 合并文件");
-    let results =
-        merge_by_package(contents.iter().map(|s| s.as_str()).collect()).context("合并文件失败")?;
+    let results = merge_by_package(sources).context("合并文件失败")?;
+
+    write_merge_results(results, output_dir)?;
+
+    info!("目录规范化完成");
+    Ok(())
+}
+
+/// 规范化 FileDescriptorSet（单文件直接输出，多文件按 package 合并）
+fn normalize_descriptor_set(
+    input: &Path,
+    output: Option<&Path>,
+    options: TextGeneratorOptions,
+    verify: bool,
+) -> Result<()> {
+    info!("读取描述符集: {}", input.display());
+    let bytes = fs::read(input).context("读取输入文件失败")?;
+
+    let set = decode_descriptor_set(&bytes).context("解析描述符集失败")?;
+    let files: Vec<_> = set
+        .file
+        .iter()
+        .filter(|f| !f.name().starts_with("google/protobuf/"))
+        .collect();
+    info!("描述符集中有 {} 个文件", files.len());
+
+    if let [descriptor] = files.as_slice() {
+        debug!("生成规范化内容");
+        let normalized = generate_normalized(descriptor, set.file.clone(), options, verify)
+            .context("生成规范化内容失败")?;
+        return write_normalized(&normalized, output);
+    }
+
+    let output_dir = output.context("包含多个文件的描述符集需要指定 --output 参数")?;
+    if options.include_comments || options.preserve_order {
+        warn!("多文件描述符集合并后的文件不保留注释和声明顺序");
+    }
+    if verify {
+        warn!("多文件描述符集不支持往返校验");
+    }
+    if options.style != Style::canonical() {
+        warn!("多文件描述符集合并后的文件始终使用规范样式");
+    }
+
+    info!("按 package 合并文件");
+    let results = merge_descriptor_set(&bytes).context("合并文件失败")?;
+    write_merge_results(results, output_dir)?;
 
+    info!("描述符集规范化完成");
+    Ok(())
+}
+
+/// 将合并结果按 package 写入输出目录
+fn write_merge_results(results: Vec<MergeResult>, output_dir: &Path) -> Result<()> {
     info!("生成 {} 个合并后的 package", results.len());
 
     // 创建输出目录
@@ -155,7 +308,6 @@ fn normalize_directory(input: &Path, output: Option<&Path>) -> Result<()> {
         debug!("指纹: {}", result.fingerprint);
     }
 
-    info!("目录规范化完成");
     Ok(())
 }
 
@@ -176,13 +328,42 @@ fn collect_proto_files(dir: &Path) -> Result<Vec<PathBuf>> {
     Ok(proto_files)
 }
 
+/// 判断文件是否为序列化的 FileDescriptorSet
+fn is_descriptor_set(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(|s| s.to_str()),
+        Some("pb" | "binpb")
+    )
+}
+
+/// 获取文件名，作为 descriptor 的逻辑路径
+fn file_name(path: &Path) -> Result<String> {
+    path.file_name()
+        .and_then(|s| s.to_str())
+        .map(str::to_string)
+        .with_context(|| format!("无效的文件名: {}", path.display()))
+}
+
 /// 查看文件 descriptor
 fn inspect_file(file: &Path) -> Result<()> {
+    if is_descriptor_set(file) {
+        info!("读取描述符集: {}", file.display());
+        let bytes = fs::read(file).context("读取文件失败")?;
+        let set = decode_descriptor_set(&bytes).context("解析描述符集失败")?;
+
+        debug!("输出 descriptor 详细信息");
+        for descriptor in &set.file {
+            println!("{descriptor:#?}");
+        }
+        return Ok(());
+    }
+
     info!("读取文件: {}", file.display());
     let content = fs::read_to_string(file).context("读取文件失败")?;
 
     debug!("解析 proto 文件");
-    let descriptor = parse_proto_to_file_descriptor(&content).context("解析 proto 文件失败")?;
+    let descriptor = parse_proto_to_file_descriptor(SourceFile::new(file_name(file)?, content))
+        .context("解析 proto 文件失败")?;
 
     debug!("输出 descriptor 详细信息");
     println!("{descriptor:#?}");
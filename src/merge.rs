@@ -3,24 +3,25 @@
 //! Merges multiple proto file contents by package name, producing
 //! normalized, deduplicated output with semantic fingerprints.
 
+use crate::editions;
 use crate::text_gen::{TextGenerator, TextGeneratorOptions, TEXT_GENERATOR_VERSION};
-use anyhow::{anyhow, bail, Context, Result};
+use crate::{ParseSession, SourceFile};
+use anyhow::{bail, Context, Result};
 use protobuf::descriptor::FileDescriptorProto;
-use protobuf_parse::Parser;
 use std::collections::{BTreeMap, BTreeSet, HashMap};
-use tempfile::TempDir;
 
 /// Version of the merge algorithm.
 /// Format: "{merge_version}+{text_gen_version}"
 pub const MERGE_ALGORITHM_VERSION: &str =
-    const_format::formatcp!("1.0.0+{}", TEXT_GENERATOR_VERSION);
+    const_format::formatcp!("1.1.0+{}", TEXT_GENERATOR_VERSION);
 
 /// Result of merging proto files by package.
 #[derive(Debug, Clone)]
 pub struct MergeResult {
     /// Package name (empty string for files without package declaration)
     pub package_name: String,
-    /// Normalized proto content
+    /// Normalized proto content, in the canonical form of
+    /// [`crate::canonicalize`]
     pub content: String,
     /// Semantic fingerprint of the content
     pub fingerprint: String,
@@ -32,7 +33,9 @@ pub struct MergeResult {
 ///
 /// # Arguments
 ///
-/// * `files` - Vector of proto file contents (as strings)
+/// * `files` - Proto files, either as plain contents or as `(path, content)`
+///   pairs. Paths become descriptor names and are used in error messages;
+///   files without a path are referred to by index (`file #3`).
 ///
 /// # Returns
 ///
@@ -67,106 +70,138 @@ pub struct MergeResult {
 /// assert_eq!(results.len(), 1);
 /// assert_eq!(results[0].package_name, "foo.bar");
 /// ```
-pub fn merge_by_package(files: Vec<&str>) -> Result<Vec<MergeResult>> {
+pub fn merge_by_package(files: Vec<impl Into<SourceFile>>) -> Result<Vec<MergeResult>> {
+    merge_by_package_with_session(files, &ParseSession::lenient())
+}
+
+/// Merges multiple proto file contents by package name, parsing every file
+/// with `session`.
+///
+/// [`merge_by_package`] is equivalent to calling this with
+/// [`ParseSession::lenient`].
+pub fn merge_by_package_with_session(
+    files: Vec<impl Into<SourceFile>>,
+    session: &ParseSession,
+) -> Result<Vec<MergeResult>> {
+    let files: Vec<SourceFile> = files.into_iter().map(Into::into).collect();
     if files.is_empty() {
         return Ok(Vec::new());
     }
 
     // Step 1: Parse all files
-    let parsed_files = parse_all_files(&files)?;
+    let (parsed_files, imports) = parse_all_files(&files, session)?;
+
+    merge_parsed_files(parsed_files, imports)
+}
+
+/// Merges the files of a serialized `FileDescriptorSet` by package name.
+///
+/// Files under `google/protobuf/` are skipped, since the parser already
+/// bundles them. Errors refer to files by their descriptor `name`. The files
+/// of a set are already parsed and the merged output is fingerprinted
+/// without parsing it again, so no file is written to disk.
+pub fn merge_descriptor_set(bytes: &[u8]) -> Result<Vec<MergeResult>> {
+    let set = crate::decode_descriptor_set(bytes)?;
+
+    let mut parsed_files = Vec::new();
+    for (idx, descriptor) in set.file.iter().cloned().enumerate() {
+        if descriptor.name().starts_with("google/protobuf/") {
+            continue;
+        }
+        let label = match descriptor.name() {
+            "" => format!("descriptor #{idx}"),
+            name => name.to_string(),
+        };
+        parsed_files.push(ParsedFile {
+            descriptor,
+            label,
+            original_content: None,
+        });
+    }
+
+    merge_parsed_files(parsed_files, set.file)
+}
 
-    // Step 2: Group by package
+// ========== Internal Implementation ==========
+
+/// Merge parsed files by package. `imports` are the files the inputs import,
+/// used with the inputs themselves to render custom options.
+fn merge_parsed_files(
+    parsed_files: Vec<ParsedFile>,
+    mut imports: Vec<FileDescriptorProto>,
+) -> Result<Vec<MergeResult>> {
+    imports.extend(parsed_files.iter().map(|f| f.descriptor.clone()));
+
+    // Group by package
     let grouped = group_by_package(parsed_files)?;
 
-    // Step 3: Merge each package group
+    // Merge each package group
     let mut results = Vec::new();
     for (package_name, file_group) in grouped {
-        let merge_result = merge_package_group(&package_name, file_group)?;
+        let merge_result = merge_package_group(&package_name, file_group, &imports)?;
         results.push(merge_result);
     }
 
-    // Step 4: Sort by package name for deterministic output
+    // Sort by package name for deterministic output
     results.sort_by(|a, b| a.package_name.cmp(&b.package_name));
 
     Ok(results)
 }
 
-// ========== Internal Implementation ==========
-
 struct ParsedFile {
     descriptor: FileDescriptorProto,
+    /// How the file is referred to in errors and warnings
+    label: String,
+    /// Source text, `None` for files from a descriptor set
     #[allow(dead_code)]
-    original_content: String,
+    original_content: Option<String>,
 }
 
-fn parse_all_files(files: &[&str]) -> Result<Vec<ParsedFile>> {
+/// Parse every input in one parser run, returning the parsed inputs and the
+/// files they import.
+fn parse_all_files(
+    files: &[SourceFile],
+    session: &ParseSession,
+) -> Result<(Vec<ParsedFile>, Vec<FileDescriptorProto>)> {
+    // Give every input a distinct path so all of them share one parser run
+    let mut names: Vec<String> = Vec::with_capacity(files.len());
+    let mut labels: Vec<String> = Vec::with_capacity(files.len());
+    let mut seen_paths: HashMap<&str, usize> = HashMap::new();
+    for (idx, file) in files.iter().enumerate() {
+        match &file.path {
+            Some(path) => {
+                if let Some(prev_idx) = seen_paths.insert(path, idx) {
+                    bail!("Duplicate input path '{path}' (files #{prev_idx} and #{idx})");
+                }
+                names.push(path.clone());
+                labels.push(path.clone());
+            }
+            None => {
+                names.push(format!("input_{idx}.proto"));
+                labels.push(format!("file #{idx}"));
+            }
+        }
+    }
+    let inputs: Vec<(&str, &str)> = names
+        .iter()
+        .map(String::as_str)
+        .zip(files.iter().map(|f| f.content.as_str()))
+        .collect();
+
     let mut parsed = Vec::new();
+    let results = session.parse_files(&inputs)?;
 
-    for (idx, content) in files.iter().enumerate() {
-        let descriptor =
-            parse_proto_content(content).with_context(|| format!("Failed to parse file #{idx}"))?;
+    for ((file, label), result) in files.iter().zip(labels).zip(results.inputs) {
+        let descriptor = result.with_context(|| format!("Failed to parse {label}"))?;
 
         parsed.push(ParsedFile {
             descriptor,
-            original_content: content.to_string(),
+            label,
+            original_content: Some(file.content.clone()),
         });
     }
 
-    Ok(parsed)
-}
-
-fn parse_proto_content(content: &str) -> Result<FileDescriptorProto> {
-    // Create temporary directory for parsing
-    let temp_dir = tempfile::tempdir().context("Failed to create temp directory")?;
-    let file_name = "input.proto";
-    let temp_path = temp_dir.path().join(file_name);
-    std::fs::write(&temp_path, content).context("Failed to write temp file")?;
-
-    // Handle imports by creating dummy files
-    create_dummy_imports(content, &temp_dir)?;
-
-    // Parse using protobuf-parse
-    let parsed = Parser::new()
-        .pure()
-        .include(temp_dir.path())
-        .input(&temp_path)
-        .file_descriptor_set()
-        .context("Protobuf parsing failed")?;
-
-    // Find our input file in the parsed results
-    let file_descriptor = parsed
-        .file
-        .into_iter()
-        .find(|d| d.name() == file_name)
-        .ok_or_else(|| anyhow!("Could not find parsed file descriptor"))?;
-
-    Ok(file_descriptor)
-}
-
-fn create_dummy_imports(content: &str, temp_dir: &TempDir) -> Result<()> {
-    for line in content.lines() {
-        let trimmed = line.trim();
-        if trimmed.starts_with("import ") {
-            let path_str = trimmed
-                .trim_start_matches("import ")
-                .trim_start_matches("public ")
-                .trim_start_matches("weak ")
-                .trim_matches(|c| c == '"' || c == ';')
-                .trim();
-
-            // Skip google standard imports
-            if !path_str.starts_with("google/protobuf/") {
-                let import_path = temp_dir.path().join(path_str);
-                if let Some(parent) = import_path.parent() {
-                    std::fs::create_dir_all(parent)
-                        .with_context(|| format!("Failed to create import dir: {path_str}"))?;
-                }
-                std::fs::write(&import_path, "syntax = \"proto3\";")
-                    .with_context(|| format!("Failed to create dummy import: {path_str}"))?;
-            }
-        }
-    }
-    Ok(())
+    Ok((parsed, results.imports))
 }
 
 fn group_by_package(files: Vec<ParsedFile>) -> Result<BTreeMap<String, Vec<ParsedFile>>> {
@@ -180,7 +215,11 @@ fn group_by_package(files: Vec<ParsedFile>) -> Result<BTreeMap<String, Vec<Parse
     Ok(groups)
 }
 
-fn merge_package_group(package_name: &str, files: Vec<ParsedFile>) -> Result<MergeResult> {
+fn merge_package_group(
+    package_name: &str,
+    files: Vec<ParsedFile>,
+    imports: &[FileDescriptorProto],
+) -> Result<MergeResult> {
     let mut warnings = Vec::new();
 
     // Validate syntax consistency
@@ -189,6 +228,9 @@ fn merge_package_group(package_name: &str, files: Vec<ParsedFile>) -> Result<Mer
     // Create merged descriptor
     let mut merged = FileDescriptorProto::new();
     merged.set_syntax(syntax.to_string());
+    if let Some(edition) = files.first().and_then(|f| editions::edition(&f.descriptor)) {
+        editions::set_edition(&mut merged, &edition)?;
+    }
     if !package_name.is_empty() {
         merged.set_package(package_name.to_string());
     }
@@ -212,14 +254,17 @@ fn merge_package_group(package_name: &str, files: Vec<ParsedFile>) -> Result<Mer
     merge_extensions(&files, &mut merged);
 
     // Generate canonical text using TextGenerator
-    let mut generator = TextGenerator::new(TextGeneratorOptions::default());
+    let merged = crate::canonicalize(&merged);
+    let mut generator =
+        TextGenerator::new(TextGeneratorOptions::default()).with_imports(imports.iter().cloned());
     let content = generator
         .format_file(&merged)
         .context("Failed to generate canonical text")?;
 
-    // Generate fingerprint
-    let fingerprint =
-        crate::generate_fingerprint(&content).context("Failed to generate fingerprint")?;
+    // Generate fingerprint from the merged descriptor; parsing `content`
+    // back would give the same descriptor
+    let fingerprint = crate::fingerprint_descriptor(&merged, imports.iter().cloned())
+        .context("Failed to generate fingerprint")?;
 
     Ok(MergeResult {
         package_name: package_name.to_string(),
@@ -229,6 +274,8 @@ fn merge_package_group(package_name: &str, files: Vec<ParsedFile>) -> Result<Mer
     })
 }
 
+/// Check that all files share the same syntax, or the same edition and
+/// file-level features, and return that syntax.
 fn validate_syntax_consistency<'a>(
     files: &'a [ParsedFile],
     _warnings: &mut [String],
@@ -236,7 +283,15 @@ fn validate_syntax_consistency<'a>(
     let mut syntaxes = BTreeSet::new();
 
     for file in files {
-        let syntax = file.descriptor.syntax.as_deref().unwrap_or("proto2");
+        let syntax = match editions::edition(&file.descriptor) {
+            Some(edition) => format!("edition {edition}"),
+            None => file
+                .descriptor
+                .syntax
+                .as_deref()
+                .unwrap_or("proto2")
+                .to_string(),
+        };
         syntaxes.insert(syntax);
     }
 
@@ -246,7 +301,27 @@ fn validate_syntax_consistency<'a>(
         );
     }
 
-    Ok(syntaxes.into_iter().next().unwrap_or("proto2"))
+    // File-level features are defaults for every element of the file, so
+    // files with different defaults cannot share one merged file
+    let mut first_features: Option<(&str, Vec<String>)> = None;
+    for file in files {
+        let features = editions::features(&*file.descriptor.options)?;
+        match &first_features {
+            None => first_features = Some((&file.label, features)),
+            Some((first_label, first)) if *first != features => bail!(
+                "File-level features conflict between {first_label} ({}) and {} ({}). All files in the same package must use the same features.",
+                first.join(", "),
+                file.label,
+                features.join(", ")
+            ),
+            Some(_) => {}
+        }
+    }
+
+    Ok(files
+        .first()
+        .and_then(|f| f.descriptor.syntax.as_deref())
+        .unwrap_or("proto2"))
 }
 
 fn merge_imports(files: &[ParsedFile], merged: &mut FileDescriptorProto) {
@@ -303,18 +378,19 @@ fn merge_file_options(
     }
 
     // Check for conflicts in subsequent files
-    for (idx, file) in files.iter().enumerate().skip(1) {
+    for file in files.iter().skip(1) {
+        let label = &file.label;
         if let Some(opts) = file.descriptor.options.as_ref() {
             if let Some(merged_opts) = merged.options.as_ref() {
                 // Compare key options
                 if opts.java_package != merged_opts.java_package && opts.java_package.is_some() {
                     warnings.push(format!(
-                        "File #{idx}: java_package option conflict (using first occurrence)"
+                        "java_package option conflict in {label} (using first occurrence)"
                     ));
                 }
                 if opts.go_package != merged_opts.go_package && opts.go_package.is_some() {
                     warnings.push(format!(
-                        "File #{idx}: go_package option conflict (using first occurrence)"
+                        "go_package option conflict in {label} (using first occurrence)"
                     ));
                 }
             }
@@ -328,16 +404,19 @@ fn merge_messages(files: &[ParsedFile], merged: &mut FileDescriptorProto) -> Res
     let mut seen_names = HashMap::new();
     let mut all_messages = Vec::new();
 
-    for (file_idx, file) in files.iter().enumerate() {
+    for file in files {
         for message in file.descriptor.message_type.iter() {
             let name = message.name();
 
             // Check for duplicates
-            if let Some(&prev_idx) = seen_names.get(name) {
-                bail!("Duplicate message '{name}' found in files #{prev_idx} and #{file_idx}");
+            if let Some(prev) = seen_names.get(name) {
+                bail!(
+                    "Duplicate message '{name}' found in {prev} and {}",
+                    file.label
+                );
             }
 
-            seen_names.insert(name.to_string(), file_idx);
+            seen_names.insert(name.to_string(), file.label.as_str());
             all_messages.push(message.clone());
         }
     }
@@ -353,16 +432,16 @@ fn merge_enums(files: &[ParsedFile], merged: &mut FileDescriptorProto) -> Result
     let mut seen_names = HashMap::new();
     let mut all_enums = Vec::new();
 
-    for (file_idx, file) in files.iter().enumerate() {
+    for file in files {
         for enum_type in file.descriptor.enum_type.iter() {
             let name = enum_type.name();
 
             // Check for duplicates
-            if let Some(&prev_idx) = seen_names.get(name) {
-                bail!("Duplicate enum '{name}' found in files #{prev_idx} and #{file_idx}");
+            if let Some(prev) = seen_names.get(name) {
+                bail!("Duplicate enum '{name}' found in {prev} and {}", file.label);
             }
 
-            seen_names.insert(name.to_string(), file_idx);
+            seen_names.insert(name.to_string(), file.label.as_str());
             all_enums.push(enum_type.clone());
         }
     }
@@ -378,16 +457,19 @@ fn merge_services(files: &[ParsedFile], merged: &mut FileDescriptorProto) -> Res
     let mut seen_names = HashMap::new();
     let mut all_services = Vec::new();
 
-    for (file_idx, file) in files.iter().enumerate() {
+    for file in files {
         for service in file.descriptor.service.iter() {
             let name = service.name();
 
             // Check for duplicates
-            if let Some(&prev_idx) = seen_names.get(name) {
-                bail!("Duplicate service '{name}' found in files #{prev_idx} and #{file_idx}");
+            if let Some(prev) = seen_names.get(name) {
+                bail!(
+                    "Duplicate service '{name}' found in {prev} and {}",
+                    file.label
+                );
             }
 
-            seen_names.insert(name.to_string(), file_idx);
+            seen_names.insert(name.to_string(), file.label.as_str());
             all_services.push(service.clone());
         }
     }
@@ -408,11 +490,12 @@ fn merge_extensions(files: &[ParsedFile], merged: &mut FileDescriptorProto) {
         }
     }
 
-    // Sort by extendee and then by field number
+    // Sort by extendee, then by field number and name
     all_extensions.sort_by(|a, b| {
         a.extendee
             .cmp(&b.extendee)
             .then_with(|| a.number().cmp(&b.number()))
+            .then_with(|| a.name().cmp(b.name()))
     });
 
     merged.extension = all_extensions;
@@ -424,13 +507,13 @@ mod tests {
 
     #[test]
     fn test_merge_algorithm_version() {
-        assert!(MERGE_ALGORITHM_VERSION.starts_with("1.0.0+"));
+        assert!(MERGE_ALGORITHM_VERSION.starts_with("1.1.0+"));
         assert!(MERGE_ALGORITHM_VERSION.contains(TEXT_GENERATOR_VERSION));
     }
 
     #[test]
     fn test_empty_input() {
-        let result = merge_by_package(vec![]).unwrap();
+        let result = merge_by_package(Vec::<&str>::new()).unwrap();
         assert_eq!(result.len(), 0);
     }
 
@@ -696,6 +779,212 @@ message Profile {
         assert!(empty_pos < timestamp_pos);
     }
 
+    #[test]
+    fn test_parse_error_names_failing_file() {
+        let valid = r#"
+syntax = "proto3";
+package test;
+message Ok { string name = 1; }
+"#;
+        let invalid = r#"
+syntax = "proto3";
+package test;
+message Broken { string name = ; }
+"#;
+
+        let err = merge_by_package(vec![valid, invalid, valid]).unwrap_err();
+        assert!(
+            format!("{err:#}").contains("Failed to parse file #1"),
+            "Unexpected error: {err:#}"
+        );
+    }
+
+    #[test]
+    fn test_errors_name_input_paths() {
+        let file1 = "syntax = \"proto3\"; package foo; message User { string name = 1; }";
+        let file2 = "syntax = \"proto3\"; package foo; message User { int32 id = 1; }";
+
+        let err =
+            merge_by_package(vec![("foo/a.proto", file1), ("foo/b.proto", file2)]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Duplicate message 'User' found in foo/a.proto and foo/b.proto"
+        );
+
+        let err =
+            merge_by_package(vec![("foo/a.proto", file1), ("foo/a.proto", file2)]).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Duplicate input path 'foo/a.proto'"));
+
+        let broken = "syntax = \"proto3\"; message Broken { string name = ; }";
+        let err = merge_by_package(vec![("foo/a.proto", file1), ("foo/broken.proto", broken)])
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("Failed to parse foo/broken.proto"));
+    }
+
+    #[test]
+    fn test_inputs_resolve_each_other() {
+        let common = "syntax = \"proto3\"; package common; message Money { int64 units = 1; }";
+        let order = r#"
+syntax = "proto3";
+package shop;
+import "common/money.proto";
+message Order { common.Money total = 1; }
+"#;
+
+        let results = merge_by_package(vec![
+            ("common/money.proto", common),
+            ("shop/order.proto", order),
+        ])
+        .unwrap();
+        assert_eq!(results.len(), 2);
+        assert!(results[1].content.contains("common.Money total = 1;"));
+    }
+
+    #[test]
+    fn test_merge_descriptor_set_matches_text_merge() {
+        use protobuf::descriptor::FileDescriptorSet;
+        use protobuf::Message;
+
+        let files = vec![
+            (
+                "common/money.proto",
+                "syntax = \"proto3\"; package common; message Money { int64 units = 1; }",
+            ),
+            (
+                "shop/order.proto",
+                "syntax = \"proto3\"; package shop; import \"common/money.proto\"; message Order { common.Money total = 1; }",
+            ),
+            (
+                "shop/item.proto",
+                "syntax = \"proto3\"; package shop; import \"google/protobuf/timestamp.proto\"; message Item { google.protobuf.Timestamp at = 1; }",
+            ),
+        ];
+
+        let mut set = FileDescriptorSet::new();
+        set.file
+            .push(crate::parse_proto_to_file_descriptor(files[0]).unwrap());
+        for (path, content) in &files[1..] {
+            let session = ParseSession::new()
+                .with_files([files[0]])
+                .with_file_name(*path);
+            set.file.push(session.parse(content).unwrap());
+        }
+        let bytes = set.write_to_bytes().unwrap();
+
+        let from_set = merge_descriptor_set(&bytes).unwrap();
+        let from_text = merge_by_package(files).unwrap();
+        assert_eq!(from_set.len(), 2);
+        for (a, b) in from_set.iter().zip(&from_text) {
+            assert_eq!(a.package_name, b.package_name);
+            assert_eq!(a.content, b.content);
+            assert_eq!(a.fingerprint, b.fingerprint);
+        }
+
+        assert!(merge_descriptor_set(b"not a descriptor set").is_err());
+    }
+
+    #[test]
+    fn test_merge_descriptor_set_without_imports() {
+        use protobuf::descriptor::FileDescriptorSet;
+        use protobuf::Message;
+
+        // Like `protoc --descriptor_set_out` without `--include_imports`: the
+        // file declaring the custom option is not in the set
+        let options =
+            "syntax = \"proto3\"; package my; import \"google/protobuf/descriptor.proto\"; \
+                       extend google.protobuf.MessageOptions { string owner = 50000; }";
+        let session = ParseSession::new()
+            .with_files([("my/options.proto", options)])
+            .with_file_name("shop/order.proto");
+        let merge = |owner: &str| {
+            let file = session
+                .parse(&format!(
+                    "syntax = \"proto3\"; package shop; import \"my/options.proto\"; \
+                     message Order {{ option (my.owner) = \"{owner}\"; }}"
+                ))
+                .unwrap();
+            let mut set = FileDescriptorSet::new();
+            set.file.push(file);
+            let mut results = merge_descriptor_set(&set.write_to_bytes().unwrap()).unwrap();
+            assert_eq!(results.len(), 1);
+            results.remove(0)
+        };
+
+        // The option cannot be written back, but still changes the fingerprint
+        let alice = merge("alice");
+        let bob = merge("bob");
+        assert!(alice.content.contains("message Order {"));
+        assert_ne!(alice.fingerprint, bob.fingerprint);
+    }
+
+    #[test]
+    fn test_merge_editions() {
+        let file1 = r#"
+edition = "2023";
+package foo;
+option features.field_presence = IMPLICIT;
+message User { string name = 1 [features.field_presence = EXPLICIT]; }
+"#;
+        let file2 = r#"
+edition = "2023";
+package foo;
+option features.field_presence = IMPLICIT;
+message Profile { int32 age = 1; }
+"#;
+
+        let results = merge_by_package(vec![file1, file2]).unwrap();
+        assert_eq!(results.len(), 1);
+        let content = &results[0].content;
+        assert!(content.starts_with("edition = \"2023\";"));
+        assert!(content.contains("option features.field_presence = IMPLICIT;"));
+        assert!(content.contains("string name = 1 [features.field_presence = EXPLICIT];"));
+
+        let proto3 = "syntax = \"proto3\"; package foo; message Other {}";
+        let err = merge_by_package(vec![file1, proto3]).unwrap_err();
+        assert!(err.to_string().contains("Syntax version conflict"));
+
+        let explicit = "edition = \"2023\"; package foo; message Other {}";
+        let err = merge_by_package(vec![file1, explicit]).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("File-level features conflict between file #0"));
+    }
+
+    #[test]
+    fn test_merge_with_resolved_imports() {
+        let session = ParseSession::new().with_files([(
+            "common/types.proto",
+            "syntax = \"proto3\"; package common; message Money { int64 units = 1; }",
+        )]);
+
+        let file1 = r#"
+syntax = "proto3";
+package shop;
+
+import "common/types.proto";
+
+message Order {
+  common.Money total = 1;
+}
+"#;
+
+        let results = merge_by_package_with_session(vec![file1], &session).unwrap();
+        assert_eq!(results.len(), 1);
+        assert!(results[0]
+            .content
+            .contains("import \"common/types.proto\";"));
+        assert!(results[0].content.contains("common.Money total = 1;"));
+        assert_eq!(
+            results[0].fingerprint,
+            crate::generate_fingerprint_with_session(&results[0].content, &session).unwrap()
+        );
+
+        // Lenient mode stubs the import, so the type cannot be resolved
+        assert!(merge_by_package(vec![file1]).is_err());
+    }
+
     #[test]
     fn test_merge_mixed_content() {
         let file1 = r#"
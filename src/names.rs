@@ -0,0 +1,201 @@
+//! Relative type names.
+//!
+//! `protoc` resolves a relative name such as `Foo.Bar` by looking its first
+//! component up in the enclosing scopes, innermost first, and then the rest
+//! of the name inside the first match (see `DescriptorBuilder::LookupSymbol`
+//! in `descriptor.cc`). A nested type or a field can therefore shadow a type
+//! of an outer scope. [`SymbolTable`] replays that lookup to find the
+//! shortest name that still refers to a given type.
+
+use protobuf::descriptor::{
+    DescriptorProto, EnumDescriptorProto, FieldDescriptorProto, FileDescriptorProto,
+};
+use std::collections::HashMap;
+
+/// Kind of a named element, as far as name lookup is concerned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Symbol {
+    Package,
+    Message,
+    Enum,
+    Service,
+    /// Fields, oneofs, enum values and methods
+    Member,
+}
+
+impl Symbol {
+    /// Whether the symbol can contain other symbols.
+    fn is_aggregate(self) -> bool {
+        !matches!(self, Symbol::Member)
+    }
+
+    fn is_type(self) -> bool {
+        matches!(self, Symbol::Message | Symbol::Enum)
+    }
+}
+
+/// Which symbols a single-component name may resolve to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Lookup {
+    /// Only types: other symbols are skipped. Used for field types.
+    Types,
+    /// Any symbol: the innermost match wins even when it is not a type. Used
+    /// for method input and output types and for extendees.
+    All,
+}
+
+/// Every named element of a set of files, by full name.
+///
+/// Full names carry a leading dot, like the `type_name` and `extendee` of
+/// parsed descriptors.
+#[derive(Debug, Default)]
+pub(crate) struct SymbolTable {
+    symbols: HashMap<String, Symbol>,
+}
+
+impl SymbolTable {
+    pub(crate) fn new<'a>(files: impl IntoIterator<Item = &'a FileDescriptorProto>) -> Self {
+        let mut table = Self::default();
+        for file in files {
+            let mut scope = String::new();
+            for part in file.package().split('.').filter(|part| !part.is_empty()) {
+                scope = format!("{scope}.{part}");
+                table.insert(scope.clone(), Symbol::Package);
+            }
+            table.add_fields(&scope, &file.extension);
+            table.add_enums(&scope, &file.enum_type);
+            table.add_messages(&scope, &file.message_type);
+            for service in &file.service {
+                let name = format!("{scope}.{}", service.name());
+                for method in &service.method {
+                    table.insert(format!("{name}.{}", method.name()), Symbol::Member);
+                }
+                table.insert(name, Symbol::Service);
+            }
+        }
+        table
+    }
+
+    fn add_messages(&mut self, scope: &str, messages: &[DescriptorProto]) {
+        for message in messages {
+            let name = format!("{scope}.{}", message.name());
+            self.add_fields(&name, &message.field);
+            self.add_fields(&name, &message.extension);
+            for oneof in &message.oneof_decl {
+                self.insert(format!("{name}.{}", oneof.name()), Symbol::Member);
+            }
+            self.add_enums(&name, &message.enum_type);
+            self.add_messages(&name, &message.nested_type);
+            self.insert(name, Symbol::Message);
+        }
+    }
+
+    fn add_enums(&mut self, scope: &str, enums: &[EnumDescriptorProto]) {
+        for enum_type in enums {
+            self.insert(format!("{scope}.{}", enum_type.name()), Symbol::Enum);
+            // Enum values are siblings of their enum, not children
+            for value in &enum_type.value {
+                self.insert(format!("{scope}.{}", value.name()), Symbol::Member);
+            }
+        }
+    }
+
+    fn add_fields(&mut self, scope: &str, fields: &[FieldDescriptorProto]) {
+        for field in fields {
+            self.insert(format!("{scope}.{}", field.name()), Symbol::Member);
+        }
+    }
+
+    fn insert(&mut self, name: String, symbol: Symbol) {
+        self.symbols.entry(name).or_insert(symbol);
+    }
+
+    /// Shortest name that refers to the type `full_name` from `scope`, the
+    /// full name of the message, service or package the reference appears
+    /// in (empty for the root).
+    ///
+    /// Types missing from the table keep their full name, without the
+    /// leading dot. If every relative name is shadowed, the fully qualified
+    /// name is returned with its leading dot.
+    pub(crate) fn shortest_name(&self, full_name: &str, scope: &str, lookup: Lookup) -> String {
+        let Some(name) = full_name.strip_prefix('.') else {
+            return full_name.to_string();
+        };
+        if !self.symbols.contains_key(full_name) {
+            return name.to_string();
+        }
+        let parts: Vec<&str> = name.split('.').collect();
+        (0..parts.len())
+            .rev()
+            .map(|start| parts[start..].join("."))
+            .find(|candidate| self.resolve(candidate, scope, lookup).as_deref() == Some(full_name))
+            .unwrap_or_else(|| full_name.to_string())
+    }
+
+    /// Full name the relative `name` resolves to from `scope`, if any.
+    fn resolve(&self, name: &str, scope: &str, lookup: Lookup) -> Option<String> {
+        let first = name.split('.').next().unwrap_or(name);
+        let mut scope = scope;
+        loop {
+            let candidate = format!("{scope}.{first}");
+            if let Some(symbol) = self.symbols.get(&candidate) {
+                if first.len() < name.len() {
+                    // The rest of the name must be found inside the first
+                    // aggregate match; outer scopes are not searched again
+                    if symbol.is_aggregate() {
+                        let full_name = format!("{scope}.{name}");
+                        return self.symbols.contains_key(&full_name).then_some(full_name);
+                    }
+                } else if lookup == Lookup::All || symbol.is_type() {
+                    return Some(candidate);
+                }
+            }
+            if scope.is_empty() {
+                return None;
+            }
+            scope = &scope[..scope.rfind('.').unwrap_or(0)];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_proto_to_file_descriptor;
+
+    #[test]
+    fn test_shortest_name_respects_shadowing() {
+        let file = parse_proto_to_file_descriptor((
+            "a/b.proto",
+            r#"
+syntax = "proto3";
+package a.b;
+message Foo {}
+message Bar {
+  message Foo {}
+  Foo inner = 1;
+  Foo Baz = 2;
+}
+message Baz {}
+service Svc {
+  rpc Foo(Foo) returns (Bar);
+}
+"#,
+        ))
+        .unwrap();
+        let table = SymbolTable::new([&file]);
+        let shortest = |name, scope, lookup| table.shortest_name(name, scope, lookup);
+
+        assert_eq!(shortest(".a.b.Bar.Foo", ".a.b.Bar", Lookup::Types), "Foo");
+        // The nested Foo shadows the outer one
+        assert_eq!(shortest(".a.b.Foo", ".a.b.Bar", Lookup::Types), "b.Foo");
+        // Fields do not shadow types in field types
+        assert_eq!(shortest(".a.b.Baz", ".a.b.Bar", Lookup::Types), "Baz");
+        assert_eq!(shortest(".a.b.Foo", ".a.b", Lookup::Types), "Foo");
+        // ...but methods do in method signatures
+        assert_eq!(shortest(".a.b.Foo", ".a.b.Svc", Lookup::All), "b.Foo");
+        assert_eq!(shortest(".a.b.Bar", ".a.b.Svc", Lookup::All), "Bar");
+        // Unknown types keep their full name
+        assert_eq!(shortest(".x.Y", ".a.b", Lookup::Types), "x.Y");
+    }
+}
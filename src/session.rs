@@ -0,0 +1,519 @@
+//! Shared parser configuration.
+//!
+//! Every code path that turns proto text into descriptors (single-file
+//! parsing, fingerprinting and package merging) goes through a
+//! [`ParseSession`], so the same input is always parsed the same way.
+
+use crate::diff::{diff_file_descriptors, DescriptorDifference};
+use crate::vfs;
+use anyhow::{Context, Result};
+use protobuf::descriptor::FileDescriptorProto;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// What to do with imports that cannot be resolved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StubPolicy {
+    /// Fail parsing when an import cannot be found.
+    #[default]
+    Strict,
+    /// Replace unresolved imports with empty `proto3` stubs.
+    /// Types from stubbed files cannot be referenced.
+    Lenient,
+}
+
+/// Parser implementation used to build descriptors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Backend {
+    /// The pure Rust parser from `protobuf-parse`.
+    #[default]
+    Pure,
+    /// The `protoc` binary found on `PATH` (or named by `$PROTOC`).
+    Protoc,
+    /// Parse with both the pure parser and `protoc`, failing if their
+    /// descriptors differ (ignoring `source_code_info`). Descriptors from
+    /// the pure parser are returned.
+    CrossCheck,
+}
+
+/// A proto file to parse: its content and, optionally, its logical path.
+///
+/// The path (e.g. `"shop/order.proto"`) becomes the descriptor `name` and is
+/// used in error messages. Plain strings convert into a `SourceFile` without
+/// a path, `(path, content)` pairs into one with a path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceFile {
+    /// Relative proto path, if known.
+    pub path: Option<String>,
+    /// File content.
+    pub content: String,
+}
+
+impl SourceFile {
+    pub fn new(path: impl Into<String>, content: impl Into<String>) -> Self {
+        Self {
+            path: Some(path.into()),
+            content: content.into(),
+        }
+    }
+}
+
+impl From<&str> for SourceFile {
+    fn from(content: &str) -> Self {
+        Self {
+            path: None,
+            content: content.to_string(),
+        }
+    }
+}
+
+impl From<String> for SourceFile {
+    fn from(content: String) -> Self {
+        Self {
+            path: None,
+            content,
+        }
+    }
+}
+
+impl From<&String> for SourceFile {
+    fn from(content: &String) -> Self {
+        content.as_str().into()
+    }
+}
+
+impl From<(&str, &str)> for SourceFile {
+    fn from((path, content): (&str, &str)) -> Self {
+        Self::new(path, content)
+    }
+}
+
+impl From<(String, String)> for SourceFile {
+    fn from((path, content): (String, String)) -> Self {
+        Self::new(path, content)
+    }
+}
+
+impl From<(&String, &String)> for SourceFile {
+    fn from((path, content): (&String, &String)) -> Self {
+        Self::new(path.as_str(), content.as_str())
+    }
+}
+
+/// Parser configuration shared by parsing, fingerprinting and merging.
+///
+/// Imports are looked up in `files` first, then in each of `include_roots`
+/// in order. `google/protobuf/*` imports are always served by the parser's
+/// bundled well-known types.
+///
+/// Parsing is not done in memory: every parse writes the input, the
+/// in-memory `files` it needs and any stubs to a temporary directory (see
+/// [`std::env::temp_dir`]) and runs the parser on it, so `TMPDIR` must be
+/// writable. `protobuf-parse` 3.7 offers no public way to feed the pure
+/// parser from memory.
+#[derive(Debug, Clone)]
+pub struct ParseSession {
+    /// Directories searched, in order, for imported files.
+    pub include_roots: Vec<PathBuf>,
+    /// In-memory import contents keyed by import path (e.g. `"foo/bar.proto"`).
+    pub files: BTreeMap<String, String>,
+    /// Handling of imports that cannot be resolved.
+    pub stub_policy: StubPolicy,
+    /// Logical name of the parsed file, used as the descriptor `name`.
+    pub file_name: String,
+    /// Parser implementation.
+    pub backend: Backend,
+}
+
+impl Default for ParseSession {
+    fn default() -> Self {
+        Self {
+            include_roots: Vec::new(),
+            files: BTreeMap::new(),
+            stub_policy: StubPolicy::Strict,
+            file_name: "input.proto".to_string(),
+            backend: Backend::Pure,
+        }
+    }
+}
+
+impl ParseSession {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Session that stubs every import it cannot resolve.
+    pub fn lenient() -> Self {
+        Self::new().with_stub_policy(StubPolicy::Lenient)
+    }
+
+    /// Add include roots, searched in order after the in-memory files.
+    pub fn with_include_roots<P: AsRef<Path>>(
+        mut self,
+        roots: impl IntoIterator<Item = P>,
+    ) -> Self {
+        self.include_roots
+            .extend(roots.into_iter().map(|p| p.as_ref().to_path_buf()));
+        self
+    }
+
+    /// Add in-memory imports keyed by import path. They are staged in a
+    /// temporary directory with the input when parsing.
+    pub fn with_files<K: Into<String>, V: Into<String>>(
+        mut self,
+        files: impl IntoIterator<Item = (K, V)>,
+    ) -> Self {
+        self.files
+            .extend(files.into_iter().map(|(k, v)| (k.into(), v.into())));
+        self
+    }
+
+    pub fn with_stub_policy(mut self, stub_policy: StubPolicy) -> Self {
+        self.stub_policy = stub_policy;
+        self
+    }
+
+    pub fn with_file_name(mut self, file_name: impl Into<String>) -> Self {
+        self.file_name = file_name.into();
+        self
+    }
+
+    pub fn with_backend(mut self, backend: Backend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    /// Parse `content` as the session's logical file.
+    ///
+    /// The sources are staged in a temporary directory for the parser, which
+    /// fails if it cannot be written (see [`ParseSession`]).
+    pub fn parse(&self, content: &str) -> Result<FileDescriptorProto> {
+        Ok(self.parse_with_imports(content)?.0)
+    }
+
+    /// Parse `content` as the session's logical file, also returning the
+    /// descriptors of every file it imports, directly or indirectly.
+    ///
+    /// Pass the imports to [`TextGenerator::with_imports`] (or
+    /// [`descriptor_to_proto_with_imports`]) to render custom options
+    /// declared in those files.
+    ///
+    /// Like [`ParseSession::parse`], this writes the sources to a temporary
+    /// directory.
+    ///
+    /// [`TextGenerator::with_imports`]: crate::TextGenerator::with_imports
+    /// [`descriptor_to_proto_with_imports`]: crate::descriptor_to_proto_with_imports
+    pub fn parse_with_imports(
+        &self,
+        content: &str,
+    ) -> Result<(FileDescriptorProto, Vec<FileDescriptorProto>)> {
+        let mut parsed = self.parse_files(&[(&self.file_name, content)])?;
+        let descriptor = parsed
+            .inputs
+            .pop()
+            .context("Could not find the parsed file descriptor")??;
+        Ok((descriptor, parsed.imports))
+    }
+
+    /// Parse `content` with both the pure parser and `protoc` and return
+    /// every difference between the two descriptors.
+    ///
+    /// An empty result means both backends agree. `source_code_info` is
+    /// ignored. Fails if either backend rejects the input. The sources are
+    /// staged in a temporary directory, as for [`ParseSession::parse`].
+    pub fn cross_check(&self, content: &str) -> Result<Vec<DescriptorDifference>> {
+        let pure = self.clone().with_backend(Backend::Pure).parse(content)?;
+        let protoc = self.clone().with_backend(Backend::Protoc).parse(content)?;
+        Ok(diff_file_descriptors(&pure, &protoc))
+    }
+
+    /// Parse several `(path, content)` inputs with this configuration.
+    ///
+    /// Returns one result per input, in input order, so that callers can
+    /// attribute failures to individual files, and the imports of all inputs.
+    pub(crate) fn parse_files(&self, inputs: &[(&str, &str)]) -> Result<vfs::ParsedFiles> {
+        vfs::parse_files(inputs, self)
+    }
+
+    /// Read an import from the include roots, if present in any of them.
+    pub(crate) fn read_from_roots(&self, import_path: &str) -> Result<Option<String>> {
+        vfs::validate_import_path(import_path)?;
+        for root in &self.include_roots {
+            let candidate = root.join(import_path);
+            if candidate.is_file() {
+                let content = std::fs::read_to_string(&candidate)
+                    .with_context(|| format!("Failed to read import: {}", candidate.display()))?;
+                return Ok(Some(content));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IMPORTER: &str = r#"
+syntax = "proto3";
+package app;
+
+import "shared/status.proto";
+
+message Job {
+  shared.Status status = 1;
+}
+"#;
+
+    const IMPORTED: &str = r#"
+syntax = "proto3";
+package shared;
+
+enum Status {
+  STATUS_UNKNOWN = 0;
+  STATUS_DONE = 1;
+}
+"#;
+
+    #[test]
+    fn test_lenient_stubs_unresolved_imports() {
+        let proto = r#"
+syntax = "proto3";
+import "missing/dep.proto";
+message Local { string name = 1; }
+"#;
+        let descriptor = ParseSession::lenient().parse(proto).unwrap();
+        assert_eq!(descriptor.dependency, vec!["missing/dep.proto"]);
+
+        let strict = ParseSession::new().parse(proto);
+        assert!(strict.is_err());
+    }
+
+    #[test]
+    fn test_resolve_imports_from_files() {
+        let session = ParseSession::new().with_files([("shared/status.proto", IMPORTED)]);
+        let descriptor = session.parse(IMPORTER).unwrap();
+
+        let field = &descriptor.message_type[0].field[0];
+        assert_eq!(field.type_name(), ".shared.Status");
+    }
+
+    #[test]
+    fn test_resolve_imports_from_include_roots() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(root.path().join("shared")).unwrap();
+        std::fs::write(root.path().join("shared/status.proto"), IMPORTED).unwrap();
+
+        let session = ParseSession::new().with_include_roots([root.path()]);
+        let descriptor = session.parse(IMPORTER).unwrap();
+
+        let field = &descriptor.message_type[0].field[0];
+        assert_eq!(field.type_name(), ".shared.Status");
+    }
+
+    #[test]
+    fn test_editions_imports_from_include_roots() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(root.path().join("shared")).unwrap();
+        std::fs::write(
+            root.path().join("shared/status.proto"),
+            IMPORTED.replace("syntax = \"proto3\";", "edition = \"2023\";"),
+        )
+        .unwrap();
+
+        let session = ParseSession::new().with_include_roots([root.path()]);
+        let descriptor = session.parse(IMPORTER).unwrap();
+        assert_eq!(
+            descriptor.message_type[0].field[0].type_name(),
+            ".shared.Status"
+        );
+    }
+
+    #[cfg(feature = "googleapis")]
+    #[test]
+    fn test_resolve_bundled_googleapis_imports() {
+        let proto = r#"
+syntax = "proto3";
+package app;
+
+import "google/api/annotations.proto";
+import "google/api/field_behavior.proto";
+import "google/rpc/status.proto";
+import "google/type/date.proto";
+
+message Job {
+  google.type.Date due = 1 [(google.api.field_behavior) = REQUIRED];
+  google.rpc.Status status = 2;
+}
+
+service Jobs {
+  rpc Get(Job) returns (Job) {
+    option (google.api.http) = { get: "/v1/jobs" };
+  }
+}
+"#;
+        let (descriptor, imports) = ParseSession::new().parse_with_imports(proto).unwrap();
+        let fields = &descriptor.message_type[0].field;
+        assert_eq!(fields[0].type_name(), ".google.type.Date");
+        assert_eq!(fields[1].type_name(), ".google.rpc.Status");
+
+        let text = crate::descriptor_to_proto_with_imports(&descriptor, &imports).unwrap();
+        assert!(text.contains("[(google.api.field_behavior) = REQUIRED]"));
+        assert!(text.contains("option (google.api.http) = { get: \"/v1/jobs\" };"));
+    }
+
+    #[cfg(feature = "googleapis")]
+    #[test]
+    fn test_include_roots_override_bundled_googleapis() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(root.path().join("google/type")).unwrap();
+        std::fs::write(
+            root.path().join("google/type/date.proto"),
+            "syntax = \"proto3\";\npackage google.type;\nmessage Day { int32 value = 1; }\n",
+        )
+        .unwrap();
+
+        let proto = "syntax = \"proto3\";\nimport \"google/type/date.proto\";\nmessage Job { google.type.Day day = 1; }\n";
+        let session = ParseSession::new().with_include_roots([root.path()]);
+        let descriptor = session.parse(proto).unwrap();
+        assert_eq!(
+            descriptor.message_type[0].field[0].type_name(),
+            ".google.type.Day"
+        );
+    }
+
+    #[test]
+    fn test_lenient_prefers_real_imports() {
+        let session = ParseSession::lenient().with_files([("shared/status.proto", IMPORTED)]);
+
+        let proto = IMPORTER.replace(
+            "import \"shared/status.proto\";",
+            "import \"shared/status.proto\";\nimport \"other/missing.proto\";",
+        );
+        let descriptor = session.parse(&proto).unwrap();
+        assert_eq!(
+            descriptor.message_type[0].field[0].type_name(),
+            ".shared.Status"
+        );
+    }
+
+    #[test]
+    fn test_logical_file_name() {
+        let session = ParseSession::new().with_file_name("app/job.proto");
+        let descriptor = session
+            .parse("syntax = \"proto3\"; message Job {}")
+            .unwrap();
+        assert_eq!(descriptor.name(), "app/job.proto");
+    }
+
+    fn protoc_available() -> bool {
+        vfs::find_protoc().is_some()
+    }
+
+    #[test]
+    fn test_protoc_backend_reports_missing_binary() {
+        if protoc_available() {
+            return;
+        }
+        let err = ParseSession::new()
+            .with_backend(Backend::Protoc)
+            .parse("syntax = \"proto3\"; message A {}")
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("protoc"));
+    }
+
+    #[test]
+    fn test_cross_check_backends_agree() {
+        if !protoc_available() {
+            eprintln!("protoc not found, skipping");
+            return;
+        }
+        let session = ParseSession::new().with_files([("shared/status.proto", IMPORTED)]);
+        assert_eq!(session.cross_check(IMPORTER).unwrap(), vec![]);
+
+        let descriptor = session
+            .with_backend(Backend::CrossCheck)
+            .parse(IMPORTER)
+            .unwrap();
+        assert_eq!(
+            descriptor.message_type[0].field[0].type_name(),
+            ".shared.Status"
+        );
+    }
+
+    #[test]
+    fn test_cross_check_against_protoc_fixture() {
+        use protobuf::descriptor::FileDescriptorSet;
+
+        const JOB: &str = r#"
+syntax = "proto3";
+package app;
+
+import "shared/status.proto";
+
+message Job {
+  shared.Status status = 1;
+  map<string, int32> labels = 2;
+  optional string note = 3;
+}
+"#;
+        // Output of protoc for the same sources, so that the comparison is
+        // covered without the binary
+        let set: FileDescriptorSet =
+            protobuf::text_format::parse_from_str(include_str!("../tests/data/protoc/job.txtpb"))
+                .unwrap();
+        let mut protoc: BTreeMap<String, FileDescriptorProto> = set
+            .file
+            .into_iter()
+            .map(|file| (file.name().to_string(), file))
+            .collect();
+
+        let (descriptor, imports) = ParseSession::new()
+            .with_files([("shared/status.proto", IMPORTED)])
+            .with_file_name("app/job.proto")
+            .parse_with_imports(JOB)
+            .unwrap();
+        let pure: BTreeMap<String, FileDescriptorProto> = imports
+            .into_iter()
+            .chain([descriptor])
+            .map(|file| (file.name().to_string(), file))
+            .collect();
+
+        let inputs = ["shared/status.proto", "app/job.proto"];
+        vfs::check_backends_agree(&inputs, &pure, &protoc).unwrap();
+
+        // A descriptor that differs from the pure parser's fails the check
+        let job = protoc.get_mut("app/job.proto").unwrap();
+        job.message_type[0].field[2].clear_proto3_optional();
+        let err = vfs::check_backends_agree(&inputs, &pure, &protoc).unwrap_err();
+        let message = err.to_string();
+        assert!(message.starts_with("Pure parser and protoc disagree on app/job.proto"));
+        assert!(message.contains("message_type[Job].field[note].proto3_optional"));
+    }
+
+    #[test]
+    fn test_source_file_conversions() {
+        assert_eq!(SourceFile::from("a").path, None);
+        let source = SourceFile::from(("x/a.proto", "a"));
+        assert_eq!(source, SourceFile::new("x/a.proto", "a"));
+
+        let descriptor =
+            crate::parse_proto_to_file_descriptor(("x/a.proto", "syntax = \"proto3\";")).unwrap();
+        assert_eq!(descriptor.name(), "x/a.proto");
+    }
+
+    #[test]
+    fn test_session_paths_agree() {
+        // Single-file parsing and merging go through the same session
+        let session = ParseSession::new().with_files([("shared/status.proto", IMPORTED)]);
+        let direct = session.parse(IMPORTER).unwrap();
+        let batched = session
+            .parse_files(&[("input.proto", IMPORTER)])
+            .unwrap()
+            .inputs
+            .pop()
+            .unwrap()
+            .unwrap();
+        assert_eq!(direct, batched);
+    }
+}
@@ -3,17 +3,66 @@
 //! This module ports Google's C++ DebugStringWithOptions implementation to Rust,
 //! ensuring stable, deterministic output for proto descriptors.
 
-use anyhow::Result;
+use crate::builtin_options;
+use crate::comments::{
+    ENUM_VALUE, FILE_DEPENDENCY, FILE_ENUM_TYPE, FILE_EXTENSION, FILE_MESSAGE_TYPE, FILE_PACKAGE,
+    FILE_SERVICE, FILE_SYNTAX, MESSAGE_ENUM_TYPE, MESSAGE_EXTENSION, MESSAGE_FIELD,
+    MESSAGE_NESTED_TYPE, MESSAGE_ONEOF_DECL, SERVICE_METHOD,
+};
+use crate::custom_options::{ExtensionIndex, Unresolved};
+use crate::diff::{diff_file_descriptors, DescriptorDifference};
+use crate::editions;
+use crate::names::{Lookup, SymbolTable};
+use crate::session::ParseSession;
+use anyhow::{Context, Result};
 use protobuf::descriptor::{
     field_descriptor_proto::{Label, Type},
+    source_code_info::Location,
     DescriptorProto, EnumDescriptorProto, EnumValueDescriptorProto, FieldDescriptorProto,
     FileDescriptorProto, MethodDescriptorProto, OneofDescriptorProto, ServiceDescriptorProto,
 };
+use std::collections::BTreeMap;
 use std::fmt::Write;
 
 /// Version of the text generation algorithm.
 /// Increment when output format changes to ensure reproducibility.
-pub const TEXT_GENERATOR_VERSION: &str = "1.0.0";
+pub const TEXT_GENERATOR_VERSION: &str = "1.10.0";
+
+/// A top-level definition of a file.
+enum Definition<'a> {
+    Message(&'a DescriptorProto),
+    Enum(&'a EnumDescriptorProto),
+    Service(&'a ServiceDescriptorProto),
+    Extension(&'a FieldDescriptorProto),
+}
+
+/// An element declared in a message body, with its index in the message.
+enum Member<'a> {
+    Enum(usize, &'a EnumDescriptorProto),
+    Message(usize, &'a DescriptorProto),
+    Field(usize, &'a FieldDescriptorProto),
+    /// A oneof with its fields and their index in the message
+    Oneof(
+        usize,
+        &'a OneofDescriptorProto,
+        Vec<(usize, &'a FieldDescriptorProto)>,
+    ),
+    Extension(usize, &'a FieldDescriptorProto),
+}
+
+impl Member<'_> {
+    /// Location path of the member in the message at `message_path`.
+    fn path(&self, message_path: &[i32]) -> Vec<i32> {
+        let (kind, index) = match self {
+            Member::Enum(index, _) => (MESSAGE_ENUM_TYPE, index),
+            Member::Message(index, _) => (MESSAGE_NESTED_TYPE, index),
+            Member::Field(index, _) => (MESSAGE_FIELD, index),
+            Member::Oneof(index, _, _) => (MESSAGE_ONEOF_DECL, index),
+            Member::Extension(index, _) => (MESSAGE_EXTENSION, index),
+        };
+        child_path(message_path, kind, *index)
+    }
+}
 
 /// Information about a map field
 struct MapFieldInfo {
@@ -26,12 +75,160 @@ struct MapFieldInfo {
 pub struct TextGeneratorOptions {
     /// Indent size in spaces (default: 2)
     pub indent_size: usize,
-    /// Sort messages by name (default: true for determinism)
+    /// Sort messages by name, at every nesting level (default: true for
+    /// determinism)
     pub sort_messages: bool,
-    /// Sort enums by name (default: true for determinism)
+    /// Sort enums by name, at every nesting level (default: true for
+    /// determinism)
     pub sort_enums: bool,
     /// Sort services by name (default: true for determinism)
     pub sort_services: bool,
+    /// Keep the declaration order of the source instead of sorting
+    /// (default: false)
+    ///
+    /// Definitions, fields, enum values, methods and imports are written in
+    /// the order they were declared, and definitions of different kinds are
+    /// interleaved as in the source when `source_code_info` is present. The
+    /// `sort_*` flags are ignored. Options are still sorted, and extension
+    /// ranges and reserved statements still close their message.
+    pub preserve_order: bool,
+    /// Emit the comments recorded in `source_code_info` (default: false)
+    ///
+    /// Leading and detached comments are written as `//` lines above their
+    /// element, trailing comments at the end of its first line.
+    pub include_comments: bool,
+    /// Write type references with the shortest name that resolves to the
+    /// same type under protobuf scoping rules (default: false)
+    ///
+    /// By default every field type, extendee and method type is fully
+    /// qualified. Types declared in other files are only shortened when
+    /// those files are passed to [`TextGenerator::with_imports`].
+    pub relative_type_names: bool,
+    /// Wrap declarations whose line would be longer than this many
+    /// characters (default: None, never wrap)
+    ///
+    /// Option brackets are split into one option per line, `reserved`
+    /// lists are filled up to the width with continuation lines indented
+    /// one level, and the `returns` clause of an RPC moves to its own line.
+    /// Only the lines that would be too long are wrapped.
+    pub max_line_width: Option<usize>,
+    /// Layout of definitions: blank lines, the order of top-level
+    /// definition kinds and the placement of options (default:
+    /// [`Style::canonical`])
+    ///
+    /// Merging and fingerprinting always use the canonical style.
+    pub style: Style,
+    /// Fail when a custom option's extension cannot be found (default:
+    /// false)
+    ///
+    /// Custom options are decoded with the extensions declared by the file
+    /// and the imports passed to [`TextGenerator::with_imports`]. By default
+    /// an option whose extension is missing is left out of the text with a
+    /// warning.
+    pub strict_custom_options: bool,
+}
+
+/// Layout preset for [`TextGeneratorOptions::style`].
+///
+/// The presets only move definitions, options and blank lines around; the
+/// declarations themselves are written the same way in every style.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Style {
+    /// Where blank lines go
+    pub blank_lines: BlankLines,
+    /// Order of the top-level definition kinds. Kinds missing from the list
+    /// are written after the others, in the canonical order. Ignored with
+    /// [`TextGeneratorOptions::preserve_order`].
+    pub definition_order: [DefinitionKind; 4],
+    /// Where the options of messages, enums and services go in their body
+    pub option_placement: OptionPlacement,
+}
+
+impl Style {
+    /// The layout of merged files and fingerprints, which never changes
+    /// within a [`TEXT_GENERATOR_VERSION`]: messages, enums, services and
+    /// extensions, a blank line after each top-level definition except
+    /// between `extend` blocks, and options first.
+    pub const fn canonical() -> Self {
+        Self {
+            blank_lines: BlankLines::TopLevel,
+            definition_order: [
+                DefinitionKind::Messages,
+                DefinitionKind::Enums,
+                DefinitionKind::Services,
+                DefinitionKind::Extensions,
+            ],
+            option_placement: OptionPlacement::First,
+        }
+    }
+
+    /// Enums before messages, as `protoc` prints descriptors, with blank
+    /// lines around nested definitions and after option blocks.
+    pub const fn google() -> Self {
+        Self {
+            blank_lines: BlankLines::Blocks,
+            definition_order: [
+                DefinitionKind::Enums,
+                DefinitionKind::Messages,
+                DefinitionKind::Services,
+                DefinitionKind::Extensions,
+            ],
+            option_placement: OptionPlacement::First,
+        }
+    }
+
+    /// Services first, followed by the messages and enums they use, with
+    /// blank lines around nested definitions and options at the end of
+    /// each body.
+    pub const fn buf() -> Self {
+        Self {
+            blank_lines: BlankLines::Blocks,
+            definition_order: [
+                DefinitionKind::Services,
+                DefinitionKind::Messages,
+                DefinitionKind::Enums,
+                DefinitionKind::Extensions,
+            ],
+            option_placement: OptionPlacement::Last,
+        }
+    }
+}
+
+impl Default for Style {
+    fn default() -> Self {
+        Self::canonical()
+    }
+}
+
+/// Blank-line policy of a [`Style`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlankLines {
+    /// A blank line after each top-level definition; consecutive
+    /// top-level `extend` blocks are not separated. Bodies have no blank
+    /// lines.
+    TopLevel,
+    /// As `TopLevel`, and also between top-level `extend` blocks, around
+    /// nested messages, enums and `extend` blocks, and between the options
+    /// of a body and its other declarations.
+    Blocks,
+}
+
+/// Kind of top-level definition, for [`Style::definition_order`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DefinitionKind {
+    Messages,
+    Enums,
+    Services,
+    Extensions,
+}
+
+/// Placement of `option` statements in message, enum and service bodies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OptionPlacement {
+    /// Before every other declaration of the body
+    First,
+    /// After every other declaration of the body
+    Last,
 }
 
 impl Default for TextGeneratorOptions {
@@ -41,6 +238,12 @@ impl Default for TextGeneratorOptions {
             sort_messages: true,
             sort_enums: true,
             sort_services: true,
+            preserve_order: false,
+            include_comments: false,
+            relative_type_names: false,
+            max_line_width: None,
+            style: Style::canonical(),
+            strict_custom_options: false,
         }
     }
 }
@@ -52,6 +255,19 @@ pub struct TextGenerator {
     indent_level: usize,
     current_message: Option<DescriptorProto>,
     current_file: Option<FileDescriptorProto>,
+    /// Files that may declare the custom options used by formatted files
+    imports: Vec<FileDescriptorProto>,
+    extensions: ExtensionIndex,
+    /// Render custom options with unknown extensions by field number, for
+    /// fingerprints, instead of following `strict_custom_options`
+    raw_unresolved_options: bool,
+    /// Locations of the current file by path, when comments or the source
+    /// order are needed
+    locations: BTreeMap<Vec<i32>, Location>,
+    /// Types of the current file and its imports, for relative type names
+    symbols: SymbolTable,
+    /// Full name of the package, message or service being written
+    scope: String,
 }
 
 impl TextGenerator {
@@ -62,6 +278,12 @@ impl TextGenerator {
             indent_level: 0,
             current_message: None,
             current_file: None,
+            imports: Vec::new(),
+            extensions: ExtensionIndex::default(),
+            raw_unresolved_options: false,
+            locations: BTreeMap::new(),
+            symbols: SymbolTable::default(),
+            scope: String::new(),
         }
     }
 
@@ -69,22 +291,216 @@ impl TextGenerator {
         Self::new(TextGeneratorOptions::default())
     }
 
+    /// Add the descriptors of imported files.
+    ///
+    /// Custom options are stored as unknown fields, so rendering them needs
+    /// the declaration of their extension. Extensions declared in the
+    /// formatted file itself are always found; the ones declared elsewhere
+    /// must be in one of these files (see [`crate::ParseSession::parse_with_imports`]).
+    pub fn with_imports(mut self, imports: impl IntoIterator<Item = FileDescriptorProto>) -> Self {
+        self.imports.extend(imports);
+        self
+    }
+
+    /// Write custom options whose extension is not found by field number,
+    /// with their raw values, so that they still change the text. The
+    /// result does not parse and is only meant to be hashed.
+    pub(crate) fn with_raw_unresolved_options(mut self) -> Self {
+        self.raw_unresolved_options = true;
+        self
+    }
+
     /// Main entry point: format a FileDescriptorProto to canonical proto text.
     pub fn format_file(&mut self, file: &FileDescriptorProto) -> Result<String> {
+        let mut text = String::new();
+        self.format_file_to(file, &mut text)?;
+        Ok(text)
+    }
+
+    /// Format `file` into `out`.
+    ///
+    /// The text is handed to `out` one top-level definition at a time, so
+    /// only the definition being generated is buffered, however large the
+    /// file.
+    pub fn format_file_to(
+        &mut self,
+        file: &FileDescriptorProto,
+        out: &mut impl std::fmt::Write,
+    ) -> Result<()> {
+        self.write_file(file, out)
+    }
+
+    /// Format `file` into the byte sink `out`, such as a file or a socket.
+    ///
+    /// See [`Self::format_file_to`]. I/O errors of `out` are returned as is.
+    pub fn format_file_to_io(
+        &mut self,
+        file: &FileDescriptorProto,
+        out: &mut impl std::io::Write,
+    ) -> Result<()> {
+        let mut sink = IoSink {
+            inner: out,
+            error: None,
+        };
+        let result = self.write_file(file, &mut sink);
+        match sink.error {
+            Some(error) => Err(error).context("Failed to write generated text"),
+            None => result,
+        }
+    }
+
+    /// Format the message `name` of `file`.
+    ///
+    /// `name` is the full name of the message, with or without the leading
+    /// dot. Type names are resolved in the context of `file`, and the
+    /// message is written at the top level even when it is nested.
+    pub fn format_message(&mut self, file: &FileDescriptorProto, name: &str) -> Result<String> {
+        let full_name = qualified_name(name);
+        let (scope, index) = definition_scopes(file)
+            .into_iter()
+            .find_map(|scope| {
+                let messages = scope.message.map_or(&file.message_type, |m| &m.nested_type);
+                let index = messages
+                    .iter()
+                    .position(|m| format!("{}.{}", scope.name, m.name()) == full_name)?;
+                Some((scope, index))
+            })
+            .with_context(|| format!("Message {name} not found in {}", file.name()))?;
+        let (kind, message) = match scope.message {
+            Some(parent) => (MESSAGE_NESTED_TYPE, &parent.nested_type[index]),
+            None => (FILE_MESSAGE_TYPE, &file.message_type[index]),
+        };
+        let path = child_path(&scope.path, kind, index);
+        self.format_definition(file, &scope, |this, syntax| {
+            this.write_message(message, &path, syntax)
+        })
+    }
+
+    /// Format the enum `name` of `file`. See [`Self::format_message`].
+    pub fn format_enum(&mut self, file: &FileDescriptorProto, name: &str) -> Result<String> {
+        let full_name = qualified_name(name);
+        let (scope, index) = definition_scopes(file)
+            .into_iter()
+            .find_map(|scope| {
+                let enums = scope.message.map_or(&file.enum_type, |m| &m.enum_type);
+                let index = enums
+                    .iter()
+                    .position(|e| format!("{}.{}", scope.name, e.name()) == full_name)?;
+                Some((scope, index))
+            })
+            .with_context(|| format!("Enum {name} not found in {}", file.name()))?;
+        let (kind, enum_type) = match scope.message {
+            Some(parent) => (MESSAGE_ENUM_TYPE, &parent.enum_type[index]),
+            None => (FILE_ENUM_TYPE, &file.enum_type[index]),
+        };
+        let path = child_path(&scope.path, kind, index);
+        self.format_definition(file, &scope, |this, _| this.write_enum(enum_type, &path))
+    }
+
+    /// Format the service `name` of `file`. See [`Self::format_message`].
+    pub fn format_service(&mut self, file: &FileDescriptorProto, name: &str) -> Result<String> {
+        let full_name = qualified_name(name);
+        let scope = DefinitionScope::file(file);
+        let index = file
+            .service
+            .iter()
+            .position(|s| format!("{}.{}", scope.name, s.name()) == full_name)
+            .with_context(|| format!("Service {name} not found in {}", file.name()))?;
+        let path = child_path(&scope.path, FILE_SERVICE, index);
+        self.format_definition(file, &scope, |this, _| {
+            this.write_service(&file.service[index], &path)
+        })
+    }
+
+    /// Format the extension `name` of `file` in an `extend` block of its
+    /// own. `name` is the full name of the extension field, such as
+    /// `pkg.Outer.my_ext` for an extension declared in `Outer`. See
+    /// [`Self::format_message`].
+    pub fn format_extension(&mut self, file: &FileDescriptorProto, name: &str) -> Result<String> {
+        let full_name = qualified_name(name);
+        let (scope, index) = definition_scopes(file)
+            .into_iter()
+            .find_map(|scope| {
+                let extensions = scope.message.map_or(&file.extension, |m| &m.extension);
+                let index = extensions
+                    .iter()
+                    .position(|e| format!("{}.{}", scope.name, e.name()) == full_name)?;
+                Some((scope, index))
+            })
+            .with_context(|| format!("Extension {name} not found in {}", file.name()))?;
+        let (kind, extension) = match scope.message {
+            Some(parent) => (MESSAGE_EXTENSION, &parent.extension[index]),
+            None => (FILE_EXTENSION, &file.extension[index]),
+        };
+        let extensions = [(child_path(&scope.path, kind, index), extension)];
+        self.format_definition(file, &scope, |this, syntax| {
+            this.write_extend(extension.extendee(), &extensions, syntax)
+        })
+    }
+
+    /// Run `write` with the state of `file` and the scope of a definition
+    /// declared in `scope`, and return the text it generated.
+    fn format_definition(
+        &mut self,
+        file: &FileDescriptorProto,
+        scope: &DefinitionScope,
+        write: impl FnOnce(&mut Self, &str) -> Result<()>,
+    ) -> Result<String> {
+        self.begin_file(file);
+        self.scope = scope.name.clone();
+        // The enclosing message holds the group messages of extensions
+        self.current_message = scope.message.cloned();
+        let result = write(self, file.syntax.as_deref().unwrap_or("proto2"));
+        self.current_message = None;
+        result?;
+        Ok(std::mem::take(&mut self.output))
+    }
+
+    /// Reset the generator state for writing definitions of `file`.
+    fn begin_file(&mut self, file: &FileDescriptorProto) {
         self.output.clear();
         self.indent_level = 0;
         self.current_file = Some(file.clone());
-        // 1. Syntax (default to proto2 if not specified)
+        self.extensions = ExtensionIndex::new(std::iter::once(file).chain(&self.imports));
+        self.symbols = if self.options.relative_type_names {
+            SymbolTable::new(std::iter::once(file).chain(&self.imports))
+        } else {
+            SymbolTable::default()
+        };
+        self.scope = DefinitionScope::file(file).name;
+        self.locations = match file.source_code_info.as_ref() {
+            Some(info) if self.options.include_comments || self.options.preserve_order => info
+                .location
+                .iter()
+                .map(|location| (location.path.clone(), location.clone()))
+                .collect(),
+            _ => BTreeMap::new(),
+        };
+    }
+
+    fn write_file(&mut self, file: &FileDescriptorProto, out: &mut dyn Write) -> Result<()> {
+        self.begin_file(file);
+
+        // 1. Syntax (default to proto2 if not specified) or edition
         let syntax = file.syntax.as_deref().unwrap_or("proto2");
-        if !syntax.is_empty() {
-            writeln!(self.output, "syntax = \"{syntax}\";")?;
+        if let Some(edition) = editions::edition(file) {
+            self.write_leading_comments(&[FILE_SYNTAX]);
+            write!(self.output, "edition = \"{edition}\";")?;
+            self.write_line_end(&[FILE_SYNTAX]);
+            self.write_newline();
+        } else if !syntax.is_empty() {
+            self.write_leading_comments(&[FILE_SYNTAX]);
+            write!(self.output, "syntax = \"{syntax}\";")?;
+            self.write_line_end(&[FILE_SYNTAX]);
             self.write_newline();
         }
 
         // 2. Package
         if let Some(package) = file.package.as_ref() {
             if !package.is_empty() {
-                writeln!(self.output, "package {package};")?;
+                self.write_leading_comments(&[FILE_PACKAGE]);
+                write!(self.output, "package {package};")?;
+                self.write_line_end(&[FILE_PACKAGE]);
                 self.write_newline();
             }
         }
@@ -94,25 +510,187 @@ impl TextGenerator {
 
         // 4. File-level options
         self.write_file_options(file)?;
+        self.flush(out)?;
+
+        if self.options.preserve_order {
+            // 5-8. Definitions in declaration order
+            self.write_definitions_in_order(file, syntax, out)?;
+        } else {
+            // 5-8. Messages, enums and services (sorted by name if enabled)
+            // and extensions, in the order of the style
+            let mut order = Vec::new();
+            for kind in self.options.style.definition_order {
+                if !order.contains(&kind) {
+                    order.push(kind);
+                }
+            }
+            for kind in Style::canonical().definition_order {
+                if !order.contains(&kind) {
+                    order.push(kind);
+                }
+            }
+
+            // Top-level `extend` blocks may be followed by other definitions
+            let mut extend_blocks_open = false;
+            for kind in order {
+                let definitions = match kind {
+                    DefinitionKind::Messages => file.message_type.len(),
+                    DefinitionKind::Enums => file.enum_type.len(),
+                    DefinitionKind::Services => file.service.len(),
+                    DefinitionKind::Extensions => file.extension.len(),
+                };
+                if definitions == 0 {
+                    continue;
+                }
+                if std::mem::take(&mut extend_blocks_open) {
+                    self.write_newline();
+                }
+                match kind {
+                    DefinitionKind::Messages => self.write_messages(file, syntax, out)?,
+                    DefinitionKind::Enums => self.write_enums(file, out)?,
+                    DefinitionKind::Services => self.write_services(file, out)?,
+                    DefinitionKind::Extensions => {
+                        self.write_extensions(file, syntax, out)?;
+                        extend_blocks_open = self.options.style.blank_lines == BlankLines::TopLevel;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether `width` more characters fit on the current line.
+    fn fits(&self, width: usize) -> bool {
+        let Some(max_line_width) = self.options.max_line_width else {
+            return true;
+        };
+        let line = self.output.rsplit('\n').next().unwrap_or_default();
+        line.chars().count() + width <= max_line_width
+    }
 
-        // 5. Messages (sorted by name if enabled)
-        self.write_messages(file, syntax)?;
+    /// Write ` [a, b]`, or one option per line if that does not fit before
+    /// the closing `;`.
+    fn write_option_list(&mut self, opts: &[String]) -> Result<()> {
+        if opts.is_empty() {
+            return Ok(());
+        }
+        let line = format!(" [{}]", opts.join(", "));
+        if self.fits(line.chars().count() + 1) {
+            self.output.push_str(&line);
+            return Ok(());
+        }
+        write!(self.output, " [")?;
+        self.write_newline();
+        self.indent();
+        for (i, opt) in opts.iter().enumerate() {
+            self.write_indent();
+            self.output.push_str(opt);
+            if i + 1 < opts.len() {
+                self.output.push(',');
+            }
+            self.write_newline();
+        }
+        self.dedent();
+        self.write_indent();
+        write!(self.output, "]")?;
+        Ok(())
+    }
+
+    /// Write a `reserved` statement with `items`, if any, continuing on
+    /// further lines when it is too long.
+    fn write_reserved_list(&mut self, items: &[String]) -> Result<()> {
+        let Some((first, rest)) = items.split_first() else {
+            return Ok(());
+        };
+        self.write_indent();
+        write!(self.output, "reserved {first}")?;
+        self.indent();
+        for item in rest {
+            // ", item" plus the "," or ";" that follows
+            if self.fits(item.chars().count() + 3) {
+                write!(self.output, ", {item}")?;
+            } else {
+                write!(self.output, ",")?;
+                self.write_newline();
+                self.write_indent();
+                write!(self.output, "{item}")?;
+            }
+        }
+        self.dedent();
+        writeln!(self.output, ";")?;
+        Ok(())
+    }
 
-        // 6. Enums (sorted by name if enabled)
-        self.write_enums(file)?;
+    /// Account for the declarations of a body written since `start`, a
+    /// `block` (options or a nested definition) or not. With
+    /// [`BlankLines::Blocks`], they are separated from the `previous` ones
+    /// by a blank line when either is a block.
+    fn separate_declarations(&mut self, start: usize, block: bool, previous: &mut Option<bool>) {
+        if self.output.len() == start {
+            return;
+        }
+        if self.options.style.blank_lines == BlankLines::Blocks
+            && previous.is_some_and(|previous| previous || block)
+        {
+            self.output.insert(start, '\n');
+        }
+        *previous = Some(block);
+    }
 
-        // 7. Services (sorted by name if enabled)
-        self.write_services(file)?;
+    /// Hand the text generated so far to `out`.
+    fn flush(&mut self, out: &mut dyn Write) -> Result<()> {
+        out.write_str(&self.output)?;
+        self.output.clear();
+        Ok(())
+    }
 
-        // 8. Extensions (proto2)
-        self.write_extensions(file, syntax)?;
+    /// Format `file`, parse the text back and return every element of `file`
+    /// that the text lost or altered. An empty result means the text is
+    /// lossless.
+    ///
+    /// The text is parsed with a lenient [`ParseSession`] named after
+    /// `file`, with the imports given to [`Self::with_imports`] formatted as
+    /// its in-memory files; types from other imports cannot be resolved.
+    /// `source_code_info` and `json_name`s equal to the default derived
+    /// from the field name are ignored. Fails if the text does not parse.
+    pub fn verify_roundtrip(
+        &mut self,
+        file: &FileDescriptorProto,
+    ) -> Result<Vec<DescriptorDifference>> {
+        let mut imports = BTreeMap::new();
+        for import in &self.imports {
+            if import.name().is_empty()
+                || import.name() == file.name()
+                || import.name().starts_with("google/protobuf/")
+            {
+                continue;
+            }
+            let text = TextGenerator::with_default()
+                .with_imports(self.imports.iter().cloned())
+                .format_file(import)
+                .with_context(|| format!("Failed to generate import {}", import.name()))?;
+            imports.insert(import.name().to_string(), text);
+        }
 
-        Ok(self.output.clone())
+        let text = self.format_file(file)?;
+        let mut session = ParseSession::lenient().with_files(imports);
+        if !file.name().is_empty() {
+            session = session.with_file_name(file.name());
+        }
+        let mut reparsed = session
+            .parse(&text)
+            .context("Generated text does not parse")?;
+
+        let mut original = file.clone();
+        clear_default_json_names(&mut original);
+        clear_default_json_names(&mut reparsed);
+        Ok(diff_file_descriptors(&original, &reparsed))
     }
 
     // ========== Helper Methods ==========
 
-    fn escape_string(s: &str) -> String {
+    pub(crate) fn escape_string(s: &str) -> String {
         let mut result = String::new();
         for ch in s.chars() {
             match ch {
@@ -127,7 +705,7 @@ impl TextGenerator {
         result
     }
 
-    fn escape_bytes(b: &[u8]) -> String {
+    pub(crate) fn escape_bytes(b: &[u8]) -> String {
         let mut out = String::new();
         for &byte in b {
             match byte {
@@ -164,6 +742,67 @@ impl TextGenerator {
         self.indent_level = self.indent_level.saturating_sub(1);
     }
 
+    // ========== Comments ==========
+
+    /// Write the detached and leading comments of the element at `path`,
+    /// each detached comment followed by a blank line.
+    fn write_leading_comments(&mut self, path: &[i32]) {
+        if !self.options.include_comments {
+            return;
+        }
+        let Some(location) = self.locations.get(path).cloned() else {
+            return;
+        };
+        for detached in &location.leading_detached_comments {
+            self.write_comment(detached);
+            self.write_newline();
+        }
+        if let Some(leading) = location.leading_comments.as_deref() {
+            self.write_comment(leading);
+        }
+    }
+
+    /// Start (line, column) of the element at `path` in the source, if known.
+    fn source_position(&self, path: &[i32]) -> Option<(i32, i32)> {
+        match self.locations.get(path)?.span[..] {
+            [line, column, ..] => Some((line, column)),
+            _ => None,
+        }
+    }
+
+    fn write_comment(&mut self, text: &str) {
+        for line in text.strip_suffix('\n').unwrap_or(text).split('\n') {
+            self.write_indent();
+            self.output.push_str("//");
+            self.output.push_str(line.trim_end());
+            self.write_newline();
+        }
+    }
+
+    /// End the first line of the element at `path` with its trailing
+    /// comment, if any. Multi-line trailing comments are joined.
+    fn write_line_end(&mut self, path: &[i32]) {
+        let trailing = self
+            .locations
+            .get(path)
+            .filter(|_| self.options.include_comments)
+            .and_then(|location| location.trailing_comments.as_deref())
+            .map(|text| text.trim_end().lines().collect::<Vec<_>>());
+        match trailing.as_deref() {
+            Some([]) | None => {}
+            Some([line]) => {
+                self.output.push_str(" //");
+                self.output.push_str(line);
+            }
+            Some(lines) => {
+                let lines: Vec<&str> = lines.iter().map(|line| line.trim()).collect();
+                self.output.push_str(" // ");
+                self.output.push_str(&lines.join(" "));
+            }
+        }
+        self.write_newline();
+    }
+
     // ========== Imports ==========
 
     fn write_imports(&mut self, file: &FileDescriptorProto) -> Result<()> {
@@ -180,49 +819,55 @@ impl TextGenerator {
         for dep in file.dependency.iter() {
             imports.push((dep.as_str(), false, false)); // (path, is_public, is_weak)
         }
+        let mut imports: Vec<_> = imports.into_iter().enumerate().collect();
 
         // Mark public imports
         for &idx in file.public_dependency.iter() {
-            if let Some(item) = imports.get_mut(idx as usize) {
+            if let Some((_, item)) = imports.get_mut(idx as usize) {
                 item.1 = true;
             }
         }
 
         // Mark weak imports
         for &idx in file.weak_dependency.iter() {
-            if let Some(item) = imports.get_mut(idx as usize) {
+            if let Some((_, item)) = imports.get_mut(idx as usize) {
                 item.2 = true;
             }
         }
 
         // Sort: by kind (normal=0, public=1, weak=2), then by path
-        imports.sort_by(|a, b| {
-            let rank = |is_public: bool, is_weak: bool| {
-                if is_public {
-                    1
-                } else if is_weak {
-                    2
-                } else {
-                    0
+        if !self.options.preserve_order {
+            imports.sort_by(|(_, a), (_, b)| {
+                let rank = |is_public: bool, is_weak: bool| {
+                    if is_public {
+                        1
+                    } else if is_weak {
+                        2
+                    } else {
+                        0
+                    }
+                };
+                let ar = rank(a.1, a.2);
+                let br = rank(b.1, b.2);
+                match ar.cmp(&br) {
+                    std::cmp::Ordering::Equal => a.0.cmp(b.0),
+                    other => other,
                 }
-            };
-            let ar = rank(a.1, a.2);
-            let br = rank(b.1, b.2);
-            match ar.cmp(&br) {
-                std::cmp::Ordering::Equal => a.0.cmp(b.0),
-                other => other,
-            }
-        });
+            });
+        }
 
         // Write imports
-        for (path, is_public, is_weak) in imports {
+        for (index, (path, is_public, is_weak)) in imports {
+            let import_path = [FILE_DEPENDENCY, index as i32];
+            self.write_leading_comments(&import_path);
             if is_public {
-                writeln!(self.output, "import public \"{path}\";")?;
+                write!(self.output, "import public \"{path}\";")?;
             } else if is_weak {
-                writeln!(self.output, "import weak \"{path}\";")?;
+                write!(self.output, "import weak \"{path}\";")?;
             } else {
-                writeln!(self.output, "import \"{path}\";")?;
+                write!(self.output, "import \"{path}\";")?;
             }
+            self.write_line_end(&import_path);
         }
 
         self.write_newline();
@@ -251,6 +896,9 @@ impl TextGenerator {
             if let Some(val) = options.java_multiple_files {
                 opts.push(format!("option java_multiple_files = {val};"));
             }
+            if let Some(val) = options.java_generate_equals_and_hash {
+                opts.push(format!("option java_generate_equals_and_hash = {val};"));
+            }
             if let Some(val) = options.java_string_check_utf8 {
                 opts.push(format!("option java_string_check_utf8 = {val};"));
             }
@@ -293,6 +941,12 @@ impl TextGenerator {
             if let Some(val) = options.py_generic_services {
                 opts.push(format!("option py_generic_services = {val};"));
             }
+            if let Some(val) = options.php_generic_services {
+                opts.push(format!("option php_generic_services = {val};"));
+            }
+            if let Some(val) = options.deprecated {
+                opts.push(format!("option deprecated = {val};"));
+            }
             if let Some(val) = options.objc_class_prefix.as_ref() {
                 opts.push(format!(
                     "option objc_class_prefix = \"{}\";",
@@ -335,10 +989,21 @@ impl TextGenerator {
                     Self::escape_string(val)
                 ));
             }
+            for builtin in self.builtin_options(options)? {
+                opts.push(format!("option {builtin};"));
+            }
+            for feature in editions::features(options)? {
+                opts.push(format!("option {feature};"));
+            }
 
             // Sort options for determinism
             opts.sort();
 
+            // Custom options are already sorted by name; repeated values keep their order
+            for custom in self.custom_options(options)? {
+                opts.push(format!("option {custom};"));
+            }
+
             for opt in &opts {
                 writeln!(self.output, "{opt}")?;
             }
@@ -351,118 +1016,209 @@ impl TextGenerator {
         Ok(())
     }
 
+    // ========== Definitions in declaration order ==========
+
+    /// Write the top-level messages, enums, services and extensions in the
+    /// order of the source, each followed by a blank line. Consecutive
+    /// extensions of the same message share an `extend` block.
+    fn write_definitions_in_order(
+        &mut self,
+        file: &FileDescriptorProto,
+        syntax: &str,
+        out: &mut dyn Write,
+    ) -> Result<()> {
+        let mut definitions: Vec<(Vec<i32>, Definition)> = Vec::new();
+        // Skip the messages of groups declared by top-level extensions
+        let group_messages = Self::group_message_names(&file.extension);
+        for (index, message) in file.message_type.iter().enumerate() {
+            if group_messages.contains(message.name()) {
+                continue;
+            }
+            definitions.push((
+                vec![FILE_MESSAGE_TYPE, index as i32],
+                Definition::Message(message),
+            ));
+        }
+        for (index, enum_type) in file.enum_type.iter().enumerate() {
+            definitions.push((
+                vec![FILE_ENUM_TYPE, index as i32],
+                Definition::Enum(enum_type),
+            ));
+        }
+        for (index, service) in file.service.iter().enumerate() {
+            definitions.push((
+                vec![FILE_SERVICE, index as i32],
+                Definition::Service(service),
+            ));
+        }
+        for (index, extension) in file.extension.iter().enumerate() {
+            definitions.push((
+                vec![FILE_EXTENSION, index as i32],
+                Definition::Extension(extension),
+            ));
+        }
+        definitions.sort_by_key(|(path, _)| self.source_position(path));
+
+        let mut definitions = definitions.into_iter().peekable();
+        while let Some((path, definition)) = definitions.next() {
+            match definition {
+                Definition::Message(message) => self.write_message(message, &path, syntax)?,
+                Definition::Enum(enum_type) => self.write_enum(enum_type, &path)?,
+                Definition::Service(service) => self.write_service(service, &path)?,
+                Definition::Extension(extension) => {
+                    let extendee = extension.extendee();
+                    let mut extensions = vec![(path, extension)];
+                    while let Some((path, Definition::Extension(next))) = definitions.next_if(
+                        |(_, d)| matches!(d, Definition::Extension(e) if e.extendee() == extendee),
+                    ) {
+                        extensions.push((path, next));
+                    }
+                    self.write_extend(extendee, &extensions, syntax)?;
+                }
+            }
+            self.write_newline();
+            self.flush(out)?;
+        }
+        Ok(())
+    }
+
     // ========== Messages ==========
 
-    fn write_messages(&mut self, file: &FileDescriptorProto, syntax: &str) -> Result<()> {
-        let mut messages = file.message_type.clone();
+    fn write_messages(
+        &mut self,
+        file: &FileDescriptorProto,
+        syntax: &str,
+        out: &mut dyn Write,
+    ) -> Result<()> {
+        // Skip the messages of groups declared by top-level extensions
+        let group_messages = Self::group_message_names(&file.extension);
+        let mut messages: Vec<_> = file
+            .message_type
+            .iter()
+            .enumerate()
+            .filter(|(_, message)| !group_messages.contains(message.name()))
+            .collect();
 
         if self.options.sort_messages {
-            messages.sort_by(|a, b| a.name().cmp(b.name()));
+            messages.sort_by(|(_, a), (_, b)| a.name().cmp(b.name()));
         }
 
-        for message in messages.iter() {
-            self.write_message(message, syntax)?;
+        for (index, message) in messages {
+            self.write_message(message, &[FILE_MESSAGE_TYPE, index as i32], syntax)?;
             self.write_newline();
+            self.flush(out)?;
         }
 
         Ok(())
     }
 
-    fn write_message(&mut self, message: &DescriptorProto, syntax: &str) -> Result<()> {
+    fn write_message(
+        &mut self,
+        message: &DescriptorProto,
+        path: &[i32],
+        syntax: &str,
+    ) -> Result<()> {
         // Skip map entry messages (they're synthetic)
         if self.is_map_entry(message) {
             return Ok(());
         }
 
+        self.write_leading_comments(path);
         self.write_indent();
-        writeln!(self.output, "message {} {{", message.name())?;
+        write!(self.output, "message {} {{", message.name())?;
+        self.write_line_end(path);
         self.indent();
 
-        // Message options
-        self.write_message_options(message)?;
-
-        // Nested enums
-        for nested_enum in message.enum_type.iter() {
-            self.write_enum(nested_enum)?;
-        }
-
-        // Nested messages (skip group-generated messages)
-        let group_messages = self.get_group_message_names(message);
-        for nested_msg in message.nested_type.iter() {
-            // Skip messages that are generated from groups
-            if !group_messages.contains(nested_msg.name()) {
-                self.write_message(nested_msg, syntax)?;
-            }
-        }
-
-        // Regular fields (non-oneof)
-        // Store current message for map field detection
-        let saved_message = self.current_message.take();
-        self.current_message = Some(message.clone());
-
-        let mut regular_fields: Vec<_> = message
-            .field
-            .iter()
-            // Treat proto3 optional fields as regular fields
-            .filter(|f| f.oneof_index.is_none() || f.proto3_optional.unwrap_or(false))
-            .collect();
-
-        // Sort by field number for determinism
-        regular_fields.sort_by_key(|f| f.number());
-
-        for field in regular_fields {
-            self.write_field(field, syntax)?;
+        // Message options; `previous` tells whether the last declarations
+        // written in the body, if any, form a block
+        let mut previous = None;
+        let start = self.output.len();
+        if self.options.style.option_placement == OptionPlacement::First {
+            self.write_message_options(message)?;
         }
-
-        // Oneofs (collect oneof fields)
-        let mut oneof_fields: Vec<Vec<&FieldDescriptorProto>> =
-            vec![Vec::new(); message.oneof_decl.len()];
-
-        for field in message.field.iter() {
-            if let Some(idx) = field.oneof_index {
-                // Skip synthetic oneof for proto3 optional fields
-                if field.proto3_optional.unwrap_or(false) {
-                    continue;
+        self.separate_declarations(start, true, &mut previous);
+
+        // Store current message for map field and group detection
+        let saved_message = self.current_message.replace(message.clone());
+        let scope = format!("{}.{}", self.scope, message.name());
+        let saved_scope = std::mem::replace(&mut self.scope, scope);
+
+        let mut members = self.message_members(message, path).into_iter().peekable();
+        while let Some(member) = members.next() {
+            let start = self.output.len();
+            let block = !matches!(member, Member::Field(..) | Member::Oneof(..));
+            match member {
+                Member::Enum(index, nested_enum) => {
+                    self.write_enum(nested_enum, &child_path(path, MESSAGE_ENUM_TYPE, index))?;
                 }
-                if (idx as usize) < oneof_fields.len() {
-                    oneof_fields[idx as usize].push(field);
+                Member::Message(index, nested_msg) => {
+                    let nested_path = child_path(path, MESSAGE_NESTED_TYPE, index);
+                    self.write_message(nested_msg, &nested_path, syntax)?;
+                }
+                Member::Field(index, field) => {
+                    self.write_field(field, &child_path(path, MESSAGE_FIELD, index), syntax)?;
+                }
+                Member::Oneof(index, oneof, fields) => {
+                    let oneof_path = child_path(path, MESSAGE_ONEOF_DECL, index);
+                    self.write_oneof(oneof, &oneof_path, &fields, path, syntax)?;
+                }
+                Member::Extension(index, extension) => {
+                    // Consecutive extensions of the same message share a block
+                    let extendee = extension.extendee();
+                    let mut extensions =
+                        vec![(child_path(path, MESSAGE_EXTENSION, index), extension)];
+                    while let Some(Member::Extension(index, next)) = members.next_if(
+                        |m| matches!(m, Member::Extension(_, e) if e.extendee() == extendee),
+                    ) {
+                        extensions.push((child_path(path, MESSAGE_EXTENSION, index), next));
+                    }
+                    self.write_extend(extendee, &extensions, syntax)?;
                 }
             }
-        }
-
-        // Write oneofs
-        for (idx, oneof) in message.oneof_decl.iter().enumerate() {
-            if !oneof_fields[idx].is_empty() {
-                self.write_oneof(oneof, &oneof_fields[idx], syntax)?;
-            }
+            self.separate_declarations(start, block, &mut previous);
         }
 
         // Restore previous message
         self.current_message = saved_message;
+        self.scope = saved_scope;
 
-        // Extensions
-        for extension in message.extension.iter() {
-            self.write_field(extension, syntax)?;
+        // Extension ranges, sorted by start then end
+        let start = self.output.len();
+        let mut extension_ranges: Vec<_> = message.extension_range.iter().collect();
+        if !self.options.preserve_order {
+            extension_ranges.sort_by_key(|range| (range.start(), range.end()));
         }
-
-        // Extension ranges
-        for range in message.extension_range.iter() {
+        for range in extension_ranges {
             self.write_indent();
             if range.start() + 1 == range.end() {
-                writeln!(self.output, "extensions {};", range.start())?;
+                write!(self.output, "extensions {}", range.start())?;
             } else {
                 // Max field number is 536870911 (0x1FFFFFFF), stored as end=536870912
                 let end_val = range.end() - 1;
                 if end_val == 536870911 {
-                    writeln!(self.output, "extensions {} to max;", range.start())?;
+                    write!(self.output, "extensions {} to max", range.start())?;
                 } else {
-                    writeln!(self.output, "extensions {} to {};", range.start(), end_val)?;
+                    write!(self.output, "extensions {} to {}", range.start(), end_val)?;
                 }
             }
+            if let Some(options) = range.options.as_ref() {
+                let mut opts = self.builtin_options(options)?;
+                opts.extend(editions::features(options)?);
+                opts.extend(self.custom_options(options)?);
+                self.write_option_list(&opts)?;
+            }
+            writeln!(self.output, ";")?;
         }
 
         // Reserved
         self.write_reserved(message)?;
+        self.separate_declarations(start, false, &mut previous);
+
+        let start = self.output.len();
+        if self.options.style.option_placement == OptionPlacement::Last {
+            self.write_message_options(message)?;
+        }
+        self.separate_declarations(start, true, &mut previous);
 
         self.dedent();
         self.write_indent();
@@ -471,31 +1227,133 @@ impl TextGenerator {
         Ok(())
     }
 
-    fn is_map_entry(&self, message: &DescriptorProto) -> bool {
-        message
-            .options
-            .as_ref()
-            .and_then(|o| o.map_entry)
-            .unwrap_or(false)
-    }
-
-    fn get_group_message_names(
-        &self,
-        message: &DescriptorProto,
-    ) -> std::collections::HashSet<String> {
-        let mut group_names = std::collections::HashSet::new();
-        for field in &message.field {
-            if let Some(type_) = field.type_ {
-                if type_.value() == Type::TYPE_GROUP as i32 {
-                    if let Some(type_name) = field.type_name.as_ref() {
-                        let group_name = type_name.split('.').next_back().unwrap_or(type_name);
-                        group_names.insert(group_name.to_string());
-                    }
-                }
-            }
+    /// Nested definitions, fields, oneofs and extensions of `message` in
+    /// output order: nested enums and nested messages (by name if sorted),
+    /// fields by number, oneofs by name and extensions by extendee and
+    /// number, or declaration order when preserving the order.
+    fn message_members<'a>(&self, message: &'a DescriptorProto, path: &[i32]) -> Vec<Member<'a>> {
+        let preserve_order = self.options.preserve_order;
+        let mut enums: Vec<_> = message.enum_type.iter().enumerate().collect();
+        if self.options.sort_enums && !preserve_order {
+            enums.sort_by(|(_, a), (_, b)| a.name().cmp(b.name()));
         }
-        group_names
-    }
+        let mut members: Vec<Member> = enums
+            .into_iter()
+            .map(|(index, nested_enum)| Member::Enum(index, nested_enum))
+            .collect();
+
+        // Nested messages (skip group-generated messages)
+        let group_messages =
+            Self::group_message_names(message.field.iter().chain(&message.extension));
+        let mut nested_messages: Vec<_> = message
+            .nested_type
+            .iter()
+            .enumerate()
+            .filter(|(_, nested_msg)| !group_messages.contains(nested_msg.name()))
+            .collect();
+        if self.options.sort_messages && !preserve_order {
+            nested_messages.sort_by(|(_, a), (_, b)| a.name().cmp(b.name()));
+        }
+        members.extend(
+            nested_messages
+                .into_iter()
+                .map(|(index, nested_msg)| Member::Message(index, nested_msg)),
+        );
+
+        // Regular fields (non-oneof), treating proto3 optional fields as regular fields
+        let mut regular_fields: Vec<_> = message
+            .field
+            .iter()
+            .enumerate()
+            .filter(|(_, f)| f.oneof_index.is_none() || f.proto3_optional.unwrap_or(false))
+            .collect();
+        if !preserve_order {
+            // Sort by field number for determinism
+            regular_fields
+                .sort_by(|(_, a), (_, b)| (a.number(), a.name()).cmp(&(b.number(), b.name())));
+        }
+        members.extend(
+            regular_fields
+                .into_iter()
+                .map(|(index, field)| Member::Field(index, field)),
+        );
+
+        // Oneofs (collect oneof fields)
+        let mut oneof_fields: Vec<Vec<(usize, &FieldDescriptorProto)>> =
+            vec![Vec::new(); message.oneof_decl.len()];
+        for (index, field) in message.field.iter().enumerate() {
+            if let Some(idx) = field.oneof_index {
+                // Skip synthetic oneof for proto3 optional fields
+                if field.proto3_optional.unwrap_or(false) {
+                    continue;
+                }
+                if (idx as usize) < oneof_fields.len() {
+                    oneof_fields[idx as usize].push((index, field));
+                }
+            }
+        }
+        let mut oneofs: Vec<_> = message
+            .oneof_decl
+            .iter()
+            .zip(oneof_fields)
+            .enumerate()
+            .filter(|(_, (_, fields))| !fields.is_empty())
+            .collect();
+        if !preserve_order {
+            // Sort by name, like fields by number, whatever the sort options
+            oneofs.sort_by(|(_, (a, _)), (_, (b, _))| a.name().cmp(b.name()));
+        }
+        members.extend(
+            oneofs
+                .into_iter()
+                .map(|(idx, (oneof, fields))| Member::Oneof(idx, oneof, fields)),
+        );
+
+        // Extensions, grouped by extendee and sorted by number
+        let mut extensions: Vec<_> = message.extension.iter().enumerate().collect();
+        if !preserve_order {
+            extensions.sort_by(|(_, a), (_, b)| {
+                (a.extendee(), a.number(), a.name()).cmp(&(b.extendee(), b.number(), b.name()))
+            });
+        }
+        members.extend(
+            extensions
+                .into_iter()
+                .map(|(index, extension)| Member::Extension(index, extension)),
+        );
+
+        if preserve_order {
+            // Interleave kinds by source position; without source info each
+            // kind stays in declaration order
+            members.sort_by_key(|member| self.source_position(&member.path(path)));
+        }
+        members
+    }
+
+    fn is_map_entry(&self, message: &DescriptorProto) -> bool {
+        message
+            .options
+            .as_ref()
+            .and_then(|o| o.map_entry)
+            .unwrap_or(false)
+    }
+
+    fn group_message_names<'a>(
+        fields: impl IntoIterator<Item = &'a FieldDescriptorProto>,
+    ) -> std::collections::HashSet<String> {
+        let mut group_names = std::collections::HashSet::new();
+        for field in fields {
+            if let Some(type_) = field.type_ {
+                if type_.value() == Type::TYPE_GROUP as i32 {
+                    if let Some(type_name) = field.type_name.as_ref() {
+                        let group_name = type_name.split('.').next_back().unwrap_or(type_name);
+                        group_names.insert(group_name.to_string());
+                    }
+                }
+            }
+        }
+        group_names
+    }
 
     fn get_map_field_info(&self, field: &FieldDescriptorProto) -> Option<MapFieldInfo> {
         // Check if field is repeated and of message type
@@ -592,52 +1450,112 @@ impl TextGenerator {
                     writeln!(self.output, "option deprecated = true;")?;
                 }
             }
+            self.write_builtin_options(options)?;
+            self.write_features(options)?;
+            self.write_custom_options(options)?;
         }
         Ok(())
     }
 
-    fn write_reserved(&mut self, message: &DescriptorProto) -> Result<()> {
-        // Reserved ranges (end is exclusive for messages)
-        if !message.reserved_range.is_empty() {
+    /// Write `option <name> = <value>;` lines for the built-in options of an
+    /// options message that the `protobuf` crate does not know.
+    fn write_builtin_options(&mut self, options: &dyn protobuf::MessageDyn) -> Result<()> {
+        for builtin in self.builtin_options(options)? {
             self.write_indent();
-            write!(self.output, "reserved ")?;
-            for (i, range) in message.reserved_range.iter().enumerate() {
-                if i > 0 {
-                    write!(self.output, ", ")?;
-                }
+            writeln!(self.output, "option {builtin};")?;
+        }
+        Ok(())
+    }
+
+    /// Built-in options unknown to the `protobuf` crate, as `<name> = <value>`.
+    fn builtin_options(&self, options: &dyn protobuf::MessageDyn) -> Result<Vec<String>> {
+        builtin_options::render(options).with_context(|| {
+            let file = self.current_file.as_ref().map_or("", |f| f.name());
+            format!("Failed to render built-in options in {file}")
+        })
+    }
+
+    /// Write `option features.<name> = <VALUE>;` lines for an options message.
+    fn write_features(&mut self, options: &dyn protobuf::MessageDyn) -> Result<()> {
+        for feature in editions::features(options)? {
+            self.write_indent();
+            writeln!(self.output, "option {feature};")?;
+        }
+        Ok(())
+    }
+
+    /// Write `option (<extension>) = <value>;` lines for an options message.
+    fn write_custom_options(&mut self, options: &dyn protobuf::MessageDyn) -> Result<()> {
+        for custom in self.custom_options(options)? {
+            self.write_indent();
+            writeln!(self.output, "option {custom};")?;
+        }
+        Ok(())
+    }
+
+    /// Custom options set on an options message, as `(<extension>) = <value>`.
+    fn custom_options(&self, options: &dyn protobuf::MessageDyn) -> Result<Vec<String>> {
+        let unresolved = if self.raw_unresolved_options {
+            Unresolved::Raw
+        } else if self.options.strict_custom_options {
+            Unresolved::Fail
+        } else {
+            Unresolved::Skip
+        };
+        self.extensions
+            .render(options, unresolved)
+            .with_context(|| {
+                let file = self.current_file.as_ref().map_or("", |f| f.name());
+                format!("Failed to render custom options in {file}")
+            })
+    }
+
+    fn write_reserved(&mut self, message: &DescriptorProto) -> Result<()> {
+        // Reserved ranges (end is exclusive for messages), sorted by start
+        // then end
+        let mut ranges: Vec<_> = message.reserved_range.iter().collect();
+        if !self.options.preserve_order {
+            ranges.sort_by_key(|range| (range.start(), range.end()));
+        }
+        let ranges: Vec<_> = ranges
+            .into_iter()
+            .map(|range| {
                 if range.start() + 1 == range.end() {
-                    write!(self.output, "{}", range.start())?;
+                    range.start().to_string()
                 } else {
                     let end_val = range.end() - 1;
                     if end_val == 536870911 {
-                        write!(self.output, "{} to max", range.start())?;
+                        format!("{} to max", range.start())
                     } else {
-                        write!(self.output, "{} to {}", range.start(), end_val)?;
+                        format!("{} to {}", range.start(), end_val)
                     }
                 }
-            }
-            writeln!(self.output, ";")?;
-        }
+            })
+            .collect();
+        self.write_reserved_list(&ranges)?;
 
-        // Reserved names
-        if !message.reserved_name.is_empty() {
-            self.write_indent();
-            write!(self.output, "reserved ")?;
-            for (i, name) in message.reserved_name.iter().enumerate() {
-                if i > 0 {
-                    write!(self.output, ", ")?;
-                }
-                write!(self.output, "\"{name}\"")?;
-            }
-            writeln!(self.output, ";")?;
+        // Reserved names, sorted
+        let mut names: Vec<_> = message.reserved_name.iter().collect();
+        if !self.options.preserve_order {
+            names.sort();
         }
-
-        Ok(())
+        let names: Vec<_> = names
+            .into_iter()
+            .map(|name| format!("\"{name}\""))
+            .collect();
+        self.write_reserved_list(&names)
     }
 
     // ========== Fields ==========
 
-    fn write_field(&mut self, field: &FieldDescriptorProto, syntax: &str) -> Result<()> {
+    fn write_field(
+        &mut self,
+        field: &FieldDescriptorProto,
+        path: &[i32],
+        syntax: &str,
+    ) -> Result<()> {
+        self.write_leading_comments(path);
+
         // Check if this is a map field
         if let Some(map_info) = self.get_map_field_info(field) {
             self.write_indent();
@@ -650,7 +1568,8 @@ impl TextGenerator {
                 field.number()
             )?;
             self.write_field_options(field)?;
-            writeln!(self.output, ";")?;
+            write!(self.output, ";")?;
+            self.write_line_end(path);
             return Ok(());
         }
 
@@ -663,8 +1582,10 @@ impl TextGenerator {
             } else if label.value() == Label::LABEL_REQUIRED as i32 && syntax == "proto2" {
                 write!(self.output, "required ")?;
             } else if label.value() == Label::LABEL_OPTIONAL as i32 {
-                // In proto2, optional is explicit. In proto3, optional is only printed when proto3_optional is true.
-                if syntax == "proto2" || field.proto3_optional.unwrap_or(false) {
+                // In proto2, optional is explicit except inside oneofs. In proto3, optional is only printed when proto3_optional is true.
+                if (syntax == "proto2" && field.oneof_index.is_none())
+                    || field.proto3_optional.unwrap_or(false)
+                {
                     write!(self.output, "optional ")?;
                 }
             }
@@ -684,28 +1605,38 @@ impl TextGenerator {
                 }
                 write!(self.output, " = {}", field.number())?;
                 self.write_field_options(field)?;
-                writeln!(self.output, " {{")?;
-
-                // Find and render group fields (from nested message)
-                let group_fields = if let Some(current_msg) = self.current_message.as_ref() {
-                    if let Some(type_name) = field.type_name.as_ref() {
-                        let group_name = type_name.split('.').next_back().unwrap_or(type_name);
-                        current_msg
-                            .nested_type
-                            .iter()
-                            .find(|m| m.name() == group_name)
-                            .map(|m| m.field.clone())
-                    } else {
-                        None
-                    }
-                } else {
-                    None
+                write!(self.output, " {{")?;
+                self.write_line_end(path);
+
+                // Find and render group fields: the group type is nested in
+                // the message owning the field, or top-level for extensions
+                // declared at file scope
+                let (siblings, kind) = match (&self.current_message, &self.current_file) {
+                    (Some(message), _) => (message.nested_type.as_slice(), MESSAGE_NESTED_TYPE),
+                    (None, Some(file)) => (file.message_type.as_slice(), FILE_MESSAGE_TYPE),
+                    (None, None) => (&[][..], FILE_MESSAGE_TYPE),
                 };
-
-                if let Some(fields) = group_fields {
+                let group_fields = field.type_name.as_ref().and_then(|type_name| {
+                    let group_name = type_name.split('.').next_back().unwrap_or(type_name);
+                    siblings
+                        .iter()
+                        .position(|m| m.name() == group_name)
+                        .map(|index| (index, siblings[index].field.clone()))
+                });
+
+                if let Some((group_index, fields)) = group_fields {
+                    let message_path = &path[..path.len().saturating_sub(2)];
+                    let group_path = child_path(message_path, kind, group_index);
+                    let mut fields: Vec<_> = fields.iter().enumerate().collect();
+                    if !self.options.preserve_order {
+                        fields.sort_by(|(_, a), (_, b)| {
+                            (a.number(), a.name()).cmp(&(b.number(), b.name()))
+                        });
+                    }
                     self.indent();
-                    for group_field in &fields {
-                        self.write_field(group_field, syntax)?;
+                    for (index, group_field) in fields {
+                        let field_path = child_path(&group_path, MESSAGE_FIELD, index);
+                        self.write_field(group_field, &field_path, syntax)?;
                     }
                     self.dedent();
                 }
@@ -733,15 +1664,15 @@ impl TextGenerator {
         // Field options
         self.write_field_options(field)?;
 
-        writeln!(self.output, ";")?;
+        write!(self.output, ";")?;
+        self.write_line_end(path);
 
         Ok(())
     }
 
     fn write_field_options(&mut self, field: &FieldDescriptorProto) -> Result<()> {
+        let mut opts = Vec::new();
         if let Some(options) = field.options.as_ref() {
-            let mut opts = Vec::new();
-
             if let Some(val) = options.packed {
                 opts.push(format!("packed = {val}"));
             }
@@ -786,54 +1717,58 @@ impl TextGenerator {
                 };
                 opts.push(format!("jstype = {s}"));
             }
-            if let Some(ref val) = field.default_value {
-                // Format default value based on type
-                if let Some(type_) = field.type_ {
-                    let type_val = type_.value();
-                    if type_val == Type::TYPE_STRING as i32 {
-                        opts.push(format!("default = \"{}\"", Self::escape_string(val)));
-                    } else if type_val == Type::TYPE_BYTES as i32 {
-                        // For bytes, escape non-printable and non-ASCII using \xNN
-                        let escaped = Self::escape_bytes(val.as_bytes());
-                        opts.push(format!("default = \"{escaped}\""));
-                    } else if type_val == Type::TYPE_ENUM as i32 {
-                        // Enum default: print symbol name. If numeric, map to symbol.
-                        let printed = if let Ok(num) = val.parse::<i32>() {
-                            if let Some(ref type_name) = field.type_name {
-                                self.enum_number_to_name(type_name, num)
-                                    .unwrap_or_else(|| val.clone())
-                            } else {
-                                val.clone()
-                            }
+            opts.extend(self.builtin_options(options)?);
+            opts.extend(editions::features(options)?);
+        }
+        if let Some(ref val) = field.default_value {
+            // Format default value based on type
+            if let Some(type_) = field.type_ {
+                let type_val = type_.value();
+                if type_val == Type::TYPE_STRING as i32 {
+                    opts.push(format!("default = \"{}\"", Self::escape_string(val)));
+                } else if type_val == Type::TYPE_BYTES as i32 {
+                    // For bytes, escape non-printable and non-ASCII using \xNN
+                    let escaped = Self::escape_bytes(val.as_bytes());
+                    opts.push(format!("default = \"{escaped}\""));
+                } else if type_val == Type::TYPE_ENUM as i32 {
+                    // Enum default: print symbol name. If numeric, map to symbol.
+                    let printed = if let Ok(num) = val.parse::<i32>() {
+                        if let Some(ref type_name) = field.type_name {
+                            self.enum_number_to_name(type_name, num)
+                                .unwrap_or_else(|| val.clone())
                         } else {
                             val.clone()
-                        };
-                        opts.push(format!("default = {printed}"));
-                    } else if type_val == Type::TYPE_FLOAT as i32
-                        || type_val == Type::TYPE_DOUBLE as i32
-                    {
-                        let norm = Self::normalize_float_default(val);
-                        opts.push(format!("default = {norm}"));
+                        }
                     } else {
-                        // numeric, bool default values appear as is
-                        opts.push(format!("default = {val}"));
-                    }
+                        val.clone()
+                    };
+                    opts.push(format!("default = {printed}"));
+                } else if type_val == Type::TYPE_FLOAT as i32
+                    || type_val == Type::TYPE_DOUBLE as i32
+                {
+                    let norm = Self::normalize_float_default(val);
+                    opts.push(format!("default = {norm}"));
+                } else {
+                    // numeric, bool default values appear as is
+                    opts.push(format!("default = {val}"));
                 }
             }
+        }
 
-            if !opts.is_empty() {
-                write!(self.output, " [")?;
-                for (i, opt) in opts.iter().enumerate() {
-                    if i > 0 {
-                        write!(self.output, ", ")?;
-                    }
-                    write!(self.output, "{opt}")?;
-                }
-                write!(self.output, "]")?;
+        // Only a json_name differing from the derived one changes the mapping
+        if let Some(json_name) = field.json_name.as_deref() {
+            if json_name != default_json_name(field.name()) {
+                opts.push(format!(
+                    "json_name = \"{}\"",
+                    Self::escape_string(json_name)
+                ));
             }
         }
+        if let Some(options) = field.options.as_ref() {
+            opts.extend(self.custom_options(options)?);
+        }
 
-        Ok(())
+        self.write_option_list(&opts)
     }
 
     fn field_type_to_string(&self, type_: protobuf::EnumOrUnknown<Type>) -> &'static str {
@@ -879,7 +1814,22 @@ impl TextGenerator {
         }
     }
 
+    /// Name of the type of a field, as written in the current scope.
     fn format_type_name(&self, type_name: &str) -> String {
+        self.format_reference(type_name, Lookup::Types)
+    }
+
+    /// Name of a method input or output type or of an extendee, as written
+    /// in the current scope. Unlike field types, these can be shadowed by
+    /// any symbol, not just types.
+    fn format_symbol_name(&self, type_name: &str) -> String {
+        self.format_reference(type_name, Lookup::All)
+    }
+
+    fn format_reference(&self, type_name: &str, lookup: Lookup) -> String {
+        if self.options.relative_type_names {
+            return self.symbols.shortest_name(type_name, &self.scope, lookup);
+        }
         // Remove leading dot if present
         type_name.trim_start_matches('.').to_string()
     }
@@ -975,21 +1925,40 @@ impl TextGenerator {
 
     // ========== Oneofs ==========
 
+    /// Write a oneof at `path` with its `fields`, given with their index in
+    /// the message at `message_path`.
     fn write_oneof(
         &mut self,
         oneof: &OneofDescriptorProto,
-        fields: &[&FieldDescriptorProto],
+        path: &[i32],
+        fields: &[(usize, &FieldDescriptorProto)],
+        message_path: &[i32],
         syntax: &str,
     ) -> Result<()> {
+        self.write_leading_comments(path);
         self.write_indent();
-        writeln!(self.output, "oneof {} {{", oneof.name())?;
+        write!(self.output, "oneof {} {{", oneof.name())?;
+        self.write_line_end(path);
         self.indent();
 
+        if let Some(options) = oneof.options.as_ref() {
+            self.write_builtin_options(options)?;
+            self.write_features(options)?;
+            self.write_custom_options(options)?;
+        }
+
         let mut sorted_fields = fields.to_vec();
-        sorted_fields.sort_by_key(|f| f.number());
+        if !self.options.preserve_order {
+            sorted_fields
+                .sort_by(|(_, a), (_, b)| (a.number(), a.name()).cmp(&(b.number(), b.name())));
+        }
 
-        for field in sorted_fields {
-            self.write_field(field, syntax)?;
+        for (index, field) in sorted_fields {
+            self.write_field(
+                field,
+                &child_path(message_path, MESSAGE_FIELD, index),
+                syntax,
+            )?;
         }
 
         self.dedent();
@@ -1001,39 +1970,58 @@ impl TextGenerator {
 
     // ========== Enums ==========
 
-    fn write_enums(&mut self, file: &FileDescriptorProto) -> Result<()> {
-        let mut enums = file.enum_type.clone();
+    fn write_enums(&mut self, file: &FileDescriptorProto, out: &mut dyn Write) -> Result<()> {
+        let mut enums: Vec<_> = file.enum_type.iter().enumerate().collect();
 
         if self.options.sort_enums {
-            enums.sort_by(|a, b| a.name().cmp(b.name()));
+            enums.sort_by(|(_, a), (_, b)| a.name().cmp(b.name()));
         }
 
-        for enum_type in enums.iter() {
-            self.write_enum(enum_type)?;
+        for (index, enum_type) in enums {
+            self.write_enum(enum_type, &[FILE_ENUM_TYPE, index as i32])?;
             self.write_newline();
+            self.flush(out)?;
         }
 
         Ok(())
     }
 
-    fn write_enum(&mut self, enum_type: &EnumDescriptorProto) -> Result<()> {
+    fn write_enum(&mut self, enum_type: &EnumDescriptorProto, path: &[i32]) -> Result<()> {
+        self.write_leading_comments(path);
         self.write_indent();
-        writeln!(self.output, "enum {} {{", enum_type.name())?;
+        write!(self.output, "enum {} {{", enum_type.name())?;
+        self.write_line_end(path);
         self.indent();
 
         // Enum options
-        self.write_enum_options(enum_type)?;
+        let mut previous = None;
+        let start = self.output.len();
+        if self.options.style.option_placement == OptionPlacement::First {
+            self.write_enum_options(enum_type)?;
+        }
+        self.separate_declarations(start, true, &mut previous);
 
-        // Enum values - sorted by number for determinism
-        let mut values = enum_type.value.clone();
-        values.sort_by_key(|v| v.number());
+        // Enum values - sorted by number, then name for aliases sharing a
+        // number, for determinism
+        let mut values: Vec<_> = enum_type.value.iter().enumerate().collect();
+        if !self.options.preserve_order {
+            values.sort_by(|(_, a), (_, b)| (a.number(), a.name()).cmp(&(b.number(), b.name())));
+        }
 
-        for value in values.iter() {
-            self.write_enum_value(value)?;
+        let start = self.output.len();
+        for (index, value) in values {
+            self.write_enum_value(value, &child_path(path, ENUM_VALUE, index))?;
         }
 
         // Reserved
         self.write_enum_reserved(enum_type)?;
+        self.separate_declarations(start, false, &mut previous);
+
+        let start = self.output.len();
+        if self.options.style.option_placement == OptionPlacement::Last {
+            self.write_enum_options(enum_type)?;
+        }
+        self.separate_declarations(start, true, &mut previous);
 
         self.dedent();
         self.write_indent();
@@ -1056,98 +2044,124 @@ impl TextGenerator {
                     writeln!(self.output, "option deprecated = true;")?;
                 }
             }
+            self.write_builtin_options(options)?;
+            self.write_features(options)?;
+            self.write_custom_options(options)?;
         }
         Ok(())
     }
 
-    fn write_enum_value(&mut self, value: &EnumValueDescriptorProto) -> Result<()> {
+    fn write_enum_value(&mut self, value: &EnumValueDescriptorProto, path: &[i32]) -> Result<()> {
+        self.write_leading_comments(path);
         self.write_indent();
         write!(self.output, "{} = {}", value.name(), value.number())?;
 
         // Value options
         if let Some(options) = value.options.as_ref() {
+            let mut opts = Vec::new();
             if let Some(val) = options.deprecated {
                 if val {
-                    write!(self.output, " [deprecated = true]")?;
+                    opts.push("deprecated = true".to_string());
                 }
             }
+            opts.extend(self.builtin_options(options)?);
+            opts.extend(editions::features(options)?);
+            opts.extend(self.custom_options(options)?);
+            self.write_option_list(&opts)?;
         }
 
-        writeln!(self.output, ";")?;
+        write!(self.output, ";")?;
+        self.write_line_end(path);
         Ok(())
     }
 
     fn write_enum_reserved(&mut self, enum_type: &EnumDescriptorProto) -> Result<()> {
-        // Reserved ranges (end is inclusive for enums)
-        if !enum_type.reserved_range.is_empty() {
-            self.write_indent();
-            write!(self.output, "reserved ")?;
-            for (i, range) in enum_type.reserved_range.iter().enumerate() {
-                if i > 0 {
-                    write!(self.output, ", ")?;
-                }
+        // Reserved ranges (end is inclusive for enums), sorted by start then
+        // end
+        let mut ranges: Vec<_> = enum_type.reserved_range.iter().collect();
+        if !self.options.preserve_order {
+            ranges.sort_by_key(|range| (range.start(), range.end()));
+        }
+        let ranges: Vec<_> = ranges
+            .into_iter()
+            .map(|range| {
                 if range.start() == range.end() {
-                    write!(self.output, "{}", range.start())?;
-                } else {
+                    range.start().to_string()
+                } else if range.end() == 536870911 {
                     // For enums, max field number is also 536870911
-                    if range.end() == 536870911 {
-                        write!(self.output, "{} to max", range.start())?;
-                    } else {
-                        write!(self.output, "{} to {}", range.start(), range.end())?;
-                    }
+                    format!("{} to max", range.start())
+                } else {
+                    format!("{} to {}", range.start(), range.end())
                 }
-            }
-            writeln!(self.output, ";")?;
-        }
+            })
+            .collect();
+        self.write_reserved_list(&ranges)?;
 
-        // Reserved names
-        if !enum_type.reserved_name.is_empty() {
-            self.write_indent();
-            write!(self.output, "reserved ")?;
-            for (i, name) in enum_type.reserved_name.iter().enumerate() {
-                if i > 0 {
-                    write!(self.output, ", ")?;
-                }
-                write!(self.output, "\"{name}\"")?;
-            }
-            writeln!(self.output, ";")?;
+        // Reserved names, sorted
+        let mut names: Vec<_> = enum_type.reserved_name.iter().collect();
+        if !self.options.preserve_order {
+            names.sort();
         }
-
-        Ok(())
+        let names: Vec<_> = names
+            .into_iter()
+            .map(|name| format!("\"{name}\""))
+            .collect();
+        self.write_reserved_list(&names)
     }
 
     // ========== Services ==========
 
-    fn write_services(&mut self, file: &FileDescriptorProto) -> Result<()> {
-        let mut services = file.service.clone();
+    fn write_services(&mut self, file: &FileDescriptorProto, out: &mut dyn Write) -> Result<()> {
+        let mut services: Vec<_> = file.service.iter().enumerate().collect();
 
         if self.options.sort_services {
-            services.sort_by(|a, b| a.name().cmp(b.name()));
+            services.sort_by(|(_, a), (_, b)| a.name().cmp(b.name()));
         }
 
-        for service in services.iter() {
-            self.write_service(service)?;
+        for (index, service) in services {
+            self.write_service(service, &[FILE_SERVICE, index as i32])?;
             self.write_newline();
+            self.flush(out)?;
         }
 
         Ok(())
     }
 
-    fn write_service(&mut self, service: &ServiceDescriptorProto) -> Result<()> {
+    fn write_service(&mut self, service: &ServiceDescriptorProto, path: &[i32]) -> Result<()> {
+        self.write_leading_comments(path);
         self.write_indent();
-        writeln!(self.output, "service {} {{", service.name())?;
+        write!(self.output, "service {} {{", service.name())?;
+        self.write_line_end(path);
         self.indent();
 
         // Service options
-        self.write_service_options(service)?;
+        let mut previous = None;
+        let start = self.output.len();
+        if self.options.style.option_placement == OptionPlacement::First {
+            self.write_service_options(service)?;
+        }
+        self.separate_declarations(start, true, &mut previous);
 
         // Methods - sorted by name for determinism
-        let mut methods = service.method.clone();
-        methods.sort_by(|a, b| a.name().cmp(b.name()));
+        let mut methods: Vec<_> = service.method.iter().enumerate().collect();
+        if !self.options.preserve_order {
+            methods.sort_by(|(_, a), (_, b)| a.name().cmp(b.name()));
+        }
+
+        let scope = format!("{}.{}", self.scope, service.name());
+        let saved_scope = std::mem::replace(&mut self.scope, scope);
+        let start = self.output.len();
+        for (index, method) in methods {
+            self.write_method(method, &child_path(path, SERVICE_METHOD, index))?;
+        }
+        self.scope = saved_scope;
+        self.separate_declarations(start, false, &mut previous);
 
-        for method in methods.iter() {
-            self.write_method(method)?;
+        let start = self.output.len();
+        if self.options.style.option_placement == OptionPlacement::Last {
+            self.write_service_options(service)?;
         }
+        self.separate_declarations(start, true, &mut previous);
 
         self.dedent();
         self.write_indent();
@@ -1164,11 +2178,15 @@ impl TextGenerator {
                     writeln!(self.output, "option deprecated = true;")?;
                 }
             }
+            self.write_builtin_options(options)?;
+            self.write_features(options)?;
+            self.write_custom_options(options)?;
         }
         Ok(())
     }
 
-    fn write_method(&mut self, method: &MethodDescriptorProto) -> Result<()> {
+    fn write_method(&mut self, method: &MethodDescriptorProto, path: &[i32]) -> Result<()> {
+        self.write_leading_comments(path);
         self.write_indent();
         write!(self.output, "rpc {}", method.name())?;
 
@@ -1178,82 +2196,294 @@ impl TextGenerator {
             write!(self.output, "stream ")?;
         }
         if let Some(input_type) = method.input_type.as_ref() {
-            write!(self.output, "{}", self.format_type_name(input_type))?;
+            write!(self.output, "{}", self.format_symbol_name(input_type))?;
         }
         write!(self.output, ")")?;
 
-        // Return type
-        write!(self.output, " returns (")?;
+        // Return type, on its own line if the signature is too long
+        let mut returns = "returns (".to_string();
         if method.server_streaming() {
-            write!(self.output, "stream ")?;
+            returns.push_str("stream ");
         }
         if let Some(output_type) = method.output_type.as_ref() {
-            write!(self.output, "{}", self.format_type_name(output_type))?;
+            returns.push_str(&self.format_symbol_name(output_type));
+        }
+        returns.push(')');
+        // Followed by at least ";"
+        if self.fits(1 + returns.chars().count() + 1) {
+            write!(self.output, " {returns}")?;
+        } else {
+            self.write_newline();
+            self.indent();
+            self.write_indent();
+            self.dedent();
+            write!(self.output, "{returns}")?;
         }
-        write!(self.output, ")")?;
 
         // Method options
         if let Some(options) = method.options.as_ref() {
+            let mut opts = Vec::new();
             if let Some(val) = options.deprecated {
                 if val {
-                    write!(self.output, " {{")?;
-                    self.write_newline();
-                    self.indent();
-                    self.write_indent();
-                    writeln!(self.output, "option deprecated = true;")?;
-                    self.dedent();
+                    opts.push("deprecated = true".to_string());
+                }
+            }
+            if let Some(val) = options.idempotency_level {
+                let level = match val.enum_value() {
+                    Ok(level) => format!("{level:?}"),
+                    Err(number) => number.to_string(),
+                };
+                opts.push(format!("idempotency_level = {level}"));
+            }
+            opts.extend(self.builtin_options(options)?);
+            opts.extend(editions::features(options)?);
+            opts.extend(self.custom_options(options)?);
+            if !opts.is_empty() {
+                write!(self.output, " {{")?;
+                self.write_line_end(path);
+                self.indent();
+                for opt in &opts {
                     self.write_indent();
-                    write!(self.output, "}}")?;
+                    writeln!(self.output, "option {opt};")?;
                 }
+                self.dedent();
+                self.write_indent();
+                write!(self.output, "}};")?;
+                self.write_newline();
+                return Ok(());
             }
         }
 
-        writeln!(self.output, ";")?;
+        write!(self.output, ";")?;
+        self.write_line_end(path);
         Ok(())
     }
 
     // ========== Extensions ==========
 
-    fn write_extensions(&mut self, file: &FileDescriptorProto, syntax: &str) -> Result<()> {
-        use std::collections::BTreeMap;
+    fn write_extensions(
+        &mut self,
+        file: &FileDescriptorProto,
+        syntax: &str,
+        out: &mut dyn Write,
+    ) -> Result<()> {
         // Group by extendee
-        let mut groups: BTreeMap<String, Vec<&FieldDescriptorProto>> = BTreeMap::new();
-        for ext in &file.extension {
+        let mut groups: BTreeMap<String, Vec<(usize, &FieldDescriptorProto)>> = BTreeMap::new();
+        for (index, ext) in file.extension.iter().enumerate() {
             let key = ext.extendee.clone().unwrap_or_default();
-            groups.entry(key).or_default().push(ext);
+            groups.entry(key).or_default().push((index, ext));
         }
         for (extendee, mut fields) in groups {
-            // Skip empty extendee
-            let extendee_fmt = self.format_type_name(&extendee);
-            self.write_indent();
-            writeln!(self.output, "extend {extendee_fmt} {{")?;
-            self.indent();
             // Sort by number for determinism
-            fields.sort_by_key(|f| f.number());
-            for f in fields {
-                self.write_field(f, syntax)?;
+            fields.sort_by(|(_, a), (_, b)| (a.number(), a.name()).cmp(&(b.number(), b.name())));
+            let fields: Vec<_> = fields
+                .into_iter()
+                .map(|(index, f)| (vec![FILE_EXTENSION, index as i32], f))
+                .collect();
+            self.write_extend(&extendee, &fields, syntax)?;
+            if self.options.style.blank_lines == BlankLines::Blocks {
+                self.write_newline();
             }
-            self.dedent();
-            self.write_indent();
-            writeln!(self.output, "}}")?;
+            self.flush(out)?;
+        }
+        Ok(())
+    }
+
+    /// An `extend` block with `extensions` of `extendee`, each with its
+    /// location path.
+    fn write_extend(
+        &mut self,
+        extendee: &str,
+        extensions: &[(Vec<i32>, &FieldDescriptorProto)],
+        syntax: &str,
+    ) -> Result<()> {
+        self.write_indent();
+        writeln!(
+            self.output,
+            "extend {} {{",
+            self.format_symbol_name(extendee)
+        )?;
+        self.indent();
+        for (path, extension) in extensions {
+            self.write_field(extension, path, syntax)?;
         }
+        self.dedent();
+        self.write_indent();
+        writeln!(self.output, "}}")?;
         Ok(())
     }
 }
 
+/// [`Write`] adapter for an [`std::io::Write`] sink, keeping the I/O error
+/// that [`std::fmt::Error`] cannot carry.
+struct IoSink<'a, W: ?Sized> {
+    inner: &'a mut W,
+    error: Option<std::io::Error>,
+}
+
+impl<W: std::io::Write + ?Sized> Write for IoSink<'_, W> {
+    fn write_str(&mut self, s: &str) -> std::fmt::Result {
+        self.inner.write_all(s.as_bytes()).map_err(|error| {
+            self.error = Some(error);
+            std::fmt::Error
+        })
+    }
+}
+
+/// JSON name `protoc` derives from a field name: underscores removed and the
+/// letter following each underscore capitalized (`foo_bar` -> `fooBar`).
+fn default_json_name(name: &str) -> String {
+    let mut json_name = String::with_capacity(name.len());
+    let mut capitalize_next = false;
+    for ch in name.chars() {
+        if ch == '_' {
+            capitalize_next = true;
+        } else if capitalize_next {
+            json_name.push(ch.to_ascii_uppercase());
+            capitalize_next = false;
+        } else {
+            json_name.push(ch);
+        }
+    }
+    json_name
+}
+
+/// Clear every `json_name` of `file` that `protoc` would derive anyway.
+fn clear_default_json_names(file: &mut FileDescriptorProto) {
+    fn clear_fields(fields: &mut [FieldDescriptorProto]) {
+        for field in fields {
+            if field.json_name.as_deref() == Some(default_json_name(field.name()).as_str()) {
+                field.json_name = None;
+            }
+        }
+    }
+    fn clear_messages(messages: &mut [DescriptorProto]) {
+        for message in messages {
+            clear_fields(&mut message.field);
+            clear_fields(&mut message.extension);
+            clear_messages(&mut message.nested_type);
+        }
+    }
+    clear_fields(&mut file.extension);
+    clear_messages(&mut file.message_type);
+}
+
+/// `path` extended with the element at `index` of the field `kind`.
+fn child_path(path: &[i32], kind: i32, index: usize) -> Vec<i32> {
+    let mut child = path.to_vec();
+    child.extend([kind, index as i32]);
+    child
+}
+
+/// A file or message, as the scope of the definitions declared in it.
+struct DefinitionScope<'a> {
+    /// Location path, empty for the file
+    path: Vec<i32>,
+    /// Full name with a leading dot; for the file, its package
+    name: String,
+    /// The message, or `None` for the file
+    message: Option<&'a DescriptorProto>,
+}
+
+impl DefinitionScope<'_> {
+    fn file(file: &FileDescriptorProto) -> Self {
+        Self {
+            path: Vec::new(),
+            name: match file.package() {
+                "" => String::new(),
+                package => format!(".{package}"),
+            },
+            message: None,
+        }
+    }
+}
+
+/// `file` and every message of `file`, nested ones included.
+fn definition_scopes(file: &FileDescriptorProto) -> Vec<DefinitionScope<'_>> {
+    fn add_messages<'a>(
+        scopes: &mut Vec<DefinitionScope<'a>>,
+        parent: &DefinitionScope,
+        kind: i32,
+        messages: &'a [DescriptorProto],
+    ) {
+        for (index, message) in messages.iter().enumerate() {
+            let scope = DefinitionScope {
+                path: child_path(&parent.path, kind, index),
+                name: format!("{}.{}", parent.name, message.name()),
+                message: Some(message),
+            };
+            add_messages(scopes, &scope, MESSAGE_NESTED_TYPE, &message.nested_type);
+            scopes.push(scope);
+        }
+    }
+
+    let root = DefinitionScope::file(file);
+    let mut scopes = Vec::new();
+    add_messages(&mut scopes, &root, FILE_MESSAGE_TYPE, &file.message_type);
+    scopes.insert(0, root);
+    scopes
+}
+
+/// `name` with a leading dot.
+fn qualified_name(name: &str) -> String {
+    match name.strip_prefix('.') {
+        Some(_) => name.to_string(),
+        None => format!(".{name}"),
+    }
+}
+
 /// Convenience function to convert a FileDescriptorProto to proto text.
+///
+/// Fails if the file uses custom options declared in another file; use
+/// [`descriptor_to_proto_with_imports`] for those.
 pub fn descriptor_to_proto(file: &FileDescriptorProto) -> Result<String> {
     let mut generator = TextGenerator::with_default();
     generator.format_file(file)
 }
 
+/// Convert a FileDescriptorProto to proto text, looking up the custom
+/// options it uses in `imports`.
+pub fn descriptor_to_proto_with_imports(
+    file: &FileDescriptorProto,
+    imports: &[FileDescriptorProto],
+) -> Result<String> {
+    let mut generator = TextGenerator::with_default().with_imports(imports.iter().cloned());
+    generator.format_file(file)
+}
+
+/// Check that the text of `file` parses back to the same descriptor.
+///
+/// Returns every lost or altered element; see
+/// [`TextGenerator::verify_roundtrip`].
+pub fn verify_roundtrip(file: &FileDescriptorProto) -> Result<Vec<DescriptorDifference>> {
+    TextGenerator::with_default().verify_roundtrip(file)
+}
+
+/// Convert every file of a serialized `FileDescriptorSet` to proto text.
+///
+/// Returns `(file name, proto text)` pairs in the order of the set. Custom
+/// options are looked up in all files of the set.
+pub fn descriptor_set_to_proto(bytes: &[u8]) -> Result<Vec<(String, String)>> {
+    let set = crate::decode_descriptor_set(bytes)?;
+    let mut generator = TextGenerator::with_default().with_imports(set.file.iter().cloned());
+    set.file
+        .iter()
+        .map(|file| {
+            let text = generator
+                .format_file(file)
+                .with_context(|| format!("Failed to generate {}", file.name()))?;
+            Ok((file.name().to_string(), text))
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
     fn test_version_constant() {
-        assert_eq!(TEXT_GENERATOR_VERSION, "1.0.0");
+        assert_eq!(TEXT_GENERATOR_VERSION, "1.10.0");
     }
 
     #[test]
@@ -1411,4 +2641,996 @@ message B { string x = 1; }
         let b_pos = result1.find("message B").unwrap();
         assert!(a_pos < b_pos);
     }
+
+    #[test]
+    fn test_editions_round_trip() {
+        use crate::parse_proto_to_file_descriptor;
+
+        let proto = r#"
+edition = "2023";
+package demo;
+
+option features.field_presence = IMPLICIT;
+option java_package = "com.demo";
+
+message User {
+  option features.message_encoding = DELIMITED;
+  string name = 1;
+  int32 id = 2 [features.field_presence = EXPLICIT, deprecated = true];
+  repeated int32 tags = 3;
+  oneof contact {
+    option features.utf8_validation = NONE;
+    string email = 5;
+  }
+}
+
+enum Status {
+  option features.enum_type = CLOSED;
+  STATUS_UNKNOWN = 0;
+  STATUS_ACTIVE = 1 [features.enum_type = OPEN];
+}
+
+service Users {
+  rpc Get(User) returns (User) {
+    option features.json_format = ALLOW;
+  }
+}
+"#;
+
+        let result = descriptor_to_proto(&parse_proto_to_file_descriptor(proto).unwrap()).unwrap();
+        assert!(result.starts_with("edition = \"2023\";\n"));
+        assert!(result.contains("option features.field_presence = IMPLICIT;"));
+        assert!(result.contains("  option features.message_encoding = DELIMITED;"));
+        assert!(result.contains("  string name = 1;"));
+        assert!(result
+            .contains("  int32 id = 2 [deprecated = true, features.field_presence = EXPLICIT];"));
+        assert!(result.contains("    option features.utf8_validation = NONE;"));
+        assert!(result.contains("  STATUS_ACTIVE = 1 [features.enum_type = OPEN];"));
+        assert!(result.contains("    option features.json_format = ALLOW;"));
+        assert!(!result.contains("optional"));
+
+        // The output parses back to the same descriptor text
+        let reparsed =
+            descriptor_to_proto(&parse_proto_to_file_descriptor(result.as_str()).unwrap()).unwrap();
+        assert_eq!(result, reparsed);
+    }
+
+    const CUSTOM_OPTIONS: &str = r#"
+syntax = "proto2";
+package my;
+import "google/protobuf/descriptor.proto";
+
+message Rule {
+  optional string a = 1;
+  optional int32 b = 2;
+  optional Rule nested = 3;
+  optional Level level = 4;
+}
+enum Level { LOW = 0; HIGH = 1; }
+
+extend google.protobuf.FileOptions { optional string owner = 50000; }
+extend google.protobuf.MessageOptions { optional Level msg_level = 50000; }
+extend google.protobuf.FieldOptions {
+  optional Rule rule = 50000;
+  repeated string tags = 50001;
+  optional sint32 delta = 50002;
+}
+extend google.protobuf.OneofOptions { optional int32 oneof_id = 50000; }
+extend google.protobuf.EnumOptions { optional bool closed = 50000; }
+extend google.protobuf.EnumValueOptions { optional string label = 50000; }
+extend google.protobuf.ServiceOptions { optional string host = 50000; }
+extend google.protobuf.MethodOptions { optional Rule method_rule = 50000; }
+"#;
+
+    #[test]
+    fn test_custom_options_round_trip() {
+        use crate::ParseSession;
+
+        let proto = r#"
+syntax = "proto3";
+package app;
+import "my/options.proto";
+
+option (my.owner) = "team";
+
+message User {
+  option (my.msg_level) = HIGH;
+  string name = 1 [(my.tags) = "b", (my.tags) = "a", (my.rule) = { a: "x" nested { b: 1 level: HIGH } }];
+  int32 age = 2 [(my.rule).b = 7, (my.rule).a = "y", (my.delta) = -3];
+  oneof contact {
+    option (my.oneof_id) = 9;
+    string email = 3;
+  }
+}
+
+enum Status {
+  option (my.closed) = true;
+  STATUS_UNKNOWN = 0 [(my.label) = "unknown"];
+}
+
+service Users {
+  option (my.host) = "users.example.com";
+  rpc Get(User) returns (User) {
+    option (my.method_rule) = { a: "get" };
+  }
+}
+"#;
+        let session = ParseSession::new().with_files([("my/options.proto", CUSTOM_OPTIONS)]);
+        let (descriptor, imports) = session.parse_with_imports(proto).unwrap();
+        let result = descriptor_to_proto_with_imports(&descriptor, &imports).unwrap();
+
+        assert!(result.contains("option (my.owner) = \"team\";"));
+        assert!(result.contains("  option (my.msg_level) = HIGH;"));
+        assert!(result.contains(
+            "  string name = 1 [(my.rule) = { a: \"x\" nested { b: 1 level: HIGH } }, \
+             (my.tags) = \"b\", (my.tags) = \"a\"];"
+        ));
+        assert!(
+            result.contains("  int32 age = 2 [(my.delta) = -3, (my.rule) = { a: \"y\" b: 7 }];")
+        );
+        assert!(result.contains("    option (my.oneof_id) = 9;"));
+        assert!(result.contains("  option (my.closed) = true;"));
+        assert!(result.contains("  STATUS_UNKNOWN = 0 [(my.label) = \"unknown\"];"));
+        assert!(result.contains("  option (my.host) = \"users.example.com\";"));
+        assert!(result.contains("    option (my.method_rule) = { a: \"get\" };"));
+
+        let (reparsed, imports) = session.parse_with_imports(&result).unwrap();
+        assert_eq!(
+            result,
+            descriptor_to_proto_with_imports(&reparsed, &imports).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_custom_options_affect_output() {
+        use crate::ParseSession;
+
+        let session = ParseSession::new().with_files([("my/options.proto", CUSTOM_OPTIONS)]);
+        let render = |annotation: &str| {
+            let proto = format!(
+                "syntax = \"proto3\";\nimport \"my/options.proto\";\nmessage M {{ string f = 1{annotation}; }}\n"
+            );
+            let (descriptor, imports) = session.parse_with_imports(&proto).unwrap();
+            descriptor_to_proto_with_imports(&descriptor, &imports).unwrap()
+        };
+        let plain = render("");
+        let annotated = render(" [(my.rule).a = \"x\"]");
+        assert_ne!(plain, annotated);
+        assert_ne!(annotated, render(" [(my.rule).a = \"y\"]"));
+
+        // Without the declaring file the option is skipped, or fails in
+        // strict mode
+        let (descriptor, _) = session
+            .parse_with_imports(
+                "syntax = \"proto3\";\nimport \"my/options.proto\";\noption (my.owner) = \"x\";\n",
+            )
+            .unwrap();
+        let result = descriptor_to_proto(&descriptor).unwrap();
+        assert!(!result.contains("my.owner"));
+        assert!(result.contains("import \"my/options.proto\";"));
+        let strict = TextGeneratorOptions {
+            strict_custom_options: true,
+            ..Default::default()
+        };
+        let err = TextGenerator::new(strict)
+            .format_file(&descriptor)
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("Cannot render custom option 50000"));
+    }
+
+    #[test]
+    fn test_comments_round_trip() {
+        use crate::ParseSession;
+
+        // Already canonical, so the output must reproduce it exactly
+        let proto = r#"// Copyright header
+
+// The syntax
+syntax = "proto3"; // trailing syntax
+
+package app; // trailing package
+
+// Shared types
+import "common.proto";
+
+// A user.
+// Second line.
+message User { // opens user
+  // Nested kind
+  enum Kind {
+    // Unknown kind
+    KIND_UNKNOWN = 0; // trailing value
+  }
+  // Detached inside user
+
+  // The name
+  string name = 1; // trailing name
+  // A choice
+  oneof contact {
+    // Email address
+    string email = 3;
+  }
+}
+
+// Service
+service Users {
+  // Gets a user
+  rpc Get(app.User) returns (app.User); // trailing rpc
+}
+
+"#;
+        let session = ParseSession::lenient();
+        let generate = |source: &str| {
+            let descriptor = session.parse(source).unwrap();
+            let options = TextGeneratorOptions {
+                include_comments: true,
+                ..Default::default()
+            };
+            TextGenerator::new(options)
+                .format_file(&descriptor)
+                .unwrap()
+        };
+        assert_eq!(generate(proto), proto);
+
+        // Groups and extensions
+        let result = generate(
+            "syntax = \"proto2\";\nmessage M {\n  optional group G = 1 {\n    // Group field\n    \
+             optional int32 a = 1;\n  }\n  extensions 100 to 200;\n}\n\
+             extend M {\n  // Extension\n  optional int32 b = 100; // trailing\n}\n",
+        );
+        assert!(result
+            .contains("  optional group G = 1 {\n    // Group field\n    optional int32 a = 1;\n"));
+        assert!(
+            result.contains("extend M {\n  // Extension\n  optional int32 b = 100; // trailing\n")
+        );
+
+        // Block comments are rewritten as line comments
+        let source = "/**\n * Doc\n * comment\n */\nmessage M { /* inline */ }\n";
+        assert!(generate(source).contains("// Doc\n// comment\nmessage M { // inline\n"));
+
+        // Comments are off by default
+        let descriptor = session.parse(source).unwrap();
+        assert!(!descriptor_to_proto(&descriptor).unwrap().contains("//"));
+    }
+
+    #[test]
+    fn test_json_name() {
+        let proto = "syntax = \"proto3\";\n\nmessage M {\n  \
+                     string foo_bar = 1 [json_name = \"fooBAR\"];\n  \
+                     string baz_qux = 2 [json_name = \"bazQux\"];\n  \
+                     map<string, int32> tags = 3 [json_name = \"labels\"];\n}\n\n";
+        let descriptor = crate::parse_proto_to_file_descriptor(proto).unwrap();
+        let result = descriptor_to_proto(&descriptor).unwrap();
+        assert_eq!(result, proto.replace(" [json_name = \"bazQux\"]", ""));
+
+        // json_name changes the JSON mapping, and with it the fingerprint
+        let fingerprint = |json_name: &str| {
+            crate::generate_fingerprint(&format!(
+                "syntax = \"proto3\";\nmessage M {{ string foo_bar = 1{json_name}; }}\n"
+            ))
+            .unwrap()
+        };
+        assert_eq!(fingerprint(""), fingerprint(" [json_name = \"fooBar\"]"));
+        assert_ne!(fingerprint(""), fingerprint(" [json_name = \"fooBAR\"]"));
+    }
+
+    #[test]
+    fn test_preserve_order() {
+        // Formatted, but neither sorted nor grouped by kind
+        let proto = r#"syntax = "proto2";
+
+package app;
+
+import "z.proto";
+import "a.proto";
+
+service Users {
+  rpc List(app.User) returns (app.User);
+  rpc Get(app.User) returns (app.User);
+}
+
+extend app.User {
+  optional int32 b = 101;
+}
+
+message User {
+  optional string name = 2;
+  message Address {
+    optional string city = 1;
+  }
+  optional int32 id = 1;
+  enum Kind {
+    KIND_B = 1;
+    KIND_A = 0;
+  }
+  extensions 100 to 200;
+}
+
+extend app.User {
+  optional int32 a = 100;
+}
+
+enum Status {
+  STATUS_OK = 1;
+  STATUS_UNKNOWN = 0;
+}
+
+"#;
+        let mut descriptor = crate::ParseSession::lenient().parse(proto).unwrap();
+        let options = TextGeneratorOptions {
+            preserve_order: true,
+            ..Default::default()
+        };
+        let result = TextGenerator::new(options.clone())
+            .format_file(&descriptor)
+            .unwrap();
+        assert_eq!(result, proto);
+        assert_ne!(descriptor_to_proto(&descriptor).unwrap(), proto);
+
+        // Without source info each kind keeps its declaration order
+        descriptor.source_code_info.clear();
+        let result = TextGenerator::new(options)
+            .format_file(&descriptor)
+            .unwrap();
+        assert!(result.contains(
+            "message User {\n  enum Kind {\n    KIND_B = 1;\n    KIND_A = 0;\n  }\n  \
+             message Address {\n    optional string city = 1;\n  }\n  \
+             optional string name = 2;\n  optional int32 id = 1;\n"
+        ));
+        assert!(result.contains("rpc List(app.User) returns (app.User);\n  rpc Get"));
+        let position = |needle: &str| result.find(needle).unwrap();
+        assert!(position("message User") < position("enum Status"));
+        assert!(position("enum Status") < position("service Users"));
+    }
+
+    #[test]
+    fn test_nested_extensions() {
+        let proto = r#"syntax = "proto2";
+
+package pkg;
+
+message Base {
+  extensions 100 to 199;
+}
+
+message Holder {
+  optional int32 id = 1;
+  extend pkg.Base {
+    optional int32 count = 101;
+    optional string name = 102;
+  }
+  extend pkg.Other {
+    repeated pkg.Holder holders = 100;
+  }
+}
+
+message Other {
+  extensions 100 to 199;
+}
+"#;
+        let mut descriptor = crate::parse_proto_to_file_descriptor(proto).unwrap();
+        let result = descriptor_to_proto(&descriptor).unwrap();
+        assert_eq!(result, format!("{proto}\n"));
+
+        // The output parses back to the same descriptor
+        let mut reparsed = crate::parse_proto_to_file_descriptor(result.as_str()).unwrap();
+        descriptor.source_code_info.clear();
+        reparsed.source_code_info.clear();
+        assert_eq!(reparsed, descriptor);
+
+        // The pure parser puts the type of a group extension in the wrong
+        // scope, so add one by hand: its type is nested in the message
+        // declaring the extension
+        let holder = &mut descriptor.message_type[1];
+        let mut group = DescriptorProto::new();
+        group.set_name("Extra".to_string());
+        group.field.push(holder.extension[1].clone());
+        group.field[0].extendee = None;
+        group.field[0].set_number(1);
+        holder.nested_type.push(group);
+        let mut extension = holder.extension[0].clone();
+        extension.set_name("extra".to_string());
+        extension.json_name = None;
+        extension.set_number(100);
+        extension.set_type(Type::TYPE_GROUP);
+        extension.set_type_name(".pkg.Holder.Extra".to_string());
+        holder.extension.push(extension);
+        let result = descriptor_to_proto(&descriptor).unwrap();
+        assert!(result.contains(
+            "  extend pkg.Base {\n    optional group Extra = 100 {\n      \
+             optional string name = 1;\n    }\n    optional int32 count = 101;\n"
+        ));
+        assert!(!result.contains("message Extra"));
+    }
+
+    #[test]
+    fn test_top_level_extension_group() {
+        let proto = r#"syntax = "proto2";
+
+package pkg;
+
+message M {
+  extensions 100 to 199;
+}
+
+extend pkg.M {
+  // Extra data
+  optional group G = 100 {
+    // Count of things
+    optional int32 a = 1;
+  }
+}
+
+"#;
+        let descriptor = crate::ParseSession::lenient().parse(proto).unwrap();
+        // The group type is a top-level message, rendered only inside the group
+        assert_eq!(descriptor.message_type[1].name(), "G");
+        let result = descriptor_to_proto(&descriptor).unwrap();
+        assert!(result.contains(
+            "extend pkg.M {\n  optional group G = 100 {\n    optional int32 a = 1;\n  }\n}\n"
+        ));
+        assert!(!result.contains("message G"));
+
+        let options = TextGeneratorOptions {
+            preserve_order: true,
+            include_comments: true,
+            ..Default::default()
+        };
+        let result = TextGenerator::new(options)
+            .format_file(&descriptor)
+            .unwrap();
+        assert_eq!(result, proto);
+        assert_eq!(verify_roundtrip(&descriptor).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_verify_roundtrip() {
+        let proto = r#"
+syntax = "proto2";
+package pkg;
+message M {
+  optional string foo_bar = 1 [default = "x", json_name = "custom"];
+  oneof kind { int32 a = 2; }
+  extensions 100 to 199;
+}
+extend M { optional int32 ext = 100; }
+enum E { E_A = 0; }
+service S { rpc Call(M) returns (M); }
+"#;
+        let mut descriptor = crate::parse_proto_to_file_descriptor(proto).unwrap();
+        assert_eq!(verify_roundtrip(&descriptor).unwrap(), vec![]);
+
+        // Oneofs are sorted by name, which changes their indices
+        let corpus = crate::parse_proto_to_file_descriptor(include_str!(
+            "../tests/data/edge_cases/complex_combinations.proto"
+        ))
+        .unwrap();
+        assert_eq!(verify_roundtrip(&corpus).unwrap(), vec![]);
+
+        // Oneofs without fields are not written
+        let mut oneof = OneofDescriptorProto::new();
+        oneof.set_name("empty".to_string());
+        descriptor.message_type[0].oneof_decl.push(oneof);
+        let differences = verify_roundtrip(&descriptor).unwrap();
+        assert_eq!(differences.len(), 1, "{differences:?}");
+        assert_eq!(differences[0].path, "message_type[M].oneof_decl[empty]");
+        assert_eq!(differences[0].kind, crate::DifferenceKind::Missing);
+    }
+
+    #[test]
+    fn test_format_file_to_sinks() {
+        let proto =
+            "syntax = \"proto3\";\npackage p;\nmessage A {}\nmessage B {}\nenum E { E_0 = 0; }\n";
+        let descriptor = crate::parse_proto_to_file_descriptor(proto).unwrap();
+        let expected = descriptor_to_proto(&descriptor).unwrap();
+
+        /// Records every chunk it receives.
+        #[derive(Default)]
+        struct Chunks(Vec<String>);
+        impl Write for Chunks {
+            fn write_str(&mut self, s: &str) -> std::fmt::Result {
+                self.0.push(s.to_string());
+                Ok(())
+            }
+        }
+        let mut chunks = Chunks::default();
+        let mut generator = TextGenerator::with_default();
+        generator.format_file_to(&descriptor, &mut chunks).unwrap();
+        // Header, then one chunk per top-level definition
+        assert_eq!(chunks.0.len(), 4);
+        assert_eq!(chunks.0.concat(), expected);
+
+        let mut bytes = Vec::new();
+        generator
+            .format_file_to_io(&descriptor, &mut bytes)
+            .unwrap();
+        assert_eq!(String::from_utf8(bytes).unwrap(), expected);
+
+        // I/O errors are surfaced
+        let mut full = [0u8; 8];
+        let err = generator
+            .format_file_to_io(&descriptor, &mut &mut full[..])
+            .unwrap_err();
+        assert!(err.downcast_ref::<std::io::Error>().is_some());
+    }
+
+    #[test]
+    fn test_max_line_width() {
+        let proto = r#"syntax = "proto2";
+
+package pkg;
+
+message Request {
+  optional int32 a = 1 [default = 5];
+  optional string name = 2 [
+    deprecated = true,
+    default = "anonymous",
+    json_name = "userName"
+  ];
+  reserved 10, 11, 12, 13, 14, 15, 16,
+    17, 18, 19 to 30;
+  reserved "first_name", "last_name",
+    "middle_name";
+}
+
+service Users {
+  rpc Get(Request) returns (Request);
+  rpc StreamEverything(stream Request)
+    returns (stream Request);
+}
+
+"#;
+        let descriptor = crate::parse_proto_to_file_descriptor(proto).unwrap();
+        let options = TextGeneratorOptions {
+            max_line_width: Some(40),
+            relative_type_names: true,
+            ..Default::default()
+        };
+        let result = TextGenerator::new(options)
+            .format_file(&descriptor)
+            .unwrap();
+        assert_eq!(result, proto);
+        assert_eq!(
+            crate::parse_proto_to_file_descriptor(result.as_str()).unwrap(),
+            descriptor
+        );
+
+        // Unlimited by default
+        let result = descriptor_to_proto(&descriptor).unwrap();
+        assert!(result.contains("reserved 10, 11, 12, 13, 14, 15, 16, 17, 18, 19 to 30;\n"));
+    }
+
+    #[test]
+    fn test_nested_declarations_are_sorted() {
+        let ordered = r#"
+syntax = "proto3";
+package p;
+message Outer {
+  enum A { A_0 = 0; }
+  enum B { B_0 = 0; }
+  message Inner {
+    enum C { C_0 = 0; }
+    enum D { D_0 = 0; }
+    message X {}
+    message Y {}
+    oneof first { int32 a = 1; }
+    oneof second { int32 b = 2; }
+  }
+  message Other {}
+  oneof kind { string s = 3; int32 n = 4; }
+  oneof mode { bool m = 5; }
+}
+"#;
+        let shuffled = r#"
+syntax = "proto3";
+package p;
+message Outer {
+  oneof mode { bool m = 5; }
+  message Other {}
+  enum B { B_0 = 0; }
+  oneof kind { int32 n = 4; string s = 3; }
+  message Inner {
+    oneof second { int32 b = 2; }
+    message Y {}
+    enum D { D_0 = 0; }
+    message X {}
+    oneof first { int32 a = 1; }
+    enum C { C_0 = 0; }
+  }
+  enum A { A_0 = 0; }
+}
+"#;
+        let expected =
+            descriptor_to_proto(&crate::parse_proto_to_file_descriptor(ordered).unwrap()).unwrap();
+        let result =
+            descriptor_to_proto(&crate::parse_proto_to_file_descriptor(shuffled).unwrap()).unwrap();
+        assert_eq!(result, expected);
+        assert!(expected.contains(
+            "  message Inner {\n    enum C {\n      C_0 = 0;\n    }\n    enum D {\n      D_0 = 0;\n    }\n    \
+             message X {\n    }\n    message Y {\n    }\n    oneof first {\n"
+        ));
+        assert_eq!(
+            crate::generate_fingerprint(ordered).unwrap(),
+            crate::generate_fingerprint(shuffled).unwrap()
+        );
+
+        // Reversing every nested list of the descriptor changes nothing either
+        fn reverse(message: &mut DescriptorProto) {
+            message.enum_type.reverse();
+            message.nested_type.reverse();
+            message.nested_type.iter_mut().for_each(reverse);
+            let oneofs = message.oneof_decl.len() as i32;
+            message.oneof_decl.reverse();
+            for field in &mut message.field {
+                if let Some(index) = field.oneof_index.as_mut() {
+                    *index = oneofs - 1 - *index;
+                }
+            }
+            message.field.reverse();
+        }
+        let mut descriptor = crate::parse_proto_to_file_descriptor(ordered).unwrap();
+        descriptor.message_type.iter_mut().for_each(reverse);
+        assert_eq!(descriptor_to_proto(&descriptor).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_relative_type_names() {
+        // Nested types, and methods in signatures, shadow outer names
+        let proto = r#"syntax = "proto2";
+
+package com.example;
+
+message Corpus {
+}
+
+message SearchRequest {
+  enum Corpus {
+    WEB = 0;
+  }
+  optional Corpus corpus = 1;
+  optional example.Corpus outer = 2;
+  map<string, SearchRequest> children = 3;
+  extensions 100 to 199;
+}
+
+service Search {
+  rpc Corpus(SearchRequest) returns (example.Corpus);
+}
+
+extend SearchRequest {
+  optional Corpus extra = 100;
+}
+"#;
+        let descriptor = crate::parse_proto_to_file_descriptor(proto).unwrap();
+        let options = TextGeneratorOptions {
+            relative_type_names: true,
+            ..Default::default()
+        };
+        let result = TextGenerator::new(options)
+            .format_file(&descriptor)
+            .unwrap();
+        assert_eq!(result, proto);
+
+        // Fully qualified by default
+        let result = descriptor_to_proto(&descriptor).unwrap();
+        assert!(result.contains("optional com.example.SearchRequest.Corpus corpus = 1;"));
+        assert!(result.contains("optional com.example.Corpus outer = 2;"));
+    }
+
+    #[test]
+    fn test_styles() {
+        let descriptor = crate::parse_proto_to_file_descriptor(
+            r#"
+syntax = "proto2";
+package p;
+service Svc { option deprecated = true; rpc Get(M) returns (M); }
+enum E { option allow_alias = true; A = 0; B = 0; }
+message M {
+  option deprecated = true;
+  message N {}
+  optional N n = 1;
+  reserved 5;
+}
+extend M { optional int32 x = 10; }
+extend M.N { optional int32 y = 10; }
+"#,
+        )
+        .unwrap();
+        let format = |style| {
+            TextGenerator::new(TextGeneratorOptions {
+                style,
+                ..Default::default()
+            })
+            .format_file(&descriptor)
+            .unwrap()
+        };
+
+        assert_eq!(
+            format(Style::canonical()),
+            descriptor_to_proto(&descriptor).unwrap()
+        );
+        assert_eq!(
+            format(Style::google()),
+            r#"syntax = "proto2";
+
+package p;
+
+enum E {
+  option allow_alias = true;
+
+  A = 0;
+  B = 0;
+}
+
+message M {
+  option deprecated = true;
+
+  message N {
+  }
+
+  optional p.M.N n = 1;
+  reserved 5;
+}
+
+service Svc {
+  option deprecated = true;
+
+  rpc Get(p.M) returns (p.M);
+}
+
+extend p.M {
+  optional int32 x = 10;
+}
+
+extend p.M.N {
+  optional int32 y = 10;
+}
+
+"#
+        );
+        assert_eq!(
+            format(Style::buf()),
+            r#"syntax = "proto2";
+
+package p;
+
+service Svc {
+  rpc Get(p.M) returns (p.M);
+
+  option deprecated = true;
+}
+
+message M {
+  message N {
+  }
+
+  optional p.M.N n = 1;
+  reserved 5;
+
+  option deprecated = true;
+}
+
+enum E {
+  A = 0;
+  B = 0;
+
+  option allow_alias = true;
+}
+
+extend p.M {
+  optional int32 x = 10;
+}
+
+extend p.M.N {
+  optional int32 y = 10;
+}
+
+"#
+        );
+
+        // Extensions first: the canonical blank-line policy still separates
+        // the last `extend` block from the next definition
+        let style = Style {
+            definition_order: [DefinitionKind::Extensions; 4],
+            ..Style::canonical()
+        };
+        assert!(format(style).contains("  optional int32 y = 10;\n}\n\nmessage M {"));
+    }
+
+    #[test]
+    fn test_format_single_definitions() {
+        let descriptor = crate::parse_proto_to_file_descriptor(
+            r#"
+syntax = "proto2";
+package com.example;
+message Corpus {}
+message SearchRequest {
+  enum Corpus { WEB = 0; }
+  message Result { optional Corpus corpus = 1; }
+  repeated Result results = 1;
+  extensions 100 to 199;
+  extend SearchRequest { optional example.Corpus origin = 100; }
+}
+service Search {
+  rpc Find(SearchRequest) returns (SearchRequest.Result);
+}
+"#,
+        )
+        .unwrap();
+        let mut generator = TextGenerator::new(TextGeneratorOptions {
+            relative_type_names: true,
+            ..Default::default()
+        });
+
+        // Nested definitions are written at the top level, with type names
+        // resolved from where they are declared
+        assert_eq!(
+            generator
+                .format_message(&descriptor, "com.example.SearchRequest.Result")
+                .unwrap(),
+            "message Result {\n  optional Corpus corpus = 1;\n}\n"
+        );
+        assert_eq!(
+            generator
+                .format_enum(&descriptor, ".com.example.SearchRequest.Corpus")
+                .unwrap(),
+            "enum Corpus {\n  WEB = 0;\n}\n"
+        );
+        assert_eq!(
+            generator
+                .format_extension(&descriptor, "com.example.SearchRequest.origin")
+                .unwrap(),
+            "extend SearchRequest {\n  optional example.Corpus origin = 100;\n}\n"
+        );
+        assert_eq!(
+            generator
+                .format_service(&descriptor, "com.example.Search")
+                .unwrap(),
+            "service Search {\n  rpc Find(SearchRequest) returns (SearchRequest.Result);\n}\n"
+        );
+
+        let error = generator
+            .format_message(&descriptor, "com.example.Missing")
+            .unwrap_err();
+        assert!(error.to_string().contains("com.example.Missing"));
+        // The generator can still format whole files afterwards
+        assert!(generator
+            .format_file(&descriptor)
+            .unwrap()
+            .starts_with("syntax"));
+    }
+
+    /// Options of the element of [`OPTIONS_FIXTURE`] that `options` applies to.
+    fn fixture_options<'a>(
+        file: &'a mut FileDescriptorProto,
+        options: &str,
+    ) -> &'a mut dyn protobuf::MessageDyn {
+        let message = &mut file.message_type[0];
+        match options {
+            "google.protobuf.FileOptions" => file.options.mut_or_insert_default(),
+            "google.protobuf.MessageOptions" => message.options.mut_or_insert_default(),
+            "google.protobuf.FieldOptions" => message.field[0].options.mut_or_insert_default(),
+            "google.protobuf.OneofOptions" => message.oneof_decl[0].options.mut_or_insert_default(),
+            "google.protobuf.ExtensionRangeOptions" => {
+                message.extension_range[0].options.mut_or_insert_default()
+            }
+            "google.protobuf.EnumOptions" => file.enum_type[0].options.mut_or_insert_default(),
+            "google.protobuf.EnumValueOptions" => {
+                file.enum_type[0].value[0].options.mut_or_insert_default()
+            }
+            "google.protobuf.ServiceOptions" => file.service[0].options.mut_or_insert_default(),
+            "google.protobuf.MethodOptions" => {
+                file.service[0].method[0].options.mut_or_insert_default()
+            }
+            _ => panic!("No element for {options}"),
+        }
+    }
+
+    const OPTIONS_FIXTURE: &str = r#"
+syntax = "proto2";
+package fixture;
+message M {
+  optional int32 f = 1;
+  oneof o { int32 g = 2; }
+  extensions 100 to 200;
+}
+enum E { V = 0; }
+service S { rpc R(M) returns (M); }
+"#;
+
+    #[test]
+    fn test_every_builtin_option_is_rendered() {
+        use protobuf::descriptor::*;
+        use protobuf::reflect::{ReflectValueBox, RuntimeFieldType, RuntimeType};
+        use protobuf::MessageFull;
+
+        let non_default = |runtime_type: &RuntimeType| match runtime_type {
+            RuntimeType::Bool => ReflectValueBox::Bool(true),
+            RuntimeType::String => ReflectValueBox::String("x".to_string()),
+            RuntimeType::Enum(descriptor) => {
+                let last = descriptor.values().last().unwrap().value();
+                ReflectValueBox::Enum(descriptor.clone(), last)
+            }
+            other => panic!("Unexpected option type {other}"),
+        };
+
+        let base = crate::parse_proto_to_file_descriptor(OPTIONS_FIXTURE).unwrap();
+        let upstream = builtin_options::tests::upstream_descriptor();
+        let check = |file: &FileDescriptorProto, options: &str, name: &str| {
+            let text = descriptor_to_proto(file).unwrap();
+            assert!(
+                text.contains(&format!("{name} = ")),
+                "{options}.{name} is not rendered:\n{text}"
+            );
+        };
+
+        for options_type in [
+            FileOptions::descriptor(),
+            MessageOptions::descriptor(),
+            FieldOptions::descriptor(),
+            OneofOptions::descriptor(),
+            ExtensionRangeOptions::descriptor(),
+            EnumOptions::descriptor(),
+            EnumValueOptions::descriptor(),
+            ServiceOptions::descriptor(),
+            MethodOptions::descriptor(),
+        ] {
+            let options_name = options_type.full_name();
+
+            // Fields known to the protobuf crate
+            for field in options_type.fields() {
+                // Never set after parsing, or implied by the map syntax
+                if matches!(field.name(), "uninterpreted_option" | "map_entry") {
+                    continue;
+                }
+                let mut file = base.clone();
+                let options = fixture_options(&mut file, options_name);
+                match field.runtime_field_type() {
+                    RuntimeFieldType::Singular(t) => {
+                        field.set_singular_field(options, non_default(&t))
+                    }
+                    RuntimeFieldType::Repeated(t) => {
+                        field.mut_repeated(options).push(non_default(&t))
+                    }
+                    RuntimeFieldType::Map(..) => panic!("Unexpected map option {}", field.name()),
+                }
+                check(&file, options_name, field.name());
+            }
+
+            // Fields of the upstream descriptor.proto the protobuf crate
+            // predates, set as unknown fields
+            let upstream_options = upstream
+                .message_type
+                .iter()
+                .find(|m| Some(m.name()) == options_name.strip_prefix("google.protobuf."))
+                .unwrap_or_else(|| panic!("{options_name} not found upstream"));
+            for field in &upstream_options.field {
+                let number = field.number() as u32;
+                assert!(number < crate::custom_options::FIRST_EXTENSION_NUMBER);
+                if options_type.field_by_number(number).is_some() {
+                    continue;
+                }
+                if Some(number) == editions::features_field(options_name) {
+                    assert_eq!(field.name(), "features");
+                    continue;
+                }
+                let mut file = base.clone();
+                let unknown = fixture_options(&mut file, options_name).mut_unknown_fields_dyn();
+                match field.type_() {
+                    Type::TYPE_MESSAGE | Type::TYPE_STRING => {
+                        unknown.add_length_delimited(number, Vec::new())
+                    }
+                    _ => unknown.add_varint(number, 1),
+                }
+                check(&file, options_name, field.name());
+            }
+
+            let mut file = base.clone();
+            let number = editions::features_field(options_name).unwrap();
+            fixture_options(&mut file, options_name)
+                .mut_unknown_fields_dyn()
+                .add_length_delimited(number, vec![8, 1]);
+            check(&file, options_name, "features.field_presence");
+        }
+    }
 }
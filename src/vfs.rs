@@ -0,0 +1,421 @@
+//! Virtual file map used to feed `protobuf_parse`.
+//!
+//! The input files, in-memory imports and lenient stubs of a whole parse run
+//! are collected in memory, materialized once into a single staging
+//! directory, and handed to one parser invocation.
+//!
+//! The staging directory is still needed for the pure parser:
+//! `protobuf_parse::pure::parse_and_typecheck_custom` accepts a custom
+//! resolver, but in `protobuf-parse` 3.7 its `ProtoPathResolver` trait is
+//! private to that crate and cannot be implemented here, so `Parser` with
+//! include directories is the only way in. `protoc` reads from disk anyway.
+//! Descriptor sets are merged and fingerprinted without parsing, so they
+//! never touch the staging directory.
+//!
+//! Files staged for the pure parser are lowered first when they use
+//! editions (see [`crate::editions`]); `protoc` reads them unchanged.
+//!
+//! Neither backend records comments, so the `source_code_info` of each input
+//! is rebuilt from its original source (see [`crate::comments`]).
+//!
+//! Imports are resolved from the session's in-memory files first, then its
+//! include roots, then the bundled googleapis protos (see
+//! [`crate::googleapis`]), and finally, in lenient mode, an empty stub.
+
+use crate::comments;
+use crate::diff::diff_file_descriptors;
+use crate::editions::{self, LoweredSource};
+use crate::googleapis;
+use crate::lexer::extract_imports;
+use crate::session::{Backend, ParseSession, StubPolicy};
+use anyhow::{anyhow, bail, Context, Result};
+use protobuf::descriptor::FileDescriptorProto;
+use protobuf::MessageField;
+use protobuf_parse::Parser;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+/// Content used for imports that cannot be resolved in lenient mode.
+const STUB_IMPORT_CONTENT: &str = "syntax = \"proto3\";";
+
+/// Descriptors produced by parsing a set of inputs.
+#[derive(Debug)]
+pub(crate) struct ParsedFiles {
+    /// One result per input, in input order.
+    pub(crate) inputs: Vec<Result<FileDescriptorProto>>,
+    /// Every other file loaded while parsing the inputs, i.e. their direct
+    /// and indirect imports, sorted by name.
+    pub(crate) imports: Vec<FileDescriptorProto>,
+}
+
+/// Parse `inputs` (proto path, content) in a single parser run.
+///
+/// When the combined run fails, each input is re-parsed on its own so that
+/// the error can be attributed to the file that caused it.
+pub(crate) fn parse_files(inputs: &[(&str, &str)], session: &ParseSession) -> Result<ParsedFiles> {
+    let names: Vec<&str> = inputs.iter().map(|(name, _)| *name).collect();
+    VirtualFiles::new(inputs, session)?.parse(&names, session)
+}
+
+/// In-memory map from proto path to file content.
+#[derive(Debug, Default)]
+struct VirtualFiles {
+    files: BTreeMap<String, String>,
+}
+
+impl VirtualFiles {
+    /// Build the map for `inputs` (proto path, content), adding in-memory
+    /// imports, editions files found under the include roots, bundled
+    /// googleapis protos and, in lenient mode, stubs for every unresolved
+    /// import.
+    fn new(inputs: &[(&str, &str)], session: &ParseSession) -> Result<Self> {
+        let mut vfs = Self::default();
+        for (path, content) in &session.files {
+            validate_import_path(path)?;
+            vfs.files.insert(path.clone(), content.clone());
+        }
+        for (name, content) in inputs {
+            validate_import_path(name)?;
+            for import in extract_imports(content)
+                .with_context(|| format!("Failed to scan imports of {name}"))?
+            {
+                validate_import_path(&import.path).with_context(|| {
+                    format!(
+                        "Rejected import at {}:{} in {name}",
+                        import.position.line, import.position.column
+                    )
+                })?;
+            }
+            vfs.files.insert(name.to_string(), content.to_string());
+        }
+
+        vfs.resolve_imports(inputs, session)?;
+
+        Ok(vfs)
+    }
+
+    fn resolve_imports(&mut self, inputs: &[(&str, &str)], session: &ParseSession) -> Result<()> {
+        let mut visited = BTreeSet::new();
+        let mut pending: Vec<String> = inputs.iter().map(|(_, c)| c.to_string()).collect();
+
+        while let Some(current) = pending.pop() {
+            for path_str in extract_imports(&current)?.into_iter().map(|i| i.path) {
+                if path_str.starts_with("google/protobuf/") || !visited.insert(path_str.clone()) {
+                    continue;
+                }
+                validate_import_path(&path_str)?;
+
+                if let Some(found) = self.files.get(&path_str) {
+                    pending.push(found.clone());
+                    continue;
+                }
+                if let Some(found) = session.read_from_roots(&path_str)? {
+                    // Editions files must be staged so they can be lowered
+                    if editions::lower(&found)?.is_some() {
+                        self.files.insert(path_str, found.clone());
+                    }
+                    pending.push(found);
+                } else if let Some(bundled) = googleapis::lookup(&path_str) {
+                    self.files.insert(path_str, bundled.to_string());
+                    pending.push(bundled.to_string());
+                } else if session.stub_policy == StubPolicy::Lenient {
+                    self.files.insert(path_str, STUB_IMPORT_CONTENT.to_string());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Materialize the map and parse `inputs` in a single parser run.
+    fn parse(&self, inputs: &[&str], session: &ParseSession) -> Result<ParsedFiles> {
+        let staging = Staging::new(self, session.backend)?;
+        let take = |parsed: &mut BTreeMap<String, FileDescriptorProto>, name: &str| {
+            parsed
+                .remove(name)
+                .ok_or_else(|| anyhow!("Could not find parsed file descriptor"))
+        };
+
+        let (results, imports) = match staging.run_parser(inputs, session) {
+            Ok(mut parsed) => {
+                let results = inputs.iter().map(|name| take(&mut parsed, name)).collect();
+                (results, parsed)
+            }
+            Err(err) if inputs.len() <= 1 => (vec![Err(err)], BTreeMap::new()),
+            Err(_) => {
+                let mut imports = BTreeMap::new();
+                let results = inputs
+                    .iter()
+                    .map(|name| {
+                        let mut parsed = staging.run_parser(&[name], session)?;
+                        let descriptor = take(&mut parsed, name);
+                        imports.append(&mut parsed);
+                        descriptor
+                    })
+                    .collect();
+                for name in inputs {
+                    imports.remove(*name);
+                }
+                (results, imports)
+            }
+        };
+        let results = inputs
+            .iter()
+            .zip(results)
+            .map(|(name, result)| self.with_source_code_info(name, result?))
+            .collect();
+
+        Ok(ParsedFiles {
+            inputs: results,
+            imports: imports.into_values().collect(),
+        })
+    }
+
+    /// Attach the comments and spans of the input `name` to its descriptor.
+    fn with_source_code_info(
+        &self,
+        name: &str,
+        mut descriptor: FileDescriptorProto,
+    ) -> Result<FileDescriptorProto> {
+        if let Some(content) = self.files.get(name) {
+            let info = comments::source_code_info(content, &descriptor)
+                .with_context(|| format!("Failed to read comments of {name}"))?;
+            descriptor.source_code_info = MessageField::some(info);
+        }
+        Ok(descriptor)
+    }
+}
+
+/// The virtual files written to disk, once per backend that needs them.
+struct Staging {
+    root: tempfile::TempDir,
+    /// Editions files rewritten for the pure parser, by path
+    lowered: BTreeMap<String, LoweredSource>,
+}
+
+impl Staging {
+    const PURE_DIR: &'static str = "pure";
+    const PROTOC_DIR: &'static str = "protoc";
+
+    fn new(vfs: &VirtualFiles, backend: Backend) -> Result<Self> {
+        let root = tempfile::tempdir().with_context(|| {
+            format!(
+                "Cannot stage proto sources for parsing: the temporary directory {} is not \
+                 writable (set TMPDIR to a writable directory)",
+                std::env::temp_dir().display()
+            )
+        })?;
+        let mut lowered = BTreeMap::new();
+
+        if matches!(backend, Backend::Pure | Backend::CrossCheck) {
+            let dir = root.path().join(Self::PURE_DIR);
+            for (path, content) in &vfs.files {
+                match editions::lower(content)
+                    .with_context(|| format!("Failed to read editions file {path}"))?
+                {
+                    Some(lowered_source) => {
+                        write_staged_file(&dir, path, &lowered_source.source)?;
+                        lowered.insert(path.clone(), lowered_source);
+                    }
+                    None => write_staged_file(&dir, path, content)?,
+                }
+            }
+        }
+        if matches!(backend, Backend::Protoc | Backend::CrossCheck) {
+            let dir = root.path().join(Self::PROTOC_DIR);
+            for (path, content) in &vfs.files {
+                write_staged_file(&dir, path, content)?;
+            }
+        }
+
+        Ok(Self { root, lowered })
+    }
+
+    fn run_parser(
+        &self,
+        inputs: &[&str],
+        session: &ParseSession,
+    ) -> Result<BTreeMap<String, FileDescriptorProto>> {
+        match session.backend {
+            Backend::Pure => self.run_backend(inputs, session, Backend::Pure),
+            Backend::Protoc => self.run_backend(inputs, session, Backend::Protoc),
+            Backend::CrossCheck => {
+                let pure = self.run_backend(inputs, session, Backend::Pure)?;
+                let protoc = self.run_backend(inputs, session, Backend::Protoc)?;
+                check_backends_agree(inputs, &pure, &protoc)?;
+                Ok(pure)
+            }
+        }
+    }
+
+    /// Run a single parser implementation over the staged files.
+    ///
+    /// Returns the descriptors of the inputs and of everything they import.
+    fn run_backend(
+        &self,
+        inputs: &[&str],
+        session: &ParseSession,
+        backend: Backend,
+    ) -> Result<BTreeMap<String, FileDescriptorProto>> {
+        let mut parser = Parser::new();
+        let staging_dir = match backend {
+            Backend::Protoc => {
+                let protoc = find_protoc().context("protoc binary not found on PATH")?;
+                parser.protoc().protoc_path(&protoc).capture_stderr();
+                self.root.path().join(Self::PROTOC_DIR)
+            }
+            _ => {
+                parser.pure();
+                self.root.path().join(Self::PURE_DIR)
+            }
+        };
+        let parsed = parser
+            .include(&staging_dir)
+            .includes(&session.include_roots)
+            .inputs(inputs.iter().map(|name| staging_dir.join(name)))
+            .parse_and_typecheck()
+            .with_context(|| match backend {
+                Backend::Protoc => "Protobuf parsing with protoc failed",
+                _ => "Protobuf parsing failed",
+            })?;
+
+        let mut files = BTreeMap::new();
+        for mut descriptor in parsed.file_descriptors {
+            let name = descriptor.name().to_string();
+            if backend != Backend::Protoc {
+                if let Some(lowered) = self.lowered.get(&name) {
+                    lowered.apply(&mut descriptor).with_context(|| {
+                        format!("Failed to restore editions features of {name}")
+                    })?;
+                }
+            }
+            files.insert(name, descriptor);
+        }
+        Ok(files)
+    }
+}
+
+/// Fail if the pure parser and `protoc` produced different descriptors for
+/// any of `inputs`. Files missing from either side are not compared.
+pub(crate) fn check_backends_agree(
+    inputs: &[&str],
+    pure: &BTreeMap<String, FileDescriptorProto>,
+    protoc: &BTreeMap<String, FileDescriptorProto>,
+) -> Result<()> {
+    for name in inputs {
+        let (Some(left), Some(right)) = (pure.get(*name), protoc.get(*name)) else {
+            continue;
+        };
+        let differences = diff_file_descriptors(left, right);
+        if !differences.is_empty() {
+            let details: Vec<String> = differences.iter().map(|d| format!("  {d}")).collect();
+            bail!(
+                "Pure parser and protoc disagree on {name}:\n{}",
+                details.join("\n")
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Locate the `protoc` binary: `$PROTOC` if set, otherwise the first match
+/// on `PATH`. `protobuf-parse` panics when it cannot find one itself.
+pub(crate) fn find_protoc() -> Option<PathBuf> {
+    if let Some(path) = std::env::var_os("PROTOC") {
+        return Some(PathBuf::from(path));
+    }
+    let name = format!("protoc{}", std::env::consts::EXE_SUFFIX);
+    std::env::split_paths(&std::env::var_os("PATH")?)
+        .map(|dir| dir.join(&name))
+        .find(|candidate| candidate.is_file())
+}
+
+/// Check that an import path is relative and stays inside its include root.
+///
+/// Rejects empty paths, absolute paths, backslashes, empty components and
+/// `.`/`..` segments, so that joining the path onto a directory can never
+/// escape that directory.
+pub(crate) fn validate_import_path(path: &str) -> Result<()> {
+    if path.is_empty() {
+        bail!("Invalid import path \"\": path is empty");
+    }
+    if path.contains('\\') {
+        bail!("Invalid import path \"{path}\": backslashes are not allowed");
+    }
+    if path.starts_with('/') || path.split('/').next().is_some_and(|c| c.ends_with(':')) {
+        bail!("Invalid import path \"{path}\": absolute paths are not allowed");
+    }
+    for component in path.split('/') {
+        match component {
+            "" => bail!("Invalid import path \"{path}\": empty path component"),
+            "." | ".." => {
+                bail!("Invalid import path \"{path}\": '{component}' segments are not allowed")
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn write_staged_file(staging_dir: &Path, path_str: &str, content: &str) -> Result<()> {
+    let staged_path = staging_dir.join(path_str);
+    if let Some(parent) = staged_path.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create import dir: {path_str}"))?;
+    }
+    std::fs::write(&staged_path, content)
+        .with_context(|| format!("Failed to write import: {path_str}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_import_path_accepts_relative_paths() {
+        assert!(validate_import_path("foo.proto").is_ok());
+        assert!(validate_import_path("foo/bar/baz.proto").is_ok());
+        assert!(validate_import_path("foo..bar.proto").is_ok());
+    }
+
+    #[test]
+    fn test_validate_import_path_rejects_escapes() {
+        for path in [
+            "",
+            "/etc/foo.proto",
+            "../../x.proto",
+            "foo/../../x.proto",
+            "./foo.proto",
+            "foo//bar.proto",
+            "C:/foo.proto",
+            "foo\\..\\bar.proto",
+        ] {
+            let err = validate_import_path(path).unwrap_err();
+            assert!(
+                err.to_string().starts_with("Invalid import path"),
+                "{path}: {err}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_traversal_imports_are_rejected_before_staging() {
+        let content = "syntax = \"proto3\";\nimport \"../../escaped.proto\";\n";
+        let err = parse_files(&[("input.proto", content)], &ParseSession::lenient()).unwrap_err();
+        assert!(format!("{err:#}").contains("'..' segments are not allowed"));
+
+        let content = "syntax = \"proto3\";\nimport \"/etc/foo.proto\";\n";
+        let err = parse_files(&[("input.proto", content)], &ParseSession::new()).unwrap_err();
+        assert!(format!("{err:#}").contains("absolute paths are not allowed"));
+    }
+
+    #[test]
+    fn test_in_memory_import_keys_are_validated() {
+        let session =
+            ParseSession::new().with_files([("../outside.proto", "syntax = \"proto3\";")]);
+        let err = parse_files(&[("input.proto", "syntax = \"proto3\";")], &session).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Invalid import path \"../outside.proto\""));
+    }
+}
@@ -74,6 +74,91 @@ message Test { string field = 1; }
     assert!(stdout.contains("message Test"));
 }
 
+#[test]
+fn test_cli_normalize_keep_comments() {
+    let temp_dir = TempDir::new().unwrap();
+    let input_file = temp_dir.path().join("input.proto");
+
+    let proto_content = r#"
+syntax = "proto3";
+// 测试消息
+message Test {
+  string field = 1; // 字段说明
+}
+"#;
+    fs::write(&input_file, proto_content).unwrap();
+
+    let run = |keep_comments: bool| {
+        let mut command = Command::new(get_binary_path());
+        command.arg("normalize").arg(&input_file);
+        if keep_comments {
+            command.arg("--keep-comments");
+        }
+        let output = command.output().expect("Failed to execute CLI");
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    // 默认丢弃注释，--keep-comments 时保留
+    assert!(!run(false).contains("//"));
+    let stdout = run(true);
+    assert!(stdout.contains("// 测试消息\nmessage Test {\n"));
+    assert!(stdout.contains("  string field = 1; // 字段说明\n"));
+}
+
+#[test]
+fn test_cli_normalize_preserve_order() {
+    let temp_dir = TempDir::new().unwrap();
+    let input_file = temp_dir.path().join("input.proto");
+
+    // 已格式化但未排序的文件
+    let proto_content = "syntax = \"proto3\";\n\nmessage Zebra {\n  string b = 2;\n  string a = 1;\n}\n\nmessage Apple {\n}\n\n";
+    fs::write(&input_file, proto_content).unwrap();
+
+    let output = Command::new(get_binary_path())
+        .arg("normalize")
+        .arg(&input_file)
+        .arg("--preserve-order")
+        .output()
+        .expect("Failed to execute CLI");
+
+    assert!(output.status.success());
+    // println! 会追加一个换行
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout, format!("{proto_content}\n"));
+}
+
+#[test]
+fn test_cli_normalize_style() {
+    let temp_dir = TempDir::new().unwrap();
+    let input_file = temp_dir.path().join("input.proto");
+
+    let proto_content = r#"
+syntax = "proto3";
+service Svc { rpc Get(Req) returns (Req); }
+message Req { string q = 1; }
+"#;
+    fs::write(&input_file, proto_content).unwrap();
+
+    let run = |style: &str| {
+        let output = Command::new(get_binary_path())
+            .arg("normalize")
+            .arg(&input_file)
+            .arg("--style")
+            .arg(style)
+            .output()
+            .expect("Failed to execute CLI");
+        assert!(output.status.success(), "CLI failed: {output:?}");
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    // 默认样式先写 message，buf 样式先写 service
+    let canonical = run("canonical");
+    assert!(canonical.find("message Req").unwrap() < canonical.find("service Svc").unwrap());
+    let buf = run("buf");
+    assert!(buf.find("service Svc").unwrap() < buf.find("message Req").unwrap());
+}
+
 #[test]
 fn test_cli_normalize_directory_mode() {
     let input_dir = TempDir::new().unwrap();
@@ -166,6 +251,122 @@ message User { string name = 1; }
     assert!(stdout.contains("message_type"));
 }
 
+#[test]
+fn test_cli_normalize_descriptor_set() {
+    use protobuf::descriptor::FileDescriptorSet;
+    use protobuf::Message;
+
+    let temp_dir = TempDir::new().unwrap();
+    let output_dir = TempDir::new().unwrap();
+
+    // 由 proto 文本构建描述符集
+    let mut set = FileDescriptorSet::new();
+    for (path, content) in [
+        (
+            "foo/a.proto",
+            "syntax = \"proto3\"; package foo; message A { string x = 1; }",
+        ),
+        (
+            "foo/b.proto",
+            "syntax = \"proto3\"; package foo; message B { int32 y = 1; }",
+        ),
+    ] {
+        set.file
+            .push(proto_regulate::parse_proto_to_file_descriptor((path, content)).unwrap());
+    }
+
+    // 单文件描述符集直接输出到 stdout
+    let single_file = temp_dir.path().join("single.binpb");
+    let mut single = set.clone();
+    single.file.truncate(1);
+    fs::write(&single_file, single.write_to_bytes().unwrap()).unwrap();
+
+    let output = Command::new(get_binary_path())
+        .arg("normalize")
+        .arg(&single_file)
+        .output()
+        .expect("Failed to execute CLI");
+
+    assert!(output.status.success(), "CLI failed: {output:?}");
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("message A"));
+
+    // 多文件描述符集按 package 合并
+    let set_file = temp_dir.path().join("schema.pb");
+    fs::write(&set_file, set.write_to_bytes().unwrap()).unwrap();
+
+    let output = Command::new(get_binary_path())
+        .arg("normalize")
+        .arg(&set_file)
+        .arg("-o")
+        .arg(output_dir.path())
+        .output()
+        .expect("Failed to execute CLI");
+
+    assert!(output.status.success(), "CLI failed: {output:?}");
+    let merged = fs::read_to_string(output_dir.path().join("foo.proto")).unwrap();
+    assert!(merged.contains("message A"));
+    assert!(merged.contains("message B"));
+
+    // inspect 同样接受描述符集
+    let output = Command::new(get_binary_path())
+        .arg("inspect")
+        .arg(&set_file)
+        .output()
+        .expect("Failed to execute CLI");
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("foo/b.proto"));
+}
+
+#[test]
+fn test_cli_normalize_verify_roundtrip() {
+    use protobuf::descriptor::{FileDescriptorSet, OneofDescriptorProto};
+    use protobuf::Message;
+
+    let temp_dir = TempDir::new().unwrap();
+    let input_file = temp_dir.path().join("input.proto");
+    let proto_content = r#"
+syntax = "proto2";
+package test;
+message User {
+  optional string name = 1 [default = "anonymous"];
+  oneof contact { string email = 2; }
+}
+"#;
+    fs::write(&input_file, proto_content).unwrap();
+
+    let output = Command::new(get_binary_path())
+        .arg("normalize")
+        .arg(&input_file)
+        .arg("--verify-roundtrip")
+        .output()
+        .expect("Failed to execute CLI");
+    assert!(output.status.success(), "CLI failed: {output:?}");
+
+    // 没有字段的 oneof 无法写成文本，校验失败并列出差异
+    let mut descriptor =
+        proto_regulate::parse_proto_to_file_descriptor(("user.proto", proto_content)).unwrap();
+    let mut oneof = OneofDescriptorProto::new();
+    oneof.set_name("empty".to_string());
+    descriptor.message_type[0].oneof_decl.push(oneof);
+    let mut set = FileDescriptorSet::new();
+    set.file.push(descriptor);
+    let set_file = temp_dir.path().join("user.binpb");
+    fs::write(&set_file, set.write_to_bytes().unwrap()).unwrap();
+
+    let output = Command::new(get_binary_path())
+        .arg("normalize")
+        .arg(&set_file)
+        .arg("--verify-roundtrip")
+        .output()
+        .expect("Failed to execute CLI");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("message_type[User].oneof_decl[empty]"));
+}
+
 #[test]
 fn test_cli_error_handling_missing_file() {
     let output = Command::new(get_binary_path())
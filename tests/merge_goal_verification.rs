@@ -112,6 +112,56 @@ message Msg { string field = 1; }"#;
     println!("\n✅ Fingerprint 一致性验证通过！");
 }
 
+#[test]
+fn test_fingerprint_ignores_comments() {
+    // 注释不影响 fingerprint
+    let plain = r#"syntax = "proto3";
+package test;
+message Msg { string field = 1; }"#;
+    let commented = r#"// 文件头注释
+syntax = "proto3";
+package test;
+// 消息注释
+message Msg {
+  string field = 1; // 字段注释
+}"#;
+
+    assert_eq!(
+        proto_regulate::generate_fingerprint(plain).unwrap(),
+        proto_regulate::generate_fingerprint(commented).unwrap()
+    );
+    let results1 = merge_by_package(vec![plain]).unwrap();
+    let results2 = merge_by_package(vec![commented]).unwrap();
+    assert_eq!(results1[0].fingerprint, results2[0].fingerprint);
+}
+
+#[test]
+fn test_fingerprint_ignores_equivalent_spellings() {
+    // 语义相同的写法产生相同 fingerprint
+    let compact = r#"syntax = "proto3";
+package test;
+message Msg {
+  reserved 1 to 3;
+  repeated int32 ids = 4;
+}"#;
+    let verbose = r#"syntax = "proto3";
+package test;
+option optimize_for = SPEED;
+message Msg {
+  reserved 3, 1, 2;
+  repeated int32 ids = 4 [packed = true];
+}"#;
+
+    assert_eq!(
+        proto_regulate::generate_fingerprint(compact).unwrap(),
+        proto_regulate::generate_fingerprint(verbose).unwrap()
+    );
+    let results1 = merge_by_package(vec![compact]).unwrap();
+    let results2 = merge_by_package(vec![verbose]).unwrap();
+    assert_eq!(results1[0].content, results2[0].content);
+    assert_eq!(results1[0].fingerprint, results2[0].fingerprint);
+}
+
 #[test]
 fn test_wrapper_function() {
     // 演示如何包装成用户期望的签名
@@ -0,0 +1,192 @@
+//! 置换测试：打乱 tests/data 语料中声明的顺序，规范化输出必须逐字节相同
+
+use proto_regulate::merge::merge_by_package;
+use proto_regulate::{descriptor_to_proto_with_imports, generate_fingerprint, ParseSession};
+use protobuf::descriptor::{DescriptorProto, EnumDescriptorProto, FileDescriptorProto};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// 每个文件尝试的随机排列数
+const SEEDS: u64 = 16;
+
+/// 固定种子的 xorshift 随机数，保证失败可复现
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Self(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// 随机打乱 `items`，返回新位置到原下标的映射
+    fn shuffle<T>(&mut self, items: &mut Vec<T>) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..items.len()).collect();
+        for i in (1..order.len()).rev() {
+            let j = (self.next() % (i as u64 + 1)) as usize;
+            order.swap(i, j);
+        }
+        let mut old: Vec<Option<T>> = items.drain(..).map(Some).collect();
+        items.extend(order.iter().map(|&i| old[i].take().unwrap()));
+        order
+    }
+}
+
+/// 原下标到新位置的映射
+fn positions(order: &[usize]) -> Vec<i32> {
+    let mut positions = vec![0; order.len()];
+    for (new, &old) in order.iter().enumerate() {
+        positions[old] = new as i32;
+    }
+    positions
+}
+
+fn shuffle_file(file: &mut FileDescriptorProto, rng: &mut Rng) {
+    // source_code_info 的路径依赖下标，打乱后失效
+    file.source_code_info.clear();
+
+    let order = rng.shuffle(&mut file.dependency);
+    let moved = positions(&order);
+    for index in file
+        .public_dependency
+        .iter_mut()
+        .chain(file.weak_dependency.iter_mut())
+    {
+        *index = moved[*index as usize];
+    }
+
+    rng.shuffle(&mut file.message_type);
+    rng.shuffle(&mut file.enum_type);
+    rng.shuffle(&mut file.service);
+    rng.shuffle(&mut file.extension);
+    for message in &mut file.message_type {
+        shuffle_message(message, rng);
+    }
+    for enum_type in &mut file.enum_type {
+        shuffle_enum(enum_type, rng);
+    }
+    for service in &mut file.service {
+        rng.shuffle(&mut service.method);
+    }
+}
+
+fn shuffle_message(message: &mut DescriptorProto, rng: &mut Rng) {
+    rng.shuffle(&mut message.field);
+    rng.shuffle(&mut message.extension);
+    rng.shuffle(&mut message.nested_type);
+    rng.shuffle(&mut message.enum_type);
+    rng.shuffle(&mut message.extension_range);
+    rng.shuffle(&mut message.reserved_range);
+    rng.shuffle(&mut message.reserved_name);
+
+    // 字段通过下标引用 oneof，需要同步更新
+    let order = rng.shuffle(&mut message.oneof_decl);
+    let moved = positions(&order);
+    for field in &mut message.field {
+        if let Some(index) = field.oneof_index.as_mut() {
+            *index = moved[*index as usize];
+        }
+    }
+
+    for nested in &mut message.nested_type {
+        shuffle_message(nested, rng);
+    }
+    for enum_type in &mut message.enum_type {
+        shuffle_enum(enum_type, rng);
+    }
+}
+
+fn shuffle_enum(enum_type: &mut EnumDescriptorProto, rng: &mut Rng) {
+    rng.shuffle(&mut enum_type.value);
+    rng.shuffle(&mut enum_type.reserved_range);
+    rng.shuffle(&mut enum_type.reserved_name);
+}
+
+/// tests/data 下的全部 proto 文件，按路径排序
+fn corpus() -> Vec<PathBuf> {
+    fn collect(dir: &Path, files: &mut Vec<PathBuf>) {
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                collect(&path, files);
+            } else if path.extension().is_some_and(|e| e == "proto") {
+                files.push(path);
+            }
+        }
+    }
+    let mut files = Vec::new();
+    collect(
+        &Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data"),
+        &mut files,
+    );
+    files.sort();
+    files
+}
+
+#[test]
+fn test_shuffled_declarations_render_identically() {
+    let files = corpus();
+    assert!(!files.is_empty());
+
+    for path in files {
+        let content = fs::read_to_string(&path).unwrap();
+        let (descriptor, imports) = ParseSession::lenient()
+            .with_include_roots([path.parent().unwrap()])
+            .parse_with_imports(&content)
+            .unwrap_or_else(|e| panic!("{}: {e:#}", path.display()));
+        let expected = descriptor_to_proto_with_imports(&descriptor, &imports).unwrap();
+
+        for seed in 0..SEEDS {
+            let mut shuffled = descriptor.clone();
+            shuffle_file(&mut shuffled, &mut Rng::new(seed));
+            let result = descriptor_to_proto_with_imports(&shuffled, &imports).unwrap();
+            assert_eq!(result, expected, "{} seed {seed}", path.display());
+        }
+    }
+}
+
+#[test]
+fn test_merge_ignores_input_order() {
+    // 互不冲突的文件，跨多个 package
+    let data = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data");
+    let mut inputs: Vec<(String, String)> = [
+        "b.proto",
+        "complex_self_contained.proto",
+        "edge_cases/maps_comprehensive.proto",
+        "edge_cases/proto3_optional.proto",
+    ]
+    .iter()
+    .map(|name| {
+        (
+            name.to_string(),
+            fs::read_to_string(data.join(name)).unwrap(),
+        )
+    })
+    .collect();
+
+    let expected = merge_by_package(inputs.clone()).unwrap();
+    // 合并结果的 fingerprint 与重新解析合并文本得到的一致
+    for result in &expected {
+        assert_eq!(
+            result.fingerprint,
+            generate_fingerprint(&result.content).unwrap(),
+            "{}",
+            result.package_name
+        );
+    }
+    for seed in 0..SEEDS {
+        Rng::new(seed).shuffle(&mut inputs);
+        let results = merge_by_package(inputs.clone()).unwrap();
+        assert_eq!(results.len(), expected.len());
+        for (result, expected) in results.iter().zip(&expected) {
+            assert_eq!(result.package_name, expected.package_name, "seed {seed}");
+            assert_eq!(result.content, expected.content, "seed {seed}");
+            assert_eq!(result.fingerprint, expected.fingerprint, "seed {seed}");
+        }
+    }
+}
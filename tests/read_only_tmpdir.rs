@@ -0,0 +1,29 @@
+//! 临时目录不可写时，解析应给出明确的错误
+
+use proto_regulate::{merge_descriptor_set, parse_proto_to_file_descriptor};
+use protobuf::descriptor::FileDescriptorSet;
+use protobuf::Message;
+
+#[test]
+fn test_parse_reports_unwritable_tmpdir() {
+    // 先在可写的临时目录下准备描述符集
+    let descriptor =
+        parse_proto_to_file_descriptor("syntax = \"proto3\"; package a; message A {}").unwrap();
+    let mut set = FileDescriptorSet::new();
+    set.file.push(descriptor);
+    let bytes = set.write_to_bytes().unwrap();
+
+    // 本文件只有这一个测试，修改环境变量不会影响其他测试
+    std::env::set_var("TMPDIR", "/nonexistent/proto-regulate");
+
+    let err = parse_proto_to_file_descriptor("syntax = \"proto3\"; message A {}").unwrap_err();
+    let message = format!("{err:#}");
+    assert!(
+        message.contains("Cannot stage proto sources for parsing"),
+        "{message}"
+    );
+    assert!(message.contains("/nonexistent/proto-regulate"), "{message}");
+
+    // 描述符集不需要解析，也不写临时文件
+    assert_eq!(merge_descriptor_set(&bytes).unwrap().len(), 1);
+}